@@ -0,0 +1,156 @@
+use crate::gltf_builder::push_accessor;
+use crate::materials::MaterialData;
+use gltf_json::accessor::Type;
+use gltf_json::{Root, Value};
+use serde_json::json;
+
+pub const ANIMATION_POINTER_EXTENSION: &str = "KHR_animation_pointer";
+
+/// Create animations for the texture transforms of materials with animated textures
+///
+/// The materials are expected to be written to the output in the same order.
+/// Since `gltf_json` can't represent `KHR_animation_pointer` targets, the animations are returned as raw json
+/// to be added to the output after serializing.
+pub fn push_material_animations(
+    buffer: &mut Vec<u8>,
+    gltf: &mut Root,
+    materials: &[MaterialData],
+) -> Vec<Value> {
+    let mut animations = Vec::new();
+
+    for (index, material) in materials.iter().enumerate() {
+        // the animations target the transform of the base texture
+        let Some(texture) = &material.texture else {
+            continue;
+        };
+        let base_offset = material
+            .transform
+            .as_ref()
+            .map(|transform| transform.translate)
+            .unwrap_or_default();
+
+        let (times, offsets, interpolation) = if let Some((frame_rate, sheet)) = texture
+            .sprite_sheet
+            .as_ref()
+            .and_then(|sheet| Some((sheet.frame_rate.filter(|rate| *rate > 0.0)?, sheet)))
+        {
+            // repeat the first frame at the end so the last frame is shown for its full duration
+            let times: Vec<f32> = (0..=sheet.frames)
+                .map(|frame| frame as f32 / frame_rate)
+                .collect();
+            let offsets = (0..=sheet.frames)
+                .map(|frame| add(base_offset, sheet.offset(frame % sheet.frames)))
+                .collect();
+            (times, offsets, "STEP")
+        } else if let Some(scroll) = material.scroll.as_ref().filter(|scroll| scroll.rate != 0.0) {
+            // scroll one full texture width, after which the animation loops seamlessly
+            let angle = scroll.angle.to_radians();
+            let direction = [angle.cos(), angle.sin()].map(|d| d * scroll.rate.signum());
+            let times = vec![0.0, 1.0 / scroll.rate.abs()];
+            let offsets = vec![base_offset, add(base_offset, direction)];
+            (times, offsets, "LINEAR")
+        } else {
+            continue;
+        };
+
+        let duration = times.last().copied().unwrap_or_default();
+        let input = push_accessor(
+            buffer,
+            gltf,
+            &times,
+            Type::Scalar,
+            None,
+            Some((vec![0.0], vec![duration])),
+        )
+        .value();
+        let output = push_accessor(buffer, gltf, &offsets, Type::Vec2, None, None).value();
+
+        let pointer = format!(
+            "/materials/{index}/pbrMetallicRoughness/baseColorTexture/extensions/KHR_texture_transform/offset"
+        );
+        animations.push(json!({
+            "name": material.name,
+            "samplers": [{
+                "input": input,
+                "output": output,
+                "interpolation": interpolation,
+            }],
+            "channels": [{
+                "sampler": 0,
+                "target": {
+                    "path": "pointer",
+                    "extensions": {
+                        ANIMATION_POINTER_EXTENSION: {
+                            "pointer": pointer,
+                        },
+                    },
+                },
+            }],
+        }));
+    }
+
+    animations
+}
+
+fn add(a: [f32; 2], b: [f32; 2]) -> [f32; 2] {
+    [a[0] + b[0], a[1] + b[1]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::{SpriteSheet, TextureData, TextureScroll};
+    use image::DynamicImage;
+
+    fn texture(sprite_sheet: Option<SpriteSheet>) -> Option<TextureData> {
+        Some(TextureData {
+            name: "texture".into(),
+            image: DynamicImage::new_rgba8(4, 4),
+            sprite_sheet,
+        })
+    }
+
+    #[test]
+    fn animate_typed_materials() {
+        let materials = [
+            MaterialData {
+                texture: texture(None),
+                ..MaterialData::default()
+            },
+            MaterialData {
+                name: "sheet".into(),
+                texture: texture(Some(SpriteSheet {
+                    frames: 4,
+                    columns: 2,
+                    rows: 2,
+                    frame_rate: Some(10.0),
+                })),
+                ..MaterialData::default()
+            },
+            MaterialData {
+                name: "scroll".into(),
+                texture: texture(None),
+                scroll: Some(TextureScroll {
+                    rate: 0.5,
+                    angle: 90.0,
+                }),
+                ..MaterialData::default()
+            },
+        ];
+        let mut buffer = Vec::new();
+        let mut gltf = Root::default();
+        let animations = push_material_animations(&mut buffer, &mut gltf, &materials);
+
+        assert_eq!(animations.len(), 2);
+        assert_eq!(animations[0]["name"], "sheet");
+        assert_eq!(animations[0]["samplers"][0]["interpolation"], "STEP");
+        let pointer = &animations[0]["channels"][0]["target"]["extensions"]
+            [ANIMATION_POINTER_EXTENSION]["pointer"];
+        assert!(pointer.as_str().unwrap().starts_with("/materials/1/"));
+        assert_eq!(animations[1]["name"], "scroll");
+        assert_eq!(animations[1]["samplers"][0]["interpolation"], "LINEAR");
+        // the frame times of the sheet and the scroll keyframes
+        assert_eq!(gltf.accessors[0].count.0, 5);
+        assert_eq!(gltf.accessors[2].count.0, 2);
+    }
+}
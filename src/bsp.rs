@@ -1,19 +1,14 @@
 use crate::convert::map_coords;
 use crate::error::Error;
-use crate::gltf_builder::push_or_get_material;
+use crate::layers::Layer;
+use crate::scene::{Mesh, Node, Primitive, SceneBuilder};
+use crate::visibility::FaceVisibility;
 use crate::ConvertOptions;
-use bytemuck::{offset_of, Pod, Zeroable};
-use gltf_json::accessor::{ComponentType, GenericComponentType, Type};
-use gltf_json::buffer::{Stride, Target, View};
-use gltf_json::mesh::{Mode, Primitive, Semantic};
-use gltf_json::validation::Checked::Valid;
-use gltf_json::validation::USize64;
-use gltf_json::{Accessor, Index, Mesh, Node, Root, Value};
-use std::mem::size_of;
+use std::collections::BTreeMap;
 use tf_asset_loader::Loader;
 use vbsp::{Bsp, Entity, Face, Handle, Model, Vector};
 
-pub fn bsp_models(bsp: &Bsp) -> Result<Vec<(Handle<Model>, Vector)>, Error> {
+pub fn bsp_models(bsp: &Bsp) -> Result<Vec<(Handle<'_, Model>, Vector)>, Error> {
     let world_model = bsp
         .models()
         .next()
@@ -45,166 +40,120 @@ pub fn bsp_models(bsp: &Bsp) -> Result<Vec<(Handle<Model>, Vector)>, Error> {
     Ok(models)
 }
 
-fn bounding_box(vertices: impl IntoIterator<Item = Vector>) -> ([f32; 3], [f32; 3]) {
-    let mut min = Vector::from([f32::MAX, f32::MAX, f32::MAX]);
-    let mut max = Vector::from([f32::MIN, f32::MIN, f32::MIN]);
-
-    for point in vertices {
-        min.x = f32::min(min.x, point.x);
-        min.y = f32::min(min.y, point.y);
-        min.z = f32::min(min.z, point.z);
-
-        max.x = f32::max(max.x, point.x);
-        max.y = f32::max(max.y, point.y);
-        max.z = f32::max(max.z, point.z);
-    }
-    (min.into(), max.into())
-}
-
-#[derive(Copy, Clone, Debug, Default, Zeroable, Pod)]
-#[repr(C)]
-pub struct BspVertexData {
-    position: [f32; 3],
-    uv: [f32; 2],
+/// The visible faces of a model that aren't culled or excluded, with their index in the map and layer
+pub fn model_faces<'a>(
+    model: &Handle<'a, Model>,
+    offset: Vector,
+    world: bool,
+    visibility: &'a FaceVisibility,
+    options: &'a ConvertOptions,
+) -> impl Iterator<Item = (usize, Handle<'a, Face>, Layer)> + 'a {
+    let first_face = model.first_face as usize;
+    model
+        .faces()
+        .enumerate()
+        .map(move |(index, face)| (first_face + index, face))
+        .filter(|(index, face)| face.is_visible() && !visibility.is_culled(*index))
+        .map(move |(index, face)| {
+            let layer = visibility.face_layer(index, &face, world);
+            (index, face, layer)
+        })
+        .filter(|(_, _, layer)| options.includes(*layer))
+        .filter(move |(_, face, _)| {
+            visibility.region().map_or(true, |region| {
+                let positions: Vec<[f32; 3]> = face
+                    .vertex_positions()
+                    .map(|position| map_coords(position + offset))
+                    .collect();
+                region.intersects_triangles(&positions)
+            })
+        })
 }
 
-pub fn push_bsp_model(
-    buffer: &mut Vec<u8>,
-    gltf: &mut Root,
+/// Load a brush model as nodes with a primitive for every visible face
+///
+/// The faces are grouped by layer and, when splitting the world into areas, by the area they are in.
+/// Faces that aren't in any area are put in area 0.
+pub fn load_bsp_model(
+    scene: &mut SceneBuilder,
     loader: &Loader,
     model: &Handle<Model>,
     offset: Vector,
+    world: bool,
+    visibility: &FaceVisibility,
     options: &ConvertOptions,
-) -> Node {
-    let primitives = model
-        .faces()
-        .filter(|face| face.is_visible())
-        .map(|face| push_bsp_face(buffer, gltf, loader, &face, options))
-        .collect();
-
-    let mesh = Mesh {
-        extensions: Default::default(),
-        extras: Default::default(),
-        name: None,
-        primitives,
-        weights: None,
-    };
-
-    let mesh_index = gltf.meshes.len() as u32;
-    gltf.meshes.push(mesh);
-
-    Node {
-        camera: None,
-        children: None,
-        extensions: Default::default(),
-        extras: Default::default(),
-        matrix: None,
-        mesh: Some(Index::new(mesh_index)),
-        name: Some("bsp".into()),
-        rotation: None,
-        scale: None,
-        translation: Some(map_coords(offset)),
-        skin: None,
-        weights: None,
+) -> Vec<(u16, Node)> {
+    let mut groups: BTreeMap<(Layer, u16), Vec<_>> = BTreeMap::new();
+    for (index, face, layer) in model_faces(model, offset, world, visibility, options) {
+        let area = match world && options.areas {
+            true => visibility.area(index).unwrap_or_default(),
+            false => 0,
+        };
+        groups.entry((layer, area)).or_default().push((index, face));
     }
+    groups
+        .into_iter()
+        .filter_map(|((layer, area), faces)| {
+            let primitives: Vec<_> = faces
+                .into_iter()
+                .filter_map(|(index, face)| {
+                    let mut primitive = face_primitive(scene, loader, &face, options);
+                    if let Some(region) = visibility.region().filter(|_| options.clip_region) {
+                        region.clip(&mut primitive, map_coords(offset));
+                        if primitive.positions.is_empty() {
+                            return None;
+                        }
+                    }
+                    if options.clusters {
+                        primitive.extras.insert("face".into(), index.into());
+                        let clusters = visibility.clusters(index);
+                        if !clusters.is_empty() {
+                            primitive.extras.insert("clusters".into(), clusters.into());
+                        }
+                    }
+                    Some(primitive)
+                })
+                .collect();
+            // clipping to the region can remove every face of the group
+            if primitives.is_empty() {
+                return None;
+            }
+            let mesh = scene.push_mesh(Mesh {
+                name: None,
+                primitives,
+            });
+            let node = Node {
+                name: Some("bsp".into()),
+                mesh: Some(mesh),
+                translation: map_coords(offset),
+                layer: Some(layer),
+                ..Node::default()
+            };
+            Some((area, node))
+        })
+        .collect()
 }
 
-pub fn push_bsp_face(
-    buffer: &mut Vec<u8>,
-    gltf: &mut Root,
+fn face_primitive(
+    scene: &mut SceneBuilder,
     loader: &Loader,
     face: &Handle<Face>,
     options: &ConvertOptions,
 ) -> Primitive {
-    let vertex_count = face.vertex_positions().count() as u64;
-
-    let buffer_start = buffer.len() as u64;
-
-    let (min, max) = bounding_box(face.vertex_positions());
-
     let texture = face.texture();
-    let vertices = face.vertex_positions().map(move |pos| BspVertexData {
-        position: map_coords(pos),
-        uv: texture.uv(pos),
-    });
+    let (positions, uvs) = face
+        .vertex_positions()
+        .map(|pos| (map_coords(pos), texture.uv(pos)))
+        .unzip();
 
-    let vertex_data = vertices.flat_map(bytemuck::cast::<_, [u8; size_of::<BspVertexData>()]>);
-    buffer.extend(vertex_data);
-
-    let vertex_buffer_view = View {
-        buffer: Index::new(0),
-        byte_length: USize64(buffer.len() as u64 - buffer_start),
-        byte_offset: Some(USize64(buffer_start)),
-        byte_stride: Some(Stride(size_of::<BspVertexData>())),
-        extensions: Default::default(),
-        extras: Default::default(),
-        name: None,
-        target: Some(Valid(Target::ArrayBuffer)),
-    };
-
-    let vertex_view = Index::new(gltf.buffer_views.len() as u32);
-    gltf.buffer_views.push(vertex_buffer_view);
-
-    let positions = Accessor {
-        buffer_view: Some(vertex_view),
-        byte_offset: Some(USize64(offset_of!(BspVertexData, position) as u64)),
-        count: USize64(vertex_count),
-        component_type: Valid(GenericComponentType(ComponentType::F32)),
-        extensions: Default::default(),
-        extras: Default::default(),
-        type_: Valid(Type::Vec3),
-        min: Some(Value::from(map_coords(min).to_vec())),
-        max: Some(Value::from(map_coords(max).to_vec())),
-        name: None,
-        normalized: false,
-        sparse: None,
-    };
-    let uvs = Accessor {
-        buffer_view: Some(vertex_view),
-        byte_offset: Some(USize64(offset_of!(BspVertexData, uv) as u64)),
-        count: USize64(vertex_count),
-        component_type: Valid(GenericComponentType(ComponentType::F32)),
-        extensions: Default::default(),
-        extras: Default::default(),
-        type_: Valid(Type::Vec2),
-        min: None,
-        max: None,
-        name: None,
-        normalized: false,
-        sparse: None,
-    };
-
-    let accessor_start = gltf.accessors.len() as u32;
-    gltf.accessors.push(positions);
-    gltf.accessors.push(uvs);
-
-    let material_index = if options.textures {
-        Some(push_or_get_material(
-            buffer,
-            gltf,
-            loader,
-            face.texture().name(),
-            options,
-        ))
-    } else {
-        None
-    };
+    let material = options
+        .textures
+        .then(|| scene.material(texture.name(), loader, options));
 
     Primitive {
-        attributes: {
-            let mut map = std::collections::BTreeMap::new();
-            map.insert(Valid(Semantic::Positions), Index::new(accessor_start));
-            map.insert(
-                Valid(Semantic::TexCoords(0)),
-                Index::new(accessor_start + 1),
-            );
-            map
-        },
-        extensions: Default::default(),
-        extras: Default::default(),
-        indices: None,
-        material: material_index,
-        mode: Valid(Mode::Triangles),
-        targets: None,
+        positions,
+        uvs,
+        material,
+        ..Primitive::default()
     }
 }
@@ -1,14 +1,19 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use miette::Context;
-use std::fs::{read, File};
-use std::path::PathBuf;
+use std::fs::{create_dir_all, read, write, File};
+use std::mem::take;
+use std::path::{Path, PathBuf};
 use tf_asset_loader::Loader;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
 use tracing_tree::HierarchicalLayer;
-use vbsp::Bsp;
-use vbsp_to_gltf::{export, ConvertOptions, Error};
+use vbsp::{Bsp, Vector};
+use vbsp_to_gltf::{
+    cluster_data, write_gltf, write_obj, ConvertOptions, CoordinateSystem, Culling, Error,
+    ExtraLumps, FallbackMode, Layer, ModelOptions, ProbeLayout, Recenter, Region, Scene,
+    SeparateWriter, TextureFormat, Units,
+};
 
 fn setup() {
     miette::set_panic_hook();
@@ -27,10 +32,133 @@ fn setup() {
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Path of the map file
-    source: PathBuf,
-    /// Path to save the glb to
+    /// Path of the map file(s), or path of models within the game files (e.g. "models/props_gameplay/cap_point_base.mdl")
+    #[clap(required = true, num_args = 1..)]
+    sources: Vec<PathBuf>,
+    /// Path to save the glb or gltf to, or the directory to save to when converting multiple maps or models
     target: PathBuf,
+    /// Output format, detected from the target extension by default
+    #[clap(long, value_enum)]
+    format: Option<OutputFormat>,
+    /// Scale factor for embedded textures
+    #[clap(long, default_value_t = 1.0)]
+    texture_scale: f32,
+    /// Maximum width or height of embedded textures
+    #[clap(long)]
+    max_texture_size: Option<u32>,
+    /// Image format to embed textures as
+    #[clap(long, value_enum, default_value_t)]
+    texture_format: TextureFormat,
+    /// Don't embed a png fallback for webp and KTX2 textures
+    #[clap(long)]
+    no_texture_fallback: bool,
+    /// Export animated textures as sprite sheets with animations
+    #[clap(long)]
+    texture_animations: bool,
+    /// Export the cubemaps of the map as reflection probes, with the images in the chosen layout
+    #[clap(long, value_enum)]
+    reflection_probes: Option<ProbeLayout>,
+    /// Add an image based light from the cubemap closest to the center of the map
+    #[clap(long)]
+    image_based_light: bool,
+    /// How to replace materials that fail to load
+    #[clap(long, value_enum, default_value_t)]
+    fallback: FallbackMode,
+    /// Material to use in place of materials that fail to load
+    #[clap(long)]
+    fallback_material: Option<String>,
+    /// Unit to export the map in
+    #[clap(long, value_enum, default_value_t)]
+    units: Units,
+    /// Custom number of meters per hammer unit, overrides `--units`
+    #[clap(long)]
+    unit_scale: Option<f32>,
+    /// Coordinate system to export the map in
+    #[clap(long, value_enum, default_value_t)]
+    coordinates: CoordinateSystem,
+    /// Move a point of the map to the origin
+    #[clap(long, value_enum)]
+    recenter: Option<Recenter>,
+    /// Class or target name of the entity to recenter on
+    #[clap(long)]
+    recenter_entity: Option<String>,
+    /// Point to recenter on, as "x y z" in map coordinates
+    #[clap(long)]
+    recenter_point: Option<Vector>,
+    /// Leave out world faces based on the visibility data of the map
+    #[clap(long, value_enum)]
+    culling: Option<Culling>,
+    /// Tag faces with their clusters and write the clusters, leaves and pvs to a json file next to the output
+    #[clap(long)]
+    clusters: bool,
+    /// Group the world faces and props by the area they are in
+    #[clap(long)]
+    areas: bool,
+    /// Write every area to a separate file next to the output, implies `--areas`
+    #[clap(long)]
+    area_files: bool,
+    /// Group the output into layers for world, brush entities, static props, dynamic props, skybox and tools
+    #[clap(long)]
+    layers: bool,
+    /// Layers to leave out of the output
+    #[clap(long, value_enum, value_delimiter = ',')]
+    exclude: Vec<Layer>,
+    /// Only export the part of the map within a box, minimum corner as "x y z" in map coordinates
+    #[clap(long, requires = "region_max")]
+    region_min: Option<Vector>,
+    /// Maximum corner of the region box as "x y z" in map coordinates
+    #[clap(long, requires = "region_min")]
+    region_max: Option<Vector>,
+    /// Only export the part of the map around the entity with the class or target name
+    #[clap(long, conflicts_with = "region_min")]
+    region_entity: Option<String>,
+    /// Radius of the region around `--region-entity`
+    #[clap(long, default_value_t = Region::DEFAULT_RADIUS)]
+    region_radius: f32,
+    /// Clip world faces at the boundary of the region
+    #[clap(long)]
+    clip_region: bool,
+    /// Export all skins of a model as material variants of a single mesh
+    #[clap(long)]
+    material_variants: bool,
+    /// Skin to use when converting models
+    #[clap(long, default_value_t = 0)]
+    skin: i32,
+    /// Model to use for every body part when converting models, as comma separated indices
+    #[clap(long, value_delimiter = ',')]
+    bodygroups: Vec<usize>,
+    /// Level of detail to use when converting models
+    #[clap(long, default_value_t = 0)]
+    lod: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    /// Binary gltf with everything embedded
+    Glb,
+    /// Text gltf with external buffer and image files
+    Gltf,
+    /// Wavefront obj with mtl materials and png textures
+    Obj,
+}
+
+impl OutputFormat {
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "glb" => Some(OutputFormat::Glb),
+            "gltf" => Some(OutputFormat::Gltf),
+            "obj" => Some(OutputFormat::Obj),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Glb => "glb",
+            OutputFormat::Gltf => "gltf",
+            OutputFormat::Obj => "obj",
+        }
+    }
 }
 
 fn main() -> miette::Result<()> {
@@ -38,20 +166,152 @@ fn main() -> miette::Result<()> {
 
     let args = Args::parse();
 
-    let mut loader = Loader::new().map_err(Error::from)?;
-    let data = read(args.source).map_err(Error::from)?;
-    let map = Bsp::read(&data).map_err(Error::from)?;
-    loader.add_source(map.pack.clone().into_zip());
+    let loader = Loader::new().map_err(Error::from)?;
+
+    let region = match (args.region_min, args.region_max, args.region_entity) {
+        (Some(min), Some(max), _) => Some(Region::Box {
+            min: min.into(),
+            max: max.into(),
+        }),
+        (_, _, Some(entity)) => Some(Region::Sphere {
+            entity,
+            radius: args.region_radius,
+        }),
+        _ => None,
+    };
+
+    let options = ConvertOptions {
+        texture_scale: args.texture_scale,
+        max_texture_size: args.max_texture_size,
+        texture_format: args.texture_format,
+        texture_fallback: !args.no_texture_fallback,
+        texture_animations: args.texture_animations,
+        reflection_probes: args.reflection_probes,
+        image_based_light: args.image_based_light,
+        fallback: args.fallback,
+        fallback_material: args.fallback_material,
+        units: args.units,
+        unit_scale: args.unit_scale,
+        coordinates: args.coordinates,
+        recenter: args.recenter,
+        recenter_entity: args.recenter_entity,
+        recenter_point: args.recenter_point.map(Into::into),
+        culling: args.culling,
+        clusters: args.clusters,
+        areas: args.areas || args.area_files,
+        layers: args.layers,
+        exclude: args.exclude,
+        region,
+        clip_region: args.clip_region,
+        material_variants: args.material_variants,
+        ..ConvertOptions::default()
+    };
+    let model_options = ModelOptions {
+        skin: args.skin,
+        bodygroups: args.bodygroups,
+        lod: args.lod,
+    };
+
+    let batch = args.sources.len() > 1;
+    let format = args
+        .format
+        .or_else(|| OutputFormat::from_path(&args.target).filter(|_| !batch))
+        .unwrap_or(OutputFormat::Glb);
+    // images are shared between all maps in a batch
+    let mut writer = SeparateWriter::new("textures");
 
-    let glb = export(map, &loader, ConvertOptions::default())?;
+    for source in &args.sources {
+        let target = if batch {
+            let stem = source.file_stem().unwrap_or_default();
+            args.target.join(stem).with_extension(format.extension())
+        } else {
+            args.target.clone()
+        };
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)
+                .map_err(Error::from)
+                .wrap_err("Failed to create target directory")?;
+        }
 
-    let writer = File::create(&args.target)
-        .map_err(Error::from)
-        .wrap_err("Failed to open target")?;
+        let mut scene = if is_model(source) {
+            let name = source.to_string_lossy().replace('\\', "/");
+            Scene::load_model(&loader, &name, &model_options, &options)?
+        } else {
+            let mut loader = loader.clone();
+            let data = read(source).map_err(Error::from)?;
+            let map = Bsp::read(&data).map_err(Error::from)?;
+            let lumps = ExtraLumps::read(&data)?;
+            loader.add_source(map.pack.clone().into_zip());
+
+            let scene = Scene::load(&map, &lumps, &loader, &options)?;
+            if args.clusters {
+                let clusters = cluster_data(&map, scene.origin_offset, &options);
+                let clusters = serde_json::to_vec(&clusters).expect("Serialization error");
+                write(target.with_extension("clusters.json"), clusters)
+                    .map_err(Error::from)
+                    .wrap_err("Failed to write cluster data")?;
+            }
+            scene
+        };
+        let report = take(&mut scene.report);
+        let scenes = if args.area_files {
+            let (rest, areas) = scene.split_areas();
+            let stem = target.file_stem().unwrap_or_default().to_string_lossy();
+            let area_targets = areas.into_iter().map(|(area, scene)| {
+                let name = format!("{stem}_area_{area}.{}", format.extension());
+                (target.with_file_name(name), scene)
+            });
+            [(target.clone(), rest)]
+                .into_iter()
+                .chain(area_targets)
+                .collect()
+        } else {
+            vec![(target.clone(), scene)]
+        };
+
+        for (target, scene) in scenes {
+            write_scene(scene, format, &options, &target, &mut writer)?;
+        }
+        if !report.is_empty() {
+            println!("{}:\n{report}", source.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Sources ending in `.mdl` are models to load from the game files
+fn is_model(source: &Path) -> bool {
+    source
+        .extension()
+        .is_some_and(|extension| extension == "mdl")
+}
 
-    glb.to_writer(writer)
-        .map_err(Error::from)
-        .wrap_err("glTF binary output error")?;
+fn write_scene(
+    scene: Scene,
+    format: OutputFormat,
+    options: &ConvertOptions,
+    target: &Path,
+    writer: &mut SeparateWriter,
+) -> miette::Result<()> {
+    match format {
+        OutputFormat::Obj => write_obj(scene, options, target).wrap_err("obj output error")?,
+        OutputFormat::Gltf => {
+            let output = write_gltf(scene, options);
+            writer.write(output, target).wrap_err("glTF output error")?;
+        }
+        OutputFormat::Glb => {
+            let output = write_gltf(scene, options);
+            let file = File::create(target)
+                .map_err(Error::from)
+                .wrap_err("Failed to open target")?;
 
+            output
+                .into_glb()
+                .to_writer(file)
+                .map_err(Error::from)
+                .wrap_err("glTF binary output error")?;
+        }
+    }
     Ok(())
 }
@@ -1,87 +1,262 @@
 use gltf_json as json;
 
-use crate::bsp::{bsp_models, push_bsp_model};
-use crate::prop::push_or_get_model;
-use crate::{ConvertOptions, Error};
-use cgmath::{Deg, Quaternion, Rotation3};
+use crate::animation::{push_material_animations, ANIMATION_POINTER_EXTENSION};
+use crate::cubemap::{push_image_based_light, push_reflection_probes, IMAGE_BASED_LIGHT_EXTENSION};
+use crate::gltf_builder::{
+    encode_textures, material_extensions_used, push_material, push_mesh, to_extras, AssetIndex,
+};
+use crate::prop::ModelOptions;
+use crate::report::ConversionReport;
+use crate::scene::{Area, Scene};
+use crate::{ConvertOptions, Error, ExtraLumps};
+use ahash::HashSet;
+use cgmath::{Deg, Matrix3, One, Quaternion, Rotation3, Vector3};
+use clap::ValueEnum;
 use gltf::Glb;
+use gltf_json::extensions::root::KhrMaterialsVariants;
+use gltf_json::extensions::scene::khr_materials_variants::Variant;
 use gltf_json::scene::UnitQuaternion;
 use gltf_json::validation::USize64;
-use gltf_json::{Buffer, Index, Node, Root, Scene};
+use gltf_json::{Buffer, Index, Node, Root, Scene as GltfScene, Value};
+use serde::Deserialize;
+use serde_json::json;
 use std::borrow::Cow;
 use tf_asset_loader::Loader;
-use vbsp::{Bsp, Entity};
+use tracing::warn;
+use vbsp::Bsp;
 
-pub fn export(bsp: Bsp, loader: &Loader, options: ConvertOptions) -> Result<Glb<'static>, Error> {
+/// The json and binary buffer of a converted map
+pub struct GltfOutput {
+    pub json: Value,
+    pub buffer: Vec<u8>,
+    pub report: ConversionReport,
+}
+
+impl GltfOutput {
+    pub fn into_glb(self) -> Glb<'static> {
+        let json_string = json::serialize::to_string(&self.json).expect("Serialization error");
+        let mut json_offset = json_string.len() as u32;
+        align_to_multiple_of_four(&mut json_offset);
+
+        Glb {
+            header: gltf::binary::Header {
+                magic: *b"glTF",
+                version: 2,
+                length: json_offset + self.buffer.len() as u32,
+            },
+            bin: Some(Cow::Owned(self.buffer)),
+            json: Cow::Owned(json_string.into_bytes()),
+        }
+    }
+}
+
+/// Convert the map into a glb, with a report of the assets that couldn't be converted
+///
+/// The parsed map doesn't contain the lumps that `vbsp` doesn't read, so compiled overlays, cubemaps and area portals
+/// are left out, use [`export_with_lumps`] to include them.
+pub fn export(
+    bsp: Bsp,
+    loader: &Loader,
+    options: ConvertOptions,
+) -> Result<(Glb<'static>, ConversionReport), Error> {
+    export_with_lumps(bsp, &ExtraLumps::default(), loader, options)
+}
+
+/// Convert the map into a glb using the lumps read from the map file
+pub fn export_with_lumps(
+    bsp: Bsp,
+    lumps: &ExtraLumps,
+    loader: &Loader,
+    options: ConvertOptions,
+) -> Result<(Glb<'static>, ConversionReport), Error> {
+    let mut output = convert_with_lumps(bsp, lumps, loader, options)?;
+    let report = std::mem::take(&mut output.report);
+    Ok((output.into_glb(), report))
+}
+
+/// Convert the map into gltf, without the lumps that `vbsp` doesn't read, see [`export`]
+pub fn convert(bsp: Bsp, loader: &Loader, options: ConvertOptions) -> Result<GltfOutput, Error> {
+    convert_with_lumps(bsp, &ExtraLumps::default(), loader, options)
+}
+
+/// Convert the map into gltf using the lumps read from the map file
+pub fn convert_with_lumps(
+    bsp: Bsp,
+    lumps: &ExtraLumps,
+    loader: &Loader,
+    options: ConvertOptions,
+) -> Result<GltfOutput, Error> {
+    let scene = Scene::load(&bsp, lumps, loader, &options)?;
+    Ok(write_gltf(scene, &options))
+}
+
+/// Convert a single model into a glb, with a report of the assets that couldn't be converted
+pub fn export_model(
+    loader: &Loader,
+    name: &str,
+    model_options: &ModelOptions,
+    options: ConvertOptions,
+) -> Result<(Glb<'static>, ConversionReport), Error> {
+    let mut output = convert_model(loader, name, model_options, options)?;
+    let report = std::mem::take(&mut output.report);
+    Ok((output.into_glb(), report))
+}
+
+pub fn convert_model(
+    loader: &Loader,
+    name: &str,
+    model_options: &ModelOptions,
+    options: ConvertOptions,
+) -> Result<GltfOutput, Error> {
+    let scene = Scene::load_model(loader, name, model_options, &options)?;
+    Ok(write_gltf(scene, &options))
+}
+
+/// Write the scene as gltf
+pub fn write_gltf(mut scene: Scene, options: &ConvertOptions) -> GltfOutput {
     let mut buffer = Vec::new();
 
     let mut root = Root::default();
 
-    for (model, offset) in bsp_models(&bsp)? {
-        let node = push_bsp_model(&mut buffer, &mut root, loader, &model, offset, &options);
-        root.nodes.push(node);
+    let mut assets = AssetIndex::default();
+    let (encoded, failed) = encode_textures(
+        &scene.materials,
+        options.texture_format,
+        options.texture_fallback,
+    );
+    scene.fallback_unencoded(&failed, options);
+    let animations = push_material_animations(&mut buffer, &mut root, &scene.materials);
+    for material in scene.materials {
+        let material = push_material(
+            &mut buffer,
+            &mut root,
+            &mut assets,
+            material,
+            &encoded,
+            options,
+        );
+        root.materials.push(material);
     }
-
-    let entity_props =
-        bsp.entities
+    for mesh in &scene.meshes {
+        push_mesh(&mut buffer, &mut root, mesh);
+    }
+    if !scene.variants.is_empty() {
+        let variants = scene
+            .variants
             .iter()
-            .flat_map(|ent| ent.parse())
-            .filter_map(|ent| match ent {
-                Entity::PropDynamic(prop) => Some(prop.as_prop_placement()),
-                Entity::PropPhysics(prop) => Some(prop.as_prop_placement()),
-                Entity::PropDynamicOverride(prop) => Some(prop.as_prop_placement()),
-                _ => None,
-            });
-    let static_props = bsp.static_props().map(|prop| prop.as_prop_placement());
-    for prop in static_props.chain(entity_props) {
-        if let Some(mesh) = push_or_get_model(
+            .map(|name| Variant { name: name.clone() })
+            .collect();
+        root.extensions
+            .get_or_insert_with(Default::default)
+            .khr_materials_variants = Some(KhrMaterialsVariants { variants });
+        root.extensions_used.push("KHR_materials_variants".into());
+    }
+    let rotation = options.coordinates.rotation();
+    for (index, mut node) in scene.nodes.into_iter().enumerate() {
+        if let Some(area) = scene.areas.iter().find(|area| area.node == index) {
+            node.extras
+                .insert("portals".into(), area_portals(area, rotation));
+        }
+        root.nodes.push(Node {
+            camera: None,
+            children: (!node.children.is_empty()).then(|| {
+                node.children
+                    .iter()
+                    .map(|child| Index::new(*child as u32))
+                    .collect()
+            }),
+            extensions: Default::default(),
+            extras: to_extras(node.extras),
+            matrix: None,
+            mesh: node.mesh.map(|mesh| Index::new(mesh as u32)),
+            name: node.name,
+            rotation: (node.rotation != Quaternion::one()).then_some(UnitQuaternion([
+                node.rotation.v.x,
+                node.rotation.v.y,
+                node.rotation.v.z,
+                node.rotation.s,
+            ])),
+            scale: (node.scale != 1.0).then_some([node.scale; 3]),
+            translation: (node.translation != [0.0; 3]).then_some(node.translation),
+            skin: None,
+            weights: None,
+        });
+    }
+
+    if let Some(extension) = options
+        .texture_format
+        .gltf_extension()
+        .filter(|_| !root.textures.is_empty())
+    {
+        root.extensions_used.push(extension.into());
+        // without png fallback, viewers can't show the textures without supporting the extension
+        if !options.texture_fallback {
+            root.extensions_required.push(extension.into());
+        }
+    }
+
+    root.extensions_used.extend(
+        material_extensions_used(&root)
+            .into_iter()
+            .map(String::from),
+    );
+
+    if let Some(layout) = options.reflection_probes {
+        let probes = push_reflection_probes(
             &mut buffer,
             &mut root,
-            loader,
-            prop.model,
-            prop.skin,
-            &options,
-        ) {
-            let rotation = prop.rotation;
-
-            let node = Node {
-                camera: None,
-                children: None,
-                extensions: Default::default(),
-                extras: Default::default(),
-                matrix: None,
-                mesh: Some(mesh),
-                name: Some(prop.model.into()),
-                rotation: Some(UnitQuaternion([
-                    rotation.v.x,
-                    rotation.v.y,
-                    rotation.v.z,
-                    rotation.s,
-                ])),
-                scale: None,
-                translation: Some(map_coords(prop.origin)),
-                skin: None,
-                weights: None,
-            };
-            root.nodes.push(node);
-        }
+            &mut assets,
+            &scene.cubemaps,
+            layout,
+            options,
+        );
+        root.nodes.extend(probes);
+    }
+    let image_based_light = scene
+        .environment
+        .and_then(|index| scene.cubemaps.get(index))
+        .and_then(|cubemap| {
+            push_image_based_light(
+                &mut buffer,
+                &mut root,
+                &mut assets,
+                cubemap,
+                rotation,
+                options,
+            )
+            .inspect_err(|e| warn!(error = ?e, path = cubemap.path, "failed to encode cubemap"))
+            .ok()
+        });
+    if image_based_light.is_some() {
+        root.extensions_used
+            .push(IMAGE_BASED_LIGHT_EXTENSION.into());
+    }
+
+    if !animations.is_empty() {
+        root.extensions_used
+            .push(ANIMATION_POINTER_EXTENSION.into());
     }
 
-    let node_indices = 0..root.nodes.len();
-    let root_rotation = Quaternion::<f32>::from_angle_y(Deg(90.0));
+    let child_nodes: HashSet<u32> = root
+        .nodes
+        .iter()
+        .flat_map(|node| node.children.iter().flatten())
+        .map(|child| child.value() as u32)
+        .collect();
+    let node_indices = (0..root.nodes.len() as u32).filter(|index| !child_nodes.contains(index));
     let root_node = Node {
         camera: None,
-        children: Some(node_indices.map(|index| Index::new(index as u32)).collect()),
+        children: Some(node_indices.map(Index::new).collect()),
         extensions: Default::default(),
         extras: Default::default(),
         matrix: None,
         mesh: None,
         name: None,
         rotation: Some(UnitQuaternion([
-            root_rotation.v.x,
-            root_rotation.v.y,
-            root_rotation.v.z,
-            root_rotation.s,
+            rotation.v.x,
+            rotation.v.y,
+            rotation.v.z,
+            rotation.s,
         ])),
         scale: None,
         translation: None,
@@ -91,10 +266,10 @@ pub fn export(bsp: Bsp, loader: &Loader, options: ConvertOptions) -> Result<Glb<
     let root_index = root.nodes.len();
     root.nodes.push(root_node);
 
-    root.scenes = vec![Scene {
+    root.scenes = vec![GltfScene {
         name: None,
         extensions: None,
-        extras: Default::default(),
+        extras: to_extras(scene.extras),
         nodes: vec![Index::new(root_index as u32)],
     }];
 
@@ -106,20 +281,39 @@ pub fn export(bsp: Bsp, loader: &Loader, options: ConvertOptions) -> Result<Glb<
         uri: None,
     });
 
-    let json_string = json::serialize::to_string(&root).expect("Serialization error");
-    let mut json_offset = json_string.len() as u32;
-    align_to_multiple_of_four(&mut json_offset);
+    let mut json_value = json::serialize::to_value(&root).expect("Serialization error");
+    if !animations.is_empty() {
+        json_value["animations"] = Value::Array(animations);
+    }
+    if let Some(light) = image_based_light {
+        json_value["extensions"][IMAGE_BASED_LIGHT_EXTENSION] = json!({ "lights": [light] });
+        json_value["scenes"][0]["extensions"][IMAGE_BASED_LIGHT_EXTENSION] = json!({ "light": 0 });
+    }
 
     pad_byte_vector(&mut buffer);
-    Ok(Glb {
-        header: gltf::binary::Header {
-            magic: *b"glTF",
-            version: 2,
-            length: json_offset + buffer.len() as u32,
-        },
-        bin: Some(Cow::Owned(buffer)),
-        json: Cow::Owned(json_string.into_bytes()),
-    })
+    GltfOutput {
+        json: json_value,
+        buffer,
+        report: scene.report,
+    }
+}
+
+/// The portals of an area, with the vertices in output coordinates
+fn area_portals(area: &Area, rotation: Quaternion<f32>) -> Value {
+    area.portals
+        .iter()
+        .map(|portal| {
+            let vertices: Vec<[f32; 3]> = portal
+                .vertices
+                .iter()
+                .map(|vertex| (rotation * Vector3::from(*vertex)).into())
+                .collect();
+            json!({
+                "other_area": portal.other_area,
+                "vertices": vertices,
+            })
+        })
+        .collect()
 }
 
 fn align_to_multiple_of_four(n: &mut u32) {
@@ -133,10 +327,140 @@ pub fn pad_byte_vector(vec: &mut Vec<u8>) {
 }
 
 // 1 hammer unit is ~1.905cm
-#[allow(dead_code)]
-pub const UNIT_SCALE: f32 = 1.0 / (1.905 * 100.0);
+pub const UNIT_SCALE: f32 = 1.905 / 100.0;
+
+// hammer units are 1 inch at map scale
+pub const MAP_UNIT_SCALE: f32 = 2.54 / 100.0;
+
+/// Unit the output is exported in
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Units {
+    /// Keep the hammer units, one unit per meter
+    #[default]
+    Hammer,
+    /// Meters at player scale, where one unit is 0.75 inch or 1.905 cm
+    Player,
+    /// Meters at map scale, where one unit is 1 inch
+    Map,
+}
+
+impl Units {
+    pub fn scale(&self) -> f32 {
+        match self {
+            Units::Hammer => 1.0,
+            Units::Player => UNIT_SCALE,
+            Units::Map => MAP_UNIT_SCALE,
+        }
+    }
+}
+
+/// Coordinate system the output is exported in
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum CoordinateSystem {
+    /// The gltf convention, +Y up with the map +X axis kept as +X
+    #[default]
+    YUp,
+    /// +Z up with the map facing -Y, as used by Blender
+    ZUp,
+    /// The coordinates used by the map itself, +Z up and +X forward
+    Source,
+}
+
+impl CoordinateSystem {
+    /// Rotation from the scene coordinates (see [`map_coords`]) into the output coordinates
+    ///
+    /// This is the only place the output convention is applied, writers apply it as root transform.
+    pub fn rotation(&self) -> Quaternion<f32> {
+        match self {
+            CoordinateSystem::YUp => Quaternion::from_angle_y(Deg(90.0)),
+            CoordinateSystem::ZUp => Quaternion::from(Matrix3::from_cols(
+                Vector3::unit_x(),
+                Vector3::unit_z(),
+                -Vector3::unit_y(),
+            )),
+            CoordinateSystem::Source => Quaternion::from(Matrix3::from_cols(
+                Vector3::unit_y(),
+                Vector3::unit_z(),
+                Vector3::unit_x(),
+            )),
+        }
+    }
+}
 
+/// Map source coordinates to the coordinates used in the scene
+///
+/// Rotations (like prop angles) are already expressed in these coordinates by `vbsp`.
 pub fn map_coords<C: Into<[f32; 3]>>(vec: C) -> [f32; 3] {
     let vec = vec.into();
     [vec[1], vec[2], vec[0]]
 }
+
+/// Map scene coordinates back to source coordinates, the inverse of [`map_coords`]
+pub fn unmap_coords(vec: [f32; 3]) -> [f32; 3] {
+    [vec[2], vec[0], vec[1]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where a point given in map coordinates ends up in the output coordinates
+    fn output_point(coordinates: CoordinateSystem, point: [f32; 3]) -> [f32; 3] {
+        (coordinates.rotation() * Vector3::from(map_coords(point))).into()
+    }
+
+    fn assert_point(actual: [f32; 3], expected: [f32; 3]) {
+        for axis in 0..3 {
+            assert!(
+                (actual[axis] - expected[axis]).abs() < 1e-5,
+                "{actual:?} != {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn y_up_coordinates() {
+        assert_point(
+            output_point(CoordinateSystem::YUp, [1.0, 0.0, 0.0]),
+            [1.0, 0.0, 0.0],
+        );
+        assert_point(
+            output_point(CoordinateSystem::YUp, [0.0, 1.0, 0.0]),
+            [0.0, 0.0, -1.0],
+        );
+        assert_point(
+            output_point(CoordinateSystem::YUp, [0.0, 0.0, 1.0]),
+            [0.0, 1.0, 0.0],
+        );
+    }
+
+    #[test]
+    fn z_up_coordinates() {
+        assert_point(
+            output_point(CoordinateSystem::ZUp, [1.0, 0.0, 0.0]),
+            [0.0, -1.0, 0.0],
+        );
+        assert_point(
+            output_point(CoordinateSystem::ZUp, [0.0, 1.0, 0.0]),
+            [1.0, 0.0, 0.0],
+        );
+        assert_point(
+            output_point(CoordinateSystem::ZUp, [0.0, 0.0, 1.0]),
+            [0.0, 0.0, 1.0],
+        );
+    }
+
+    #[test]
+    fn source_coordinates() {
+        let point = [1.0, 2.0, 3.0];
+        assert_point(output_point(CoordinateSystem::Source, point), point);
+    }
+
+    #[test]
+    fn unmap_coordinates() {
+        let point = [1.0, 2.0, 3.0];
+        assert_eq!(unmap_coords(map_coords(point)), point);
+    }
+}
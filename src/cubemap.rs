@@ -0,0 +1,636 @@
+use crate::convert::map_coords;
+use crate::encode::{encode_image, TextureFormat};
+use crate::gltf_builder::{push_image, to_extras, AssetIndex};
+use crate::lumps::{CubemapSample, ExtraLumps};
+use crate::materials::{highres_offset, mip_size, pick_mip};
+use crate::report::ConversionReport;
+use crate::{ConvertOptions, Error};
+use cgmath::{InnerSpace, Quaternion, Vector3};
+use clap::ValueEnum;
+use gltf_json::{Index, Node, Root, Value};
+use half::f16;
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgb, Rgb32FImage, Rgba, RgbaImage};
+use rayon::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Map};
+use std::f32::consts::PI;
+use tracing::warn;
+use vbsp::{Bsp, Vector};
+use vtf::image::{ImageFormat, VTFImage};
+use vtf::vtf::VTF;
+
+pub const IMAGE_BASED_LIGHT_EXTENSION: &str = "EXT_lights_image_based";
+
+const ENVMAP_FLAG: u32 = 0x4000;
+
+/// Layout of the images exported for reflection probes
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeLayout {
+    /// Six images, one for every face of the cube
+    Faces,
+    /// A single equirectangular image, rgbe encoded if the map contains hdr cubemaps
+    Equirectangular,
+}
+
+/// A pre-built cubemap from the pakfile
+pub struct Cubemap {
+    pub origin: Vector,
+    pub path: String,
+    /// Faces in gltf order
+    pub faces: Vec<Rgb32FImage>,
+    /// Whether the faces contain linear hdr colors instead of srgb
+    pub hdr: bool,
+}
+
+impl Cubemap {
+    fn size(&self) -> u32 {
+        self.faces[0].width()
+    }
+
+    /// Get the face in linear colors
+    fn linear_face(&self, face: usize) -> Rgb32FImage {
+        let mut image = self.faces[face].clone();
+        if !self.hdr {
+            for pixel in image.pixels_mut() {
+                pixel.0 = pixel.0.map(srgb_to_linear);
+            }
+        }
+        image
+    }
+
+    /// Get the face as 8 bit srgb
+    fn srgb_face(&self, face: usize) -> DynamicImage {
+        let mut image = self.faces[face].clone();
+        if self.hdr {
+            for pixel in image.pixels_mut() {
+                pixel.0 = pixel.0.map(linear_to_srgb);
+            }
+        }
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgb32F(image).into_rgb8())
+    }
+
+    /// Sample the cubemap in the given direction, using gltf cube face conventions
+    fn sample(&self, direction: Vector3<f32>) -> Rgb<f32> {
+        sample_faces(&self.faces, direction)
+    }
+
+    /// Project the cubemap into an equirectangular image, in linear colors if the cubemap is hdr
+    fn equirectangular(&self) -> Rgb32FImage {
+        let height = self.size() * 2;
+        let width = height * 2;
+        Rgb32FImage::from_fn(width, height, |x, y| {
+            let longitude = (x as f32 + 0.5) / width as f32 * 2.0 * PI - PI;
+            let latitude = PI / 2.0 - (y as f32 + 0.5) / height as f32 * PI;
+            let direction = Vector3::new(
+                latitude.cos() * longitude.sin(),
+                latitude.sin(),
+                latitude.cos() * longitude.cos(),
+            );
+            self.sample(direction)
+        })
+    }
+
+    /// Calculate the spherical harmonics coefficients for the irradiance of the cubemap
+    fn irradiance_coefficients(&self) -> [[f32; 3]; 9] {
+        // the exact resolution doesn't matter much for the low frequency irradiance
+        const SAMPLE_SIZE: u32 = 16;
+        // convolution with the cosine lobe for each band
+        const BAND_FACTORS: [f32; 9] = [
+            PI,
+            2.0 * PI / 3.0,
+            2.0 * PI / 3.0,
+            2.0 * PI / 3.0,
+            PI / 4.0,
+            PI / 4.0,
+            PI / 4.0,
+            PI / 4.0,
+            PI / 4.0,
+        ];
+
+        let mut coefficients = [[0.0; 3]; 9];
+        for face in 0..6 {
+            let image = DynamicImage::ImageRgb32F(self.linear_face(face))
+                .resize_exact(SAMPLE_SIZE, SAMPLE_SIZE, FilterType::Triangle)
+                .into_rgb32f();
+            for (x, y, pixel) in image.enumerate_pixels() {
+                let u = (x as f32 + 0.5) / SAMPLE_SIZE as f32 * 2.0 - 1.0;
+                let v = (y as f32 + 0.5) / SAMPLE_SIZE as f32 * 2.0 - 1.0;
+                let direction = face_direction(face, u, v);
+                let texel_size = 2.0 / SAMPLE_SIZE as f32;
+                let solid_angle = texel_size * texel_size / (1.0 + u * u + v * v).powf(1.5);
+                let [x, y, z] = direction.normalize().into();
+                let basis = [
+                    0.282095,
+                    0.488603 * y,
+                    0.488603 * z,
+                    0.488603 * x,
+                    1.092548 * x * y,
+                    1.092548 * y * z,
+                    0.315392 * (3.0 * z * z - 1.0),
+                    1.092548 * x * z,
+                    0.546274 * (x * x - y * y),
+                ];
+                for (coefficient, basis) in coefficients.iter_mut().zip(basis) {
+                    for (value, color) in coefficient.iter_mut().zip(pixel.0) {
+                        *value += color * basis * solid_angle;
+                    }
+                }
+            }
+        }
+
+        for (coefficient, factor) in coefficients.iter_mut().zip(BAND_FACTORS) {
+            *coefficient = coefficient.map(|value| value * factor);
+        }
+        coefficients
+    }
+}
+
+/// Map a direction to a cube face and the coordinates within the face, both ranging from -1 to 1
+///
+/// This follows the cube map conventions of both d3d and opengl, which is used by source in its z-up coordinates
+/// and by gltf in its y-up coordinates.
+fn cube_face(direction: Vector3<f32>) -> (usize, f32, f32) {
+    let abs = direction.map(f32::abs);
+    let (face, sc, tc, major) = if abs.x >= abs.y && abs.x >= abs.z {
+        match direction.x > 0.0 {
+            true => (0, -direction.z, -direction.y, abs.x),
+            false => (1, direction.z, -direction.y, abs.x),
+        }
+    } else if abs.y >= abs.z {
+        match direction.y > 0.0 {
+            true => (2, direction.x, direction.z, abs.y),
+            false => (3, direction.x, -direction.z, abs.y),
+        }
+    } else {
+        match direction.z > 0.0 {
+            true => (4, direction.x, -direction.y, abs.z),
+            false => (5, -direction.x, -direction.y, abs.z),
+        }
+    };
+    (face, sc / major, tc / major)
+}
+
+/// The direction for coordinates within a cube face, the inverse of [`cube_face`]
+fn face_direction(face: usize, u: f32, v: f32) -> Vector3<f32> {
+    match face {
+        0 => Vector3::new(1.0, -v, -u),
+        1 => Vector3::new(-1.0, -v, u),
+        2 => Vector3::new(u, 1.0, v),
+        3 => Vector3::new(u, -1.0, -v),
+        4 => Vector3::new(u, -v, 1.0),
+        _ => Vector3::new(-u, -v, -1.0),
+    }
+}
+
+fn sample_faces(faces: &[Rgb32FImage], direction: Vector3<f32>) -> Rgb<f32> {
+    let (face, u, v) = cube_face(direction);
+    let face = &faces[face];
+    let size = face.width() as f32;
+    let x = ((u + 1.0) / 2.0 * size) as u32;
+    let y = ((v + 1.0) / 2.0 * size) as u32;
+    *face.get_pixel(x.min(face.width() - 1), y.min(face.height() - 1))
+}
+
+/// Convert the faces of a source cubemap to gltf cube faces
+///
+/// Source looks up cubemaps with directions in its own coordinates, since the axes are swapped in gltf the faces
+/// are not only reordered but most of them also end up rotated.
+fn gltf_faces(source_faces: &[Rgb32FImage]) -> Vec<Rgb32FImage> {
+    let size = source_faces[0].width();
+    (0..6)
+        .map(|face| {
+            Rgb32FImage::from_fn(size, size, |x, y| {
+                let u = (x as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                let v = (y as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                let direction = face_direction(face, u, v);
+                let source_direction = Vector3::new(direction.z, direction.x, direction.y);
+                sample_faces(source_faces, source_direction)
+            })
+        })
+        .collect()
+}
+
+/// Load the cubemaps for all cubemap samples in the map
+pub fn load_cubemaps(
+    bsp: &Bsp,
+    lumps: &ExtraLumps,
+    options: &ConvertOptions,
+    report: &mut ConversionReport,
+) -> Vec<Cubemap> {
+    let samples = match lumps.cubemaps() {
+        Ok(samples) => samples,
+        Err(e) => {
+            report.invalid_lump("cubemaps", &e);
+            return Vec::new();
+        }
+    };
+    let pack_files = match lumps.pack_files() {
+        Ok(pack_files) => pack_files,
+        Err(e) => {
+            report.invalid_lump("pakfile", &e);
+            return Vec::new();
+        }
+    };
+    samples
+        .par_iter()
+        .filter_map(
+            |sample| match load_cubemap(bsp, &pack_files, sample, options) {
+                Ok(cubemap) => cubemap,
+                Err(e) => {
+                    warn!(error = ?e, origin = ?sample.origin, "failed to load cubemap");
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+fn load_cubemap(
+    bsp: &Bsp,
+    pack_files: &[String],
+    sample: &CubemapSample,
+    options: &ConvertOptions,
+) -> Result<Option<Cubemap>, Error> {
+    let [x, y, z] = sample.origin;
+    let name = format!("/c{x}_{y}_{z}");
+    let find = |extension: &str| {
+        pack_files.iter().find(|path| {
+            let path = path.to_ascii_lowercase();
+            path.starts_with("materials/maps/") && path.ends_with(&format!("{name}{extension}"))
+        })
+    };
+
+    // prefer the hdr version if we can decode it
+    for path in [find(".hdr.vtf"), find(".vtf")].into_iter().flatten() {
+        let Some(raw) = bsp.pack.get(path)? else {
+            continue;
+        };
+        match decode_cubemap(&raw, options) {
+            Ok((faces, hdr)) => {
+                return Ok(Some(Cubemap {
+                    origin: Vector {
+                        x: x as f32,
+                        y: y as f32,
+                        z: z as f32,
+                    },
+                    path: path.clone(),
+                    faces: gltf_faces(&faces),
+                    hdr,
+                }))
+            }
+            Err(e) => warn!(error = ?e, path, "failed to decode cubemap"),
+        }
+    }
+    Ok(None)
+}
+
+/// Decode the faces of a cubemap vtf, in the order they're stored in
+///
+/// The `vtf` crate doesn't handle the face layout of cubemaps, so the offset of each face is calculated here.
+fn decode_cubemap(
+    raw: &Vec<u8>,
+    options: &ConvertOptions,
+) -> Result<(Vec<Rgb32FImage>, bool), Error> {
+    let vtf = VTF::read(raw)?;
+    let header = &vtf.header;
+    if header.flags & ENVMAP_FLAG == 0 {
+        return Err(Error::Other("texture is not a cubemap".into()));
+    }
+    // older versions store an extra spheremap face
+    let face_count = if header.version[1] < 5 && header.first_frame != 0xffff {
+        7
+    } else {
+        6
+    };
+    let format = header.highres_image_format;
+    let frame_size =
+        |mip: u8| format.frame_size(mip_size(header.width, mip), mip_size(header.height, mip));
+
+    let (width, height) = options.texture_size(header.width as u32, header.height as u32);
+    let mip = pick_mip(header, width, height);
+    let mut offset = highres_offset(header)? as usize;
+    for smaller in mip + 1..header.mipmap_count {
+        offset += frame_size(smaller)? as usize * header.frames as usize * face_count;
+    }
+
+    let mut mip_header = header.clone();
+    mip_header.width = mip_size(header.width, mip) as u16;
+    mip_header.height = mip_size(header.height, mip) as u16;
+    mip_header.mipmap_count = 1;
+    mip_header.frames = 1;
+    let face_size = frame_size(mip)? as usize;
+
+    let faces = (0..6)
+        .map(|face| {
+            let face_offset = offset + face * face_size;
+            let image = match format {
+                ImageFormat::Rgba16161616f => decode_rgba16f(
+                    raw.get(face_offset..face_offset + face_size)
+                        .ok_or(Error::Other("cubemap data out of bounds".into()))?,
+                    mip_header.width as u32,
+                    mip_header.height as u32,
+                ),
+                _ => VTFImage::new(
+                    mip_header.clone(),
+                    format,
+                    mip_header.width,
+                    mip_header.height,
+                    raw,
+                    face_offset,
+                )
+                .decode(0)?
+                .into_rgb32f(),
+            };
+            if image.width() != width || image.height() != height {
+                Ok(DynamicImage::ImageRgb32F(image)
+                    .resize_exact(width, height, FilterType::CatmullRom)
+                    .into_rgb32f())
+            } else {
+                Ok(image)
+            }
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    Ok((faces, format == ImageFormat::Rgba16161616f))
+}
+
+fn decode_rgba16f(data: &[u8], width: u32, height: u32) -> Rgb32FImage {
+    let mut pixels = data.chunks_exact(8).map(|pixel| {
+        let channel =
+            |index: usize| f16::from_le_bytes([pixel[index * 2], pixel[index * 2 + 1]]).to_f32();
+        Rgb([channel(0), channel(1), channel(2)])
+    });
+    Rgb32FImage::from_fn(width, height, |_, _| pixels.next().unwrap_or(Rgb([0.0; 3])))
+}
+
+/// Push a node for every cubemap, referencing the images of the probe in its extras
+pub fn push_reflection_probes(
+    buffer: &mut Vec<u8>,
+    gltf: &mut Root,
+    assets: &mut AssetIndex,
+    cubemaps: &[Cubemap],
+    layout: ProbeLayout,
+    options: &ConvertOptions,
+) -> Vec<Node> {
+    cubemaps
+        .iter()
+        .filter_map(|cubemap| {
+            let mut probe = Map::new();
+            probe.insert("source".into(), cubemap.path.clone().into());
+            probe.insert("size".into(), cubemap.size().into());
+            probe.insert("hdr".into(), cubemap.hdr.into());
+            probe.insert("position".into(), json!(map_coords(cubemap.origin)));
+            match layout {
+                ProbeLayout::Faces => {
+                    let faces: Result<Vec<_>, Error> = (0..6)
+                        .map(|face| {
+                            push_or_get_face(buffer, gltf, assets, cubemap, face, options)
+                                .map(|image| image.value())
+                        })
+                        .collect();
+                    match faces {
+                        Ok(faces) => probe.insert("faces".into(), json!(faces)),
+                        Err(e) => {
+                            warn!(error = ?e, path = cubemap.path, "failed to encode cubemap");
+                            return None;
+                        }
+                    };
+                }
+                ProbeLayout::Equirectangular => {
+                    let image = match push_equirectangular(buffer, gltf, assets, cubemap, options) {
+                        Ok(image) => image,
+                        Err(e) => {
+                            warn!(error = ?e, path = cubemap.path, "failed to encode cubemap");
+                            return None;
+                        }
+                    };
+                    probe.insert("equirectangular".into(), image.value().into());
+                    if cubemap.hdr {
+                        probe.insert("encoding".into(), "rgbe".into());
+                    }
+                }
+            }
+
+            let mut extras = Map::new();
+            extras.insert("reflection_probe".into(), Value::Object(probe));
+
+            Some(Node {
+                camera: None,
+                children: None,
+                extensions: Default::default(),
+                extras: to_extras(extras),
+                matrix: None,
+                mesh: None,
+                name: Some("reflection_probe".into()),
+                rotation: None,
+                scale: None,
+                translation: Some(map_coords(cubemap.origin)),
+                skin: None,
+                weights: None,
+            })
+        })
+        .collect()
+}
+
+/// Find the cubemap closest to the center of the map
+pub fn environment_cubemap(bsp: &Bsp, cubemaps: &[Cubemap]) -> Option<usize> {
+    let world = bsp.models().next()?;
+    let center = (Vector3::from(world.mins) + Vector3::from(world.maxs)) / 2.0;
+    let distance = |cubemap: &Cubemap| (Vector3::from(cubemap.origin) - center).magnitude2();
+    (0..cubemaps.len()).min_by(|a, b| distance(&cubemaps[*a]).total_cmp(&distance(&cubemaps[*b])))
+}
+
+/// Create an image based light from a cubemap
+///
+/// Returns the light definition as raw json, since `gltf_json` doesn't support `EXT_lights_image_based`.
+pub fn push_image_based_light(
+    buffer: &mut Vec<u8>,
+    gltf: &mut Root,
+    assets: &mut AssetIndex,
+    cubemap: &Cubemap,
+    rotation: Quaternion<f32>,
+    options: &ConvertOptions,
+) -> Result<Value, Error> {
+    let faces = (0..6)
+        .map(|face| {
+            push_or_get_face(buffer, gltf, assets, cubemap, face, options)
+                .map(|image| image.value())
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    Ok(json!({
+        "name": cubemap.path,
+        "intensity": 1.0,
+        "rotation": [rotation.v.x, rotation.v.y, rotation.v.z, rotation.s],
+        "irradianceCoefficients": cubemap.irradiance_coefficients(),
+        "specularImageSize": cubemap.size(),
+        "specularImages": [faces],
+    }))
+}
+
+fn push_or_get_face(
+    buffer: &mut Vec<u8>,
+    gltf: &mut Root,
+    assets: &mut AssetIndex,
+    cubemap: &Cubemap,
+    face: usize,
+    options: &ConvertOptions,
+) -> Result<Index<gltf_json::Image>, Error> {
+    let name = format!("{}_{}", cubemap.path, face);
+    Ok(match assets.image(&name) {
+        Some(index) => index,
+        None => {
+            let encoded = encode_image(
+                &cubemap.srgb_face(face),
+                options.texture_format.image_format(),
+            )?;
+            push_image(
+                buffer,
+                gltf,
+                assets,
+                &encoded.data,
+                encoded.format.mime_type(),
+                &name,
+            )
+        }
+    })
+}
+
+fn push_equirectangular(
+    buffer: &mut Vec<u8>,
+    gltf: &mut Root,
+    assets: &mut AssetIndex,
+    cubemap: &Cubemap,
+    options: &ConvertOptions,
+) -> Result<Index<gltf_json::Image>, Error> {
+    let image = cubemap.equirectangular();
+    let name = format!("{}_equirectangular", cubemap.path);
+    if cubemap.hdr {
+        // gltf only allows 8 bit images, so hdr colors are packed into rgbe, which needs a lossless format
+        let image =
+            DynamicImage::ImageRgba8(RgbaImage::from_fn(image.width(), image.height(), |x, y| {
+                to_rgbe(*image.get_pixel(x, y))
+            }));
+        let format = match options.texture_format.image_format() {
+            TextureFormat::Jpeg => TextureFormat::Png,
+            format => format,
+        };
+        let encoded = encode_image(&image, format)?;
+        Ok(push_image(
+            buffer,
+            gltf,
+            assets,
+            &encoded.data,
+            encoded.format.mime_type(),
+            &name,
+        ))
+    } else {
+        let image = DynamicImage::ImageRgb8(DynamicImage::ImageRgb32F(image).into_rgb8());
+        let encoded = encode_image(&image, options.texture_format.image_format())?;
+        Ok(push_image(
+            buffer,
+            gltf,
+            assets,
+            &encoded.data,
+            encoded.format.mime_type(),
+            &name,
+        ))
+    }
+}
+
+/// Pack a linear hdr color into rgb with a shared exponent stored in alpha
+fn to_rgbe(color: Rgb<f32>) -> Rgba<u8> {
+    let max = color.0.into_iter().fold(0.0, f32::max);
+    if max <= 1e-32 {
+        return Rgba([0; 4]);
+    }
+    let exponent = max.log2().floor() as i32 + 1;
+    let scale = 256.0 / 2f32.powi(exponent);
+    let [r, g, b] = color
+        .0
+        .map(|channel| (channel.max(0.0) * scale).min(255.0) as u8);
+    Rgba([r, g, b, (exponent + 128).clamp(0, 255) as u8])
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Source faces of 2x2 pixels where every pixel has a unique value of `face * 10 + y * 2 + x`
+    fn source_faces() -> Vec<Rgb32FImage> {
+        (0..6)
+            .map(|face| Rgb32FImage::from_fn(2, 2, |x, y| Rgb([(face * 10 + y * 2 + x) as f32; 3])))
+            .collect()
+    }
+
+    #[test]
+    fn gltf_face_texels() {
+        let faces = gltf_faces(&source_faces());
+        // the top left texel of every gltf face, with the source face and texel it comes from
+        let expected = [
+            // +x is source back (+y), rotated 180 degrees
+            (2, 1, 1),
+            // -x is source front (-y), unchanged
+            (3, 0, 0),
+            // +y is source up (+z), rotated
+            (4, 0, 1),
+            // -y is source down (-z), rotated
+            (5, 0, 1),
+            // +z is source right (+x), rotated
+            (0, 0, 1),
+            // -z is source left (-x), rotated
+            (1, 1, 0),
+        ];
+        for (face, (source, x, y)) in expected.into_iter().enumerate() {
+            assert_eq!(
+                faces[face].get_pixel(0, 0).0[0],
+                (source * 10 + y * 2 + x) as f32,
+                "face {face}"
+            );
+        }
+    }
+
+    #[test]
+    fn cube_face_roundtrip() {
+        for face in 0..6 {
+            let (result, u, v) = cube_face(face_direction(face, 0.5, -0.25));
+            assert_eq!(result, face);
+            assert_eq!((u, v), (0.5, -0.25));
+        }
+    }
+
+    #[test]
+    fn rgbe_encoding() {
+        let decode = |rgbe: Rgba<u8>| {
+            rgbe.0[0..3]
+                .iter()
+                .map(|channel| *channel as f32 * 2f32.powi(rgbe.0[3] as i32 - 136))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(to_rgbe(Rgb([0.0; 3])), Rgba([0; 4]));
+        let color = decode(to_rgbe(Rgb([4.0, 1.0, 0.5])));
+        assert_eq!(color, [4.0, 1.0, 0.5]);
+        let color = decode(to_rgbe(Rgb([0.3, 20.0, 0.0])));
+        assert!((color[0] - 0.3).abs() < 0.1);
+        assert!((color[1] - 20.0).abs() < 0.1);
+    }
+}
@@ -0,0 +1,137 @@
+use crate::materials::decal_size;
+use crate::overlay::{overlay_mesh, quad_uvs, Overlay};
+use crate::report::ConversionReport;
+use crate::scene::{Mesh, SceneBuilder};
+use crate::ConvertOptions;
+use cgmath::{InnerSpace, Vector2, Vector3};
+use serde_json::json;
+use std::collections::BTreeMap;
+use tf_asset_loader::Loader;
+use tracing::warn;
+use vbsp::{Bsp, Vector};
+
+/// Maximum distance between a decal and the faces it's projected on
+const DECAL_DISTANCE: f32 = 8.0;
+
+/// Load all `infodecal` entities, projected onto the world faces near them
+pub fn decals(bsp: &Bsp, loader: &Loader, report: &mut ConversionReport) -> Vec<Overlay> {
+    let mut decals = Vec::new();
+    for (index, entity) in bsp.entities.iter().enumerate() {
+        if entity.prop("classname").ok() != Some("infodecal") {
+            continue;
+        }
+        let reference = format!("entity {index} (infodecal)");
+        let (Some(material), Some(origin)) = (
+            entity.prop("texture").ok(),
+            entity
+                .prop("origin")
+                .ok()
+                .and_then(|origin| origin.parse::<Vector>().ok()),
+        ) else {
+            report.skipped_entity("infodecal", "missing texture or origin", reference);
+            continue;
+        };
+        match decal_size(material, loader) {
+            Ok(size) => decals.extend(project_decal(
+                bsp,
+                material,
+                &reference,
+                Vector3::from(origin),
+                size,
+            )),
+            Err(e) => {
+                warn!(error = ?e, material, "failed to load decal material");
+                report.material_error(material, &e, vec![reference]);
+            }
+        }
+    }
+    decals
+}
+
+/// Project a decal onto every nearby plane of the world
+///
+/// Creates one overlay for every plane, containing all faces in that plane.
+fn project_decal(
+    bsp: &Bsp,
+    material: &str,
+    reference: &str,
+    origin: Vector3<f32>,
+    size: [f32; 2],
+) -> Vec<Overlay> {
+    let Some(world) = bsp.models().next() else {
+        return Vec::new();
+    };
+    let first_face = world.first_face as usize;
+
+    let mut planes: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (index, face) in world.faces().enumerate() {
+        let Some(plane) = bsp.plane(face.plane_num as usize) else {
+            continue;
+        };
+        // only project onto surfaces facing the decal
+        let distance = Vector3::from(plane.normal).dot(origin) - plane.dist;
+        if face.is_visible() && distance > -1.0 && distance < DECAL_DISTANCE {
+            planes
+                .entry(face.plane_num as usize)
+                .or_default()
+                .push(first_face + index);
+        }
+    }
+
+    let [half_width, half_height] = size.map(|size| size / 2.0);
+
+    planes
+        .into_iter()
+        .filter_map(|(plane, faces)| {
+            let plane = bsp.plane(plane)?;
+            let normal = Vector3::from(plane.normal);
+            let texture = bsp.face(faces[0])?.texture();
+            let [x, y, z, _] = texture.texture_transforms_u;
+
+            // orient the decal along the texture axis of the surface, like the engine does
+            let basis_v = normal.cross(Vector3::new(x, y, z)).normalize();
+            let basis_u = basis_v.cross(normal);
+            if !basis_v.x.is_finite() {
+                return None;
+            }
+
+            Some(Overlay {
+                material: material.into(),
+                reference: reference.into(),
+                faces,
+                origin: origin - normal * (normal.dot(origin) - plane.dist),
+                basis_u,
+                basis_v,
+                normal,
+                points: [
+                    Vector2::new(-half_width, -half_height),
+                    Vector2::new(-half_width, half_height),
+                    Vector2::new(half_width, half_height),
+                    Vector2::new(half_width, -half_height),
+                ],
+                uvs: quad_uvs([0.0, 1.0], [0.0, 1.0]),
+            })
+        })
+        .collect()
+}
+
+/// Create a mesh containing all decals
+pub fn decal_mesh(
+    scene: &mut SceneBuilder,
+    loader: &Loader,
+    bsp: &Bsp,
+    decals: &[Overlay],
+    options: &ConvertOptions,
+) -> Option<Mesh> {
+    let mut mesh = overlay_mesh(scene, loader, bsp, decals, options)?;
+
+    // gltf can't express polygon offset, leave a hint for renderers that support it
+    for primitive in mesh.primitives.iter_mut() {
+        primitive.extras.insert(
+            "polygon_offset".into(),
+            json!({"factor": -1.0, "units": -1.0}),
+        );
+    }
+
+    Some(mesh)
+}
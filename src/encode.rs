@@ -0,0 +1,159 @@
+use crate::ktx2::encode_ktx2;
+use crate::Error;
+pub use basis_universal::ColorSpace;
+use clap::ValueEnum;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{ColorType, DynamicImage, ImageEncoder};
+use serde::Deserialize;
+use std::borrow::Cow;
+
+/// Image format used for textures embedded in the output
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TextureFormat {
+    #[default]
+    Png,
+    Jpeg,
+    /// Lossless webp using `EXT_texture_webp`
+    Webp,
+    /// Basis universal compressed KTX2 using `KHR_texture_basisu`
+    Ktx2,
+}
+
+impl TextureFormat {
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            TextureFormat::Png => "image/png",
+            TextureFormat::Jpeg => "image/jpeg",
+            TextureFormat::Webp => "image/webp",
+            TextureFormat::Ktx2 => "image/ktx2",
+        }
+    }
+
+    pub fn file_extension(&self) -> &'static str {
+        match self {
+            TextureFormat::Png => "png",
+            TextureFormat::Jpeg => "jpg",
+            TextureFormat::Webp => "webp",
+            TextureFormat::Ktx2 => "ktx2",
+        }
+    }
+
+    /// The gltf extension needed to reference images in this format, if any
+    pub fn gltf_extension(&self) -> Option<&'static str> {
+        match self {
+            TextureFormat::Webp => Some("EXT_texture_webp"),
+            TextureFormat::Ktx2 => Some("KHR_texture_basisu"),
+            _ => None,
+        }
+    }
+
+    /// The format to use for images that are referenced directly instead of through a texture
+    ///
+    /// KTX2 images can only be used through the extension of a texture, so these use png instead.
+    pub fn image_format(&self) -> TextureFormat {
+        match self {
+            TextureFormat::Ktx2 => TextureFormat::Png,
+            format => *format,
+        }
+    }
+}
+
+const JPEG_QUALITY: u8 = 90;
+
+/// An encoded image with the format it was encoded as
+#[derive(Clone)]
+pub struct EncodedImage {
+    pub data: Vec<u8>,
+    pub format: TextureFormat,
+}
+
+/// An encoded texture image, with an optional png fallback for formats that need a gltf extension
+#[derive(Clone)]
+pub struct EncodedTexture {
+    pub image: EncodedImage,
+    pub fallback: Option<EncodedImage>,
+}
+
+/// Encode a texture image in the requested format
+///
+/// With `fallback`, formats that need a gltf extension also get a png copy, so the extension is optional in the output
+/// and viewers without support for it can use the png instead.
+/// The color space is only used by formats that store it, like KTX2.
+pub fn encode_texture(
+    image: &DynamicImage,
+    format: TextureFormat,
+    color_space: ColorSpace,
+    fallback: bool,
+) -> Result<EncodedTexture, Error> {
+    let fallback = format
+        .gltf_extension()
+        .filter(|_| fallback)
+        .map(|_| encode_image(image, TextureFormat::Png))
+        .transpose()?;
+    let image = match format {
+        TextureFormat::Ktx2 => EncodedImage {
+            data: encode_ktx2(image, color_space)?,
+            format,
+        },
+        format => encode_image(image, format)?,
+    };
+    Ok(EncodedTexture { image, fallback })
+}
+
+/// Encode an image in the requested format
+///
+/// Since jpeg doesn't do alpha, images with transparent pixels are encoded as png instead.
+/// KTX2 images are only valid as texture, see [`TextureFormat::image_format`].
+pub fn encode_image(image: &DynamicImage, format: TextureFormat) -> Result<EncodedImage, Error> {
+    if format == TextureFormat::Ktx2 {
+        return Ok(EncodedImage {
+            data: encode_ktx2(image, ColorSpace::Srgb)?,
+            format,
+        });
+    }
+    let format = match format {
+        TextureFormat::Jpeg if uses_alpha(image) => TextureFormat::Png,
+        format => format,
+    };
+    let image = match (format, image.color()) {
+        (TextureFormat::Jpeg, ColorType::Rgb8) => Cow::Borrowed(image),
+        (TextureFormat::Jpeg, _) => Cow::Owned(DynamicImage::ImageRgb8(image.to_rgb8())),
+        (_, ColorType::Rgba8 | ColorType::Rgb8) => Cow::Borrowed(image),
+        (_, color) if color.has_alpha() => Cow::Owned(DynamicImage::ImageRgba8(image.to_rgba8())),
+        _ => Cow::Owned(DynamicImage::ImageRgb8(image.to_rgb8())),
+    };
+
+    let mut buffer = Vec::new();
+    let bytes = image.as_bytes();
+    let (width, height) = (image.width(), image.height());
+    let color = image.color().into();
+
+    match format {
+        TextureFormat::Png => PngEncoder::new(&mut buffer).write_image(bytes, width, height, color),
+        TextureFormat::Jpeg => JpegEncoder::new_with_quality(&mut buffer, JPEG_QUALITY)
+            .write_image(bytes, width, height, color),
+        TextureFormat::Webp => {
+            WebPEncoder::new_lossless(&mut buffer).write_image(bytes, width, height, color)
+        }
+        TextureFormat::Ktx2 => unreachable!("ktx2 images are encoded separately"),
+    }?;
+
+    Ok(EncodedImage {
+        data: buffer,
+        format,
+    })
+}
+
+/// Check if any pixel of the image isn't fully opaque
+fn uses_alpha(image: &DynamicImage) -> bool {
+    if !image.color().has_alpha() {
+        return false;
+    }
+    match image.as_rgba8() {
+        Some(rgba) => rgba.pixels().any(|pixel| pixel.0[3] < 255),
+        None => image.to_rgba8().pixels().any(|pixel| pixel.0[3] < 255),
+    }
+}
@@ -24,6 +24,12 @@ pub enum Error {
     Loader(#[from] LoaderError),
     #[error(transparent)]
     Gltf(#[from] gltf::Error),
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
+    #[error("unsupported shader {0}")]
+    UnsupportedShader(String),
     #[error("resource {0} not found in vpks or pack")]
     ResourceNotFound(String),
+    #[error("failed to encode texture: {0}")]
+    Encode(String),
 }
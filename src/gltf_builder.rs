@@ -1,53 +1,329 @@
 use crate::convert::pad_byte_vector;
-use crate::materials::{load_material_fallback, MaterialData, TextureData};
-use crate::ConvertOptions;
-use gltf_json::buffer::View;
+use crate::encode::{encode_texture, ColorSpace, EncodedImage, EncodedTexture, TextureFormat};
+use crate::materials::{MaterialData, TextureData};
+use crate::scene;
+use crate::water::WaterParameters;
+use crate::{ConvertOptions, Error};
+use ahash::{HashMap, RandomState};
+use bytemuck::Pod;
+use gltf_json::accessor::{ComponentType, GenericComponentType, Type};
+use gltf_json::buffer::{Target, View};
+use gltf_json::extensions::material::{
+    AttenuationColor, AttenuationDistance, IndexOfRefraction, Ior, Specular, SpecularColorFactor,
+    ThicknessFactor, Transmission, TransmissionFactor, Unlit, Volume,
+};
 use gltf_json::extensions::texture::{
     TextureTransform, TextureTransformOffset, TextureTransformRotation, TextureTransformScale,
 };
 use gltf_json::image::MimeType;
-use gltf_json::material::{AlphaCutoff, AlphaMode, PbrBaseColorFactor, PbrMetallicRoughness};
+use gltf_json::material::{
+    AlphaCutoff, AlphaMode, NormalTexture, PbrBaseColorFactor, PbrMetallicRoughness, StrengthFactor,
+};
+use gltf_json::mesh::{Mode, Primitive, Semantic};
 use gltf_json::texture::Info;
 use gltf_json::validation::Checked::Valid;
 use gltf_json::validation::USize64;
-use gltf_json::{Extras, Image, Index, Material, Root, Texture};
-use image::codecs::png::PngEncoder;
-use image::{ColorType, DynamicImage, ImageEncoder};
+use gltf_json::{extensions, Accessor, Extras, Image, Index, Material, Mesh, Root, Texture};
+use rayon::prelude::*;
+use serde_json::value::to_raw_value;
+use serde_json::{json, Map, Value};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::f32::consts::PI;
-use tf_asset_loader::Loader;
+use tracing::error;
+
+/// Write a scene mesh, with separate buffer views for each vertex stream
+pub fn push_mesh(buffer: &mut Vec<u8>, gltf: &mut Root, mesh: &scene::Mesh) -> Index<Mesh> {
+    let primitives = mesh
+        .primitives
+        .iter()
+        .map(|primitive| push_primitive(buffer, gltf, primitive))
+        .collect();
+
+    let index = gltf.meshes.len() as u32;
+    gltf.meshes.push(Mesh {
+        extensions: Default::default(),
+        extras: Default::default(),
+        name: mesh.name.clone(),
+        primitives,
+        weights: None,
+    });
+    Index::new(index)
+}
 
-pub fn push_or_get_material(
+fn push_primitive(
     buffer: &mut Vec<u8>,
     gltf: &mut Root,
-    loader: &Loader,
-    material: &str,
-    options: &ConvertOptions,
-) -> Index<Material> {
-    let material = material.to_ascii_lowercase();
-    match get_material_index(&gltf.materials, &material) {
-        Some(index) => index,
-        None => {
-            let material = load_material_fallback(&material, &[String::new()], loader, options);
-            let index = gltf.materials.len() as u32;
-            let material = push_material(buffer, gltf, material);
-            gltf.materials.push(material);
-            Index::new(index)
+    primitive: &scene::Primitive,
+) -> Primitive {
+    let (min, max) = bounding_box(&primitive.positions);
+
+    let mut attributes = BTreeMap::new();
+    attributes.insert(
+        Valid(Semantic::Positions),
+        push_accessor(
+            buffer,
+            gltf,
+            &primitive.positions,
+            Type::Vec3,
+            Some(Target::ArrayBuffer),
+            Some((min, max)),
+        ),
+    );
+    attributes.insert(
+        Valid(Semantic::TexCoords(0)),
+        push_accessor(
+            buffer,
+            gltf,
+            &primitive.uvs,
+            Type::Vec2,
+            Some(Target::ArrayBuffer),
+            None,
+        ),
+    );
+    if let Some(normals) = &primitive.normals {
+        attributes.insert(
+            Valid(Semantic::Normals),
+            push_accessor(
+                buffer,
+                gltf,
+                normals,
+                Type::Vec3,
+                Some(Target::ArrayBuffer),
+                None,
+            ),
+        );
+    }
+
+    let indices = primitive.indices.as_ref().map(|indices| {
+        push_accessor(
+            buffer,
+            gltf,
+            indices,
+            Type::Scalar,
+            Some(Target::ElementArrayBuffer),
+            None,
+        )
+    });
+
+    // group the variants by the material they use
+    let mut variants: BTreeMap<usize, Vec<u32>> = BTreeMap::new();
+    for (variant, material) in primitive.variants.iter().enumerate() {
+        if let Some(material) = material {
+            variants.entry(*material).or_default().push(variant as u32);
+        }
+    }
+    let extensions = (!variants.is_empty()).then(|| extensions::mesh::Primitive {
+        khr_materials_variants: Some(extensions::mesh::KhrMaterialsVariants {
+            mappings: variants
+                .into_iter()
+                .map(|(material, variants)| extensions::mesh::Mapping {
+                    material: material as u32,
+                    variants,
+                })
+                .collect(),
+        }),
+        others: Default::default(),
+    });
+
+    Primitive {
+        attributes,
+        extensions,
+        extras: to_extras(primitive.extras.clone()),
+        indices,
+        material: primitive.material.map(|index| Index::new(index as u32)),
+        mode: Valid(Mode::Triangles),
+        targets: None,
+    }
+}
+
+/// Trait for the element types that can be written as accessor
+pub trait AccessorData: Pod {
+    const COMPONENT_TYPE: ComponentType;
+}
+
+impl AccessorData for u32 {
+    const COMPONENT_TYPE: ComponentType = ComponentType::U32;
+}
+
+impl AccessorData for f32 {
+    const COMPONENT_TYPE: ComponentType = ComponentType::F32;
+}
+
+impl<T: AccessorData, const N: usize> AccessorData for [T; N]
+where
+    [T; N]: Pod,
+{
+    const COMPONENT_TYPE: ComponentType = T::COMPONENT_TYPE;
+}
+
+/// Write the data into its own buffer view and create an accessor for it
+pub fn push_accessor<T: AccessorData>(
+    buffer: &mut Vec<u8>,
+    gltf: &mut Root,
+    data: &[T],
+    ty: Type,
+    target: Option<Target>,
+    bounds: Option<(Vec<f32>, Vec<f32>)>,
+) -> Index<Accessor> {
+    let buffer_start = buffer.len() as u64;
+    buffer.extend_from_slice(bytemuck::cast_slice(data));
+    let byte_length = buffer.len() as u64 - buffer_start;
+    pad_byte_vector(buffer);
+
+    let view = Index::new(gltf.buffer_views.len() as u32);
+    gltf.buffer_views.push(View {
+        buffer: Index::new(0),
+        byte_length: USize64(byte_length),
+        byte_offset: Some(USize64(buffer_start)),
+        byte_stride: None,
+        extensions: Default::default(),
+        extras: Default::default(),
+        name: None,
+        target: target.map(Valid),
+    });
+
+    let (min, max) = bounds.unzip();
+    let index = Index::new(gltf.accessors.len() as u32);
+    gltf.accessors.push(Accessor {
+        buffer_view: Some(view),
+        byte_offset: Some(USize64(0)),
+        count: USize64(data.len() as u64),
+        component_type: Valid(GenericComponentType(T::COMPONENT_TYPE)),
+        extensions: Default::default(),
+        extras: Default::default(),
+        type_: Valid(ty),
+        min: min.map(Value::from),
+        max: max.map(Value::from),
+        name: None,
+        normalized: false,
+        sparse: None,
+    });
+    index
+}
+
+fn bounding_box(positions: &[[f32; 3]]) -> (Vec<f32>, Vec<f32>) {
+    let mut min = [f32::MAX; 3];
+    let mut max = [f32::MIN; 3];
+    for position in positions {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(position[axis]);
+            max[axis] = max[axis].max(position[axis]);
         }
     }
+    (min.to_vec(), max.to_vec())
+}
+
+/// Hash indexes of the textures and images that are already written
+#[derive(Default)]
+pub struct AssetIndex {
+    textures: HashMap<String, Index<Texture>>,
+    image_names: HashMap<String, Index<Image>>,
+    /// Images by content hash, to share identical images with different names
+    image_content: HashMap<u64, Vec<Index<Image>>>,
+}
+
+impl AssetIndex {
+    pub fn image(&self, name: &str) -> Option<Index<Image>> {
+        self.image_names.get(name).copied()
+    }
 }
 
-fn get_material_index(materials: &[Material], path: &str) -> Option<Index<Material>> {
-    materials
+/// Encoded images for textures, by [`texture_key`]
+pub type EncodedTextures = HashMap<String, EncodedTexture>;
+
+/// Texture names are case-insensitive
+pub fn texture_key(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+/// Encode the textures of all materials in parallel
+///
+/// Returns the encoded textures and the errors of the textures that failed to encode, both by [`texture_key`].
+pub fn encode_textures(
+    materials: &[MaterialData],
+    format: TextureFormat,
+    fallback: bool,
+) -> (EncodedTextures, HashMap<String, Error>) {
+    let mut textures: Vec<(&TextureData, ColorSpace)> = materials
         .iter()
-        .enumerate()
-        .find_map(|(i, mat)| (mat.name.as_deref() == Some(path)).then_some(i))
-        .map(|i| Index::new(i as u32))
+        .flat_map(|material| {
+            let color = material.texture.iter().map(|tex| (tex, ColorSpace::Srgb));
+            let normal = material
+                .normal_map
+                .iter()
+                .map(|tex| (tex, ColorSpace::Linear));
+            color.chain(normal)
+        })
+        .collect();
+    textures.sort_by_cached_key(|(texture, _)| texture_key(&texture.name));
+    textures.dedup_by(|(a, _), (b, _)| texture_key(&a.name) == texture_key(&b.name));
+
+    let results: Vec<_> = textures
+        .into_par_iter()
+        .map(|(texture, color_space)| {
+            (
+                texture_key(&texture.name),
+                encode_texture(&texture.image, format, color_space, fallback),
+            )
+        })
+        .collect();
+
+    let mut encoded = EncodedTextures::default();
+    let mut failed = HashMap::default();
+    for (key, result) in results {
+        match result {
+            Ok(texture) => {
+                encoded.insert(key, texture);
+            }
+            Err(e) => {
+                failed.insert(key, e);
+            }
+        }
+    }
+    (encoded, failed)
 }
 
-pub fn push_material(buffer: &mut Vec<u8>, gltf: &mut Root, material: MaterialData) -> Material {
-    let texture_index = material
+pub fn push_material(
+    buffer: &mut Vec<u8>,
+    gltf: &mut Root,
+    assets: &mut AssetIndex,
+    material: MaterialData,
+    encoded: &EncodedTextures,
+    options: &ConvertOptions,
+) -> Material {
+    let sprite_sheet = material
         .texture
-        .map(|tex| push_or_get_texture(buffer, gltf, tex));
+        .as_ref()
+        .and_then(|tex| tex.sprite_sheet.clone());
+    let texture_index = material.texture.and_then(|tex| {
+        push_or_get_texture(
+            buffer,
+            gltf,
+            assets,
+            tex,
+            ColorSpace::Srgb,
+            encoded,
+            options,
+        )
+    });
+    let normal_texture = material.normal_map.and_then(|tex| {
+        Some(NormalTexture {
+            index: push_or_get_texture(
+                buffer,
+                gltf,
+                assets,
+                tex,
+                ColorSpace::Linear,
+                encoded,
+                options,
+            )?,
+            scale: 1.0,
+            tex_coord: 0,
+            extensions: None,
+            extras: Extras::default(),
+        })
+    });
 
     let alpha_mode = match (material.translucent, material.alpha_test.is_some()) {
         (true, _) => AlphaMode::Blend,
@@ -55,14 +331,69 @@ pub fn push_material(buffer: &mut Vec<u8>, gltf: &mut Root, material: MaterialDa
         _ => AlphaMode::Opaque,
     };
 
-    let transform = material.transform.map(|transform| TextureTransform {
+    let mut transform = material.transform.map(|transform| TextureTransform {
         offset: TextureTransformOffset(transform.translate),
         rotation: TextureTransformRotation(transform.rotate / 180.0 * PI),
         scale: TextureTransformScale(transform.scale),
         ..TextureTransform::default()
     });
+    if let Some(sprite_sheet) = &sprite_sheet {
+        // only show the first frame by default
+        let transform = transform.get_or_insert_with(TextureTransform::default);
+        let [scale_x, scale_y] = sprite_sheet.scale();
+        transform.scale.0[0] *= scale_x;
+        transform.scale.0[1] *= scale_y;
+    }
+    if material.scroll.is_some() {
+        // the scroll animation needs a transform to target
+        transform.get_or_insert_with(TextureTransform::default);
+    }
+
+    let mut extras = Map::new();
+    if let Some(sprite_sheet) = sprite_sheet {
+        extras.insert("sprite_sheet".into(), json!(sprite_sheet));
+    }
+    if let Some(scroll) = material.scroll {
+        extras.insert("texture_scroll".into(), json!(scroll));
+    }
+    if let Some(water) = &material.water {
+        extras.insert("water".into(), Value::Object(water.raw.clone()));
+    }
+
+    if let Some(blend) = material.blend {
+        extras.insert("blend".into(), json!(blend));
+    }
+    if let Some(fallback) = &material.fallback {
+        extras.insert("fallback".into(), json!(fallback));
+    }
+
+    let (metallic_factor, roughness_factor) =
+        match material.water.is_some() || material.transmissive {
+            true => (0.0, 0.1),
+            false => (1.0, 1.0),
+        };
+    let material_extensions = if let Some(water) = material.water {
+        Some(water_extensions(water))
+    } else if material.transmissive {
+        Some(extensions::material::Material {
+            transmission: Some(Transmission {
+                transmission_factor: TransmissionFactor(1.0),
+                ..Transmission::default()
+            }),
+            ..extensions::material::Material::default()
+        })
+    } else if material.unlit {
+        Some(extensions::material::Material {
+            unlit: Some(Unlit {}),
+            ..extensions::material::Material::default()
+        })
+    } else {
+        None
+    };
+
     let extensions = transform.map(|transform| gltf_json::extensions::texture::Info {
         texture_transform: Some(transform),
+        ..Default::default()
     });
 
     Material {
@@ -83,63 +414,213 @@ pub fn push_material(buffer: &mut Vec<u8>, gltf: &mut Root, material: MaterialDa
                 extensions,
                 extras: Extras::default(),
             }),
+            metallic_factor: StrengthFactor(metallic_factor),
+            roughness_factor: StrengthFactor(roughness_factor),
             ..PbrMetallicRoughness::default()
         },
+        normal_texture,
+        extensions: material_extensions,
+        extras: to_extras(extras),
         ..Material::default()
     }
 }
 
+/// Approximate the water fog and refraction with a transmissive volume
+fn water_extensions(water: WaterParameters) -> extensions::material::Material {
+    let fog_distance = (water.fog_end - water.fog_start).max(f32::MIN_POSITIVE);
+    extensions::material::Material {
+        transmission: Some(Transmission {
+            transmission_factor: TransmissionFactor(1.0),
+            ..Transmission::default()
+        }),
+        volume: Some(Volume {
+            thickness_factor: ThicknessFactor(water.fog_end.max(0.0)),
+            thickness_texture: None,
+            attenuation_distance: AttenuationDistance(fog_distance),
+            attenuation_color: AttenuationColor(water.volume_color),
+            extras: Extras::default(),
+        }),
+        ior: Some(Ior {
+            ior: IndexOfRefraction(1.33),
+            ..Ior::default()
+        }),
+        specular: Some(Specular {
+            specular_color_factor: SpecularColorFactor(water.reflect_tint),
+            ..Specular::default()
+        }),
+        ..extensions::material::Material::default()
+    }
+}
+
+/// List the material extensions that are used by any of the materials
+pub fn material_extensions_used(gltf: &Root) -> Vec<&'static str> {
+    let mut used = Vec::new();
+    let has_extension = |check: fn(&extensions::material::Material) -> bool| {
+        gltf.materials
+            .iter()
+            .filter_map(|material| material.extensions.as_ref())
+            .any(check)
+    };
+    let has_transform = gltf.materials.iter().any(|material| {
+        material
+            .pbr_metallic_roughness
+            .base_color_texture
+            .as_ref()
+            .and_then(|info| info.extensions.as_ref())
+            .is_some_and(|extensions| extensions.texture_transform.is_some())
+    });
+
+    if has_transform {
+        used.push("KHR_texture_transform");
+    }
+    if has_extension(|extensions| extensions.unlit.is_some()) {
+        used.push("KHR_materials_unlit");
+    }
+    if has_extension(|extensions| extensions.transmission.is_some()) {
+        used.push("KHR_materials_transmission");
+    }
+    if has_extension(|extensions| extensions.volume.is_some()) {
+        used.push("KHR_materials_volume");
+    }
+    if has_extension(|extensions| extensions.ior.is_some()) {
+        used.push("KHR_materials_ior");
+    }
+    if has_extension(|extensions| extensions.specular.is_some()) {
+        used.push("KHR_materials_specular");
+    }
+    used
+}
+
+/// Store a set of properties in the gltf extras, leaving the extras empty when there are none
+pub fn to_extras(properties: Map<String, Value>) -> Extras {
+    if properties.is_empty() {
+        None
+    } else {
+        Some(to_raw_value(&properties).expect("failed to serialize extras"))
+    }
+}
+
+/// Get the index of a texture, writing it if it's not used yet
+///
+/// Textures that fail to encode are left out of the material.
 fn push_or_get_texture(
     buffer: &mut Vec<u8>,
     gltf: &mut Root,
+    assets: &mut AssetIndex,
     texture: TextureData,
-) -> Index<Texture> {
-    match get_texture_index(&gltf.textures, &texture.name) {
-        Some(index) => index,
-        None => {
-            let index = gltf.textures.len() as u32;
-            let texture = push_texture(buffer, gltf, texture);
+    color_space: ColorSpace,
+    encoded: &EncodedTextures,
+    options: &ConvertOptions,
+) -> Option<Index<Texture>> {
+    let name = texture_key(&texture.name);
+    if let Some(index) = assets.textures.get(&name) {
+        return Some(*index);
+    }
+    let index = Index::new(gltf.textures.len() as u32);
+    let texture = push_texture(buffer, gltf, assets, texture, color_space, encoded, options);
+    match texture {
+        Ok(texture) => {
             gltf.textures.push(texture);
-            Index::new(index)
+            assets.textures.insert(name, index);
+            Some(index)
+        }
+        Err(e) => {
+            error!(error = ?e, texture = name, "failed to encode texture");
+            None
         }
     }
 }
 
-fn get_texture_index(textures: &[Texture], name: &str) -> Option<Index<Texture>> {
-    textures
-        .iter()
-        .enumerate()
-        .find_map(|(i, tex)| (tex.name.as_deref() == Some(name)).then_some(i))
-        .map(|i| Index::new(i as u32))
-}
-
-fn push_texture(buffer: &mut Vec<u8>, gltf: &mut Root, texture: TextureData) -> Texture {
-    let mut image = texture.image;
-    if image.color() != ColorType::Rgba8 && image.color() != ColorType::Rgb8 {
-        if image.color().has_alpha() {
-            image = DynamicImage::ImageRgba8(image.into_rgba8());
-        } else {
-            image = DynamicImage::ImageRgb8(image.into_rgb8());
-        }
+fn push_texture(
+    buffer: &mut Vec<u8>,
+    gltf: &mut Root,
+    assets: &mut AssetIndex,
+    texture: TextureData,
+    color_space: ColorSpace,
+    encoded: &EncodedTextures,
+    options: &ConvertOptions,
+) -> Result<Texture, Error> {
+    let encoded = match encoded.get(&texture_key(&texture.name)) {
+        Some(encoded) => Cow::Borrowed(encoded),
+        None => Cow::Owned(encode_texture(
+            &texture.image,
+            options.texture_format,
+            color_space,
+            options.texture_fallback,
+        )?),
+    };
+    let mut push = |image: &EncodedImage| {
+        push_image(
+            buffer,
+            gltf,
+            assets,
+            &image.data,
+            image.format.mime_type(),
+            &texture.name,
+        )
+    };
+    let image = push(&encoded.image);
+
+    // formats that need an extension are only referenced through the extension, with the png fallback as `source`
+    // when there is one, without fallback the extension is required and `source` is left out, which gltf_json
+    // does for an index of `u32::MAX`
+    let extension = encoded.image.format.gltf_extension();
+    let source = match (&encoded.fallback, extension) {
+        (Some(fallback), _) => push(fallback),
+        (None, Some(_)) => Index::new(u32::MAX),
+        (None, None) => image,
+    };
+    let extensions = extension.map(|extension| {
+        let mut others = Map::new();
+        others.insert(extension.into(), json!({ "source": image.value() }));
+        gltf_json::extensions::texture::Texture { others }
+    });
+
+    Ok(Texture {
+        name: Some(texture.name),
+        sampler: None,
+        source,
+        extensions,
+        extras: Default::default(),
+    })
+}
+
+/// Get the bytes of an image embedded in the buffer
+fn image_data<'a>(buffer: &'a [u8], gltf: &Root, image: Index<Image>) -> Option<&'a [u8]> {
+    let view = gltf.get(gltf.get(image)?.buffer_view?)?;
+    let start = view.byte_offset?.0 as usize;
+    buffer.get(start..start + view.byte_length.0 as usize)
+}
+
+/// Embed an encoded image in the buffer
+///
+/// Images with the same content as an image that was already embedded reuse the existing image.
+pub fn push_image(
+    buffer: &mut Vec<u8>,
+    gltf: &mut Root,
+    assets: &mut AssetIndex,
+    data: &[u8],
+    mime_type: &str,
+    name: &str,
+) -> Index<Image> {
+    let hash = RandomState::with_seeds(1, 2, 3, 4).hash_one(data);
+    let existing = assets
+        .image_content
+        .get(&hash)
+        .into_iter()
+        .flatten()
+        .find(|index| image_data(buffer, gltf, **index) == Some(data));
+    if let Some(index) = existing {
+        let index = *index;
+        assets.image_names.insert(name.into(), index);
+        return index;
     }
+
     let buffer_start = buffer.len() as u64;
     let view_start = gltf.buffer_views.len() as u32;
     let image_start = gltf.images.len() as u32;
-    let image_buffer_size =
-        (image.color().bits_per_pixel() / 8) as u32 * image.width() * image.height();
-
-    let mut png_buffer = Vec::new();
-    let encoder = PngEncoder::new(&mut png_buffer);
-    encoder
-        .write_image(
-            &image.as_bytes()[0..image_buffer_size as usize],
-            image.width(),
-            image.height(),
-            image.color().into(),
-        )
-        .expect("failed to encode");
 
-    buffer.extend_from_slice(&png_buffer);
+    buffer.extend_from_slice(data);
 
     let byte_length = buffer.len() as u64 - buffer_start;
     pad_byte_vector(buffer);
@@ -151,7 +632,7 @@ fn push_texture(buffer: &mut Vec<u8>, gltf: &mut Root, texture: TextureData) ->
         byte_stride: None,
         extensions: Default::default(),
         extras: Default::default(),
-        name: Some(texture.name.clone()),
+        name: Some(name.into()),
         target: None,
     };
 
@@ -159,19 +640,124 @@ fn push_texture(buffer: &mut Vec<u8>, gltf: &mut Root, texture: TextureData) ->
 
     let image = Image {
         buffer_view: Some(Index::new(view_start)),
-        mime_type: Some(MimeType("image/png".into())),
-        name: Some(texture.name.clone()),
+        mime_type: Some(MimeType(mime_type.into())),
+        name: Some(name.into()),
         uri: None,
         extensions: None,
         extras: Default::default(),
     };
     gltf.images.push(image);
 
-    Texture {
-        name: Some(texture.name),
-        sampler: None,
-        source: Index::new(image_start),
-        extensions: None,
-        extras: Default::default(),
+    let index = Index::new(image_start);
+    assets.image_content.entry(hash).or_default().push(index);
+    assets.image_names.insert(name.into(), index);
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, RgbImage};
+
+    fn texture_json(format: TextureFormat, fallback: bool) -> (Value, Root) {
+        let texture = TextureData {
+            name: "brick/wall".into(),
+            image: DynamicImage::ImageRgb8(RgbImage::new(4, 4)),
+            sprite_sheet: None,
+        };
+        let mut gltf = Root::default();
+        let texture = push_texture(
+            &mut Vec::new(),
+            &mut gltf,
+            &mut AssetIndex::default(),
+            texture,
+            ColorSpace::Srgb,
+            &EncodedTextures::default(),
+            &ConvertOptions {
+                texture_format: format,
+                texture_fallback: fallback,
+                ..ConvertOptions::default()
+            },
+        )
+        .unwrap();
+        (serde_json::to_value(texture).unwrap(), gltf)
+    }
+
+    fn mime_type(gltf: &Root, image: &Value) -> String {
+        let image = &gltf.images[image.as_u64().unwrap() as usize];
+        image.mime_type.as_ref().unwrap().0.clone()
+    }
+
+    #[test]
+    fn group_variants_by_material() {
+        let primitive = scene::Primitive {
+            positions: vec![[0.0; 3], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+            uvs: vec![[0.0; 2]; 3],
+            material: Some(1),
+            variants: vec![Some(1), Some(0), Some(1), None, Some(2)],
+            ..scene::Primitive::default()
+        };
+        let primitive = push_primitive(&mut Vec::new(), &mut Root::default(), &primitive);
+        let json = serde_json::to_value(primitive).unwrap();
+        assert_eq!(
+            json["extensions"]["KHR_materials_variants"]["mappings"],
+            serde_json::json!([
+                {"material": 0, "variants": [1]},
+                {"material": 1, "variants": [0, 2]},
+                {"material": 2, "variants": [4]},
+            ])
+        );
+    }
+
+    #[test]
+    fn primitive_without_variants() {
+        let primitive = scene::Primitive {
+            positions: vec![[0.0; 3]],
+            uvs: vec![[0.0; 2]],
+            ..scene::Primitive::default()
+        };
+        let primitive = push_primitive(&mut Vec::new(), &mut Root::default(), &primitive);
+        assert!(primitive.extensions.is_none());
+    }
+
+    #[test]
+    fn png_texture() {
+        let (json, gltf) = texture_json(TextureFormat::Png, true);
+        assert_eq!(mime_type(&gltf, &json["source"]), "image/png");
+        assert!(json.get("extensions").is_none());
+        assert_eq!(gltf.images.len(), 1);
+    }
+
+    #[test]
+    fn webp_texture_with_png_fallback() {
+        let (json, gltf) = texture_json(TextureFormat::Webp, true);
+        assert_eq!(mime_type(&gltf, &json["source"]), "image/png");
+        assert_eq!(
+            mime_type(&gltf, &json["extensions"]["EXT_texture_webp"]["source"]),
+            "image/webp"
+        );
+        assert_eq!(gltf.images.len(), 2);
+    }
+
+    #[test]
+    fn ktx2_texture_with_png_fallback() {
+        let (json, gltf) = texture_json(TextureFormat::Ktx2, true);
+        assert_eq!(mime_type(&gltf, &json["source"]), "image/png");
+        assert_eq!(
+            mime_type(&gltf, &json["extensions"]["KHR_texture_basisu"]["source"]),
+            "image/ktx2"
+        );
+        assert_eq!(gltf.images.len(), 2);
+    }
+
+    #[test]
+    fn ktx2_texture_without_fallback() {
+        let (json, gltf) = texture_json(TextureFormat::Ktx2, false);
+        assert!(json.get("source").is_none());
+        assert_eq!(
+            mime_type(&gltf, &json["extensions"]["KHR_texture_basisu"]["source"]),
+            "image/ktx2"
+        );
+        assert_eq!(gltf.images.len(), 1);
     }
 }
@@ -0,0 +1,269 @@
+use crate::Error;
+use basis_universal::{
+    BasisTextureFormat, ColorSpace, Compressor, CompressorParams, UASTC_QUALITY_DEFAULT,
+};
+use image::imageops::FilterType;
+use image::DynamicImage;
+use std::borrow::Cow;
+
+const IDENTIFIER: [u8; 12] = *b"\xABKTX 20\xBB\r\n\x1A\n";
+const HEADER_SIZE: usize = 80;
+const LEVEL_INDEX_SIZE: usize = 24;
+const WRITER: &str = "vbsp-to-gltf";
+
+/// UASTC blocks are 4x4 pixels stored in 16 bytes
+const BLOCK_SIZE: u32 = 4;
+const BLOCK_BYTES: usize = 16;
+
+// values from the khronos data format specification
+const KHR_DF_MODEL_UASTC: u32 = 166;
+const KHR_DF_PRIMARIES_BT709: u32 = 1;
+const KHR_DF_TRANSFER_LINEAR: u32 = 1;
+const KHR_DF_TRANSFER_SRGB: u32 = 2;
+const KHR_DF_CHANNEL_UASTC_RGB: u32 = 0;
+const KHR_DF_CHANNEL_UASTC_RGBA: u32 = 3;
+
+// offsets into the raw `basis_file_header` and `basis_slice_desc` structures
+const BASIS_TOTAL_SLICES: usize = 14;
+const BASIS_SLICE_DESC_OFFSET: usize = 65;
+const SLICE_DESC_SIZE: usize = 23;
+const SLICE_LEVEL: usize = 3;
+const SLICE_BLOCKS_X: usize = 9;
+const SLICE_BLOCKS_Y: usize = 11;
+const SLICE_FILE_OFFSET: usize = 13;
+const SLICE_FILE_SIZE: usize = 17;
+
+/// Encode an image as UASTC compressed KTX2 with a full mip chain, as used by `KHR_texture_basisu`
+///
+/// The extension requires the size to be a multiple of 4, other images are resized.
+pub fn encode_ktx2(image: &DynamicImage, color_space: ColorSpace) -> Result<Vec<u8>, Error> {
+    let (width, height) = (
+        image.width().next_multiple_of(BLOCK_SIZE),
+        image.height().next_multiple_of(BLOCK_SIZE),
+    );
+    let image = match (width, height) == (image.width(), image.height()) {
+        true => Cow::Borrowed(image),
+        false => Cow::Owned(image.resize_exact(width, height, FilterType::CatmullRom)),
+    };
+    let rgba = match image.as_rgba8() {
+        Some(rgba) => Cow::Borrowed(rgba),
+        None => Cow::Owned(image.to_rgba8()),
+    };
+
+    let mut params = CompressorParams::new();
+    params.set_basis_format(BasisTextureFormat::UASTC4x4);
+    params.set_uastc_quality_level(UASTC_QUALITY_DEFAULT);
+    params.set_color_space(color_space);
+    params.set_generate_mipmaps(true);
+    params.set_print_status_to_stdout(false);
+    params
+        .source_image_mut(0)
+        .init(rgba.as_raw(), width, height, 4);
+
+    let mut compressor = Compressor::new(1);
+    // safety: the parameters only contain the source image and options set through the typed setters
+    unsafe {
+        if !compressor.init(&params) {
+            return Err(Error::Encode(
+                "failed to initialize basis compressor".into(),
+            ));
+        }
+        compressor
+            .process()
+            .map_err(|e| Error::Encode(format!("basis compression failed: {e:?}")))?;
+    }
+
+    let levels = uastc_levels(compressor.basis_file())?;
+    Ok(write_ktx2(
+        width,
+        height,
+        &levels,
+        color_space,
+        compressor.any_source_image_has_alpha(),
+    ))
+}
+
+/// Get the raw UASTC blocks of every mip level from a basis file
+fn uastc_levels(basis: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let invalid = || Error::Encode("invalid basis file".into());
+    let read = |offset: usize, size: usize| -> Result<usize, Error> {
+        let bytes = basis.get(offset..offset + size).ok_or_else(invalid)?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |value, byte| (value << 8) | *byte as usize))
+    };
+
+    let slice_count = read(BASIS_TOTAL_SLICES, 3)?;
+    let slice_offset = read(BASIS_SLICE_DESC_OFFSET, 4)?;
+    let mut levels = Vec::with_capacity(slice_count);
+    for slice in 0..slice_count {
+        let desc = slice_offset + slice * SLICE_DESC_SIZE;
+        // a single image has its slices in mip order
+        if read(desc + SLICE_LEVEL, 1)? != slice {
+            return Err(invalid());
+        }
+        let blocks = read(desc + SLICE_BLOCKS_X, 2)? * read(desc + SLICE_BLOCKS_Y, 2)?;
+        let offset = read(desc + SLICE_FILE_OFFSET, 4)?;
+        let size = read(desc + SLICE_FILE_SIZE, 4)?;
+        if size != blocks * BLOCK_BYTES {
+            return Err(invalid());
+        }
+        levels.push(basis.get(offset..offset + size).ok_or_else(invalid)?);
+    }
+    Ok(levels)
+}
+
+/// Write the mip levels into a KTX2 container, without supercompression
+fn write_ktx2(
+    width: u32,
+    height: u32,
+    levels: &[&[u8]],
+    color_space: ColorSpace,
+    alpha: bool,
+) -> Vec<u8> {
+    let dfd = data_format_descriptor(color_space, alpha);
+    let kvd = key_values(&[("KTXwriter", WRITER)]);
+
+    let dfd_offset = HEADER_SIZE + levels.len() * LEVEL_INDEX_SIZE;
+    let kvd_offset = dfd_offset + dfd.len();
+    let mut data_offset = (kvd_offset + kvd.len()).next_multiple_of(BLOCK_BYTES);
+
+    // the level index starts with the largest level, while the data starts with the smallest
+    let mut level_offsets = vec![0; levels.len()];
+    for (level, data) in levels.iter().enumerate().rev() {
+        level_offsets[level] = data_offset;
+        data_offset = (data_offset + data.len()).next_multiple_of(BLOCK_BYTES);
+    }
+
+    let mut out = Vec::with_capacity(data_offset);
+    out.extend_from_slice(&IDENTIFIER);
+    for value in [
+        0, // vkFormat, undefined for basis universal
+        1, // typeSize
+        width,
+        height,
+        0, // pixelDepth
+        0, // layerCount
+        1, // faceCount
+        levels.len() as u32,
+        0, // supercompressionScheme
+        dfd_offset as u32,
+        dfd.len() as u32,
+        kvd_offset as u32,
+        kvd.len() as u32,
+    ] {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    // no supercompression global data
+    out.extend_from_slice(&[0; 16]);
+
+    for (data, offset) in levels.iter().zip(&level_offsets) {
+        for value in [*offset, data.len(), data.len()] {
+            out.extend_from_slice(&(value as u64).to_le_bytes());
+        }
+    }
+    out.extend_from_slice(&dfd);
+    out.extend_from_slice(&kvd);
+
+    for (level, data) in levels.iter().enumerate().rev() {
+        out.resize(level_offsets[level], 0);
+        out.extend_from_slice(data);
+    }
+    out
+}
+
+/// The basic data format descriptor for UASTC blocks
+fn data_format_descriptor(color_space: ColorSpace, alpha: bool) -> Vec<u8> {
+    let transfer = match color_space {
+        ColorSpace::Linear => KHR_DF_TRANSFER_LINEAR,
+        ColorSpace::Srgb => KHR_DF_TRANSFER_SRGB,
+    };
+    let channel = match alpha {
+        true => KHR_DF_CHANNEL_UASTC_RGBA,
+        false => KHR_DF_CHANNEL_UASTC_RGB,
+    };
+    let block_size = 24 + 16;
+    let words: [u32; 11] = [
+        4 + block_size,         // total size
+        0,                      // vendor and descriptor type
+        2 | (block_size << 16), // version and block size
+        KHR_DF_MODEL_UASTC | (KHR_DF_PRIMARIES_BT709 << 8) | (transfer << 16),
+        (BLOCK_SIZE - 1) | ((BLOCK_SIZE - 1) << 8), // texel block dimensions
+        BLOCK_BYTES as u32,                         // bytes in plane 0
+        0,
+        // the single sample covers the full 128 bit block
+        (127 << 16) | (channel << 24),
+        0,        // sample position
+        0,        // sample lower
+        u32::MAX, // sample upper
+    ];
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+/// Encode the key value data, with every entry padded to 4 bytes
+fn key_values(entries: &[(&str, &str)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (key, value) in entries {
+        let length = key.len() + value.len() + 2;
+        out.extend_from_slice(&(length as u32).to_le_bytes());
+        out.extend_from_slice(key.as_bytes());
+        out.push(0);
+        out.extend_from_slice(value.as_bytes());
+        out.push(0);
+        out.resize(out.len().next_multiple_of(4), 0);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_u64(data: &[u8], offset: usize) -> usize {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap()) as usize
+    }
+
+    #[test]
+    fn encode_mip_chain() {
+        let image = RgbaImage::from_fn(16, 8, |x, y| Rgba([x as u8 * 16, y as u8 * 32, 0, 255]));
+        let data = encode_ktx2(&DynamicImage::ImageRgba8(image), ColorSpace::Srgb).unwrap();
+
+        assert_eq!(data[..12], IDENTIFIER);
+        assert_eq!(read_u32(&data, 20), 16);
+        assert_eq!(read_u32(&data, 24), 8);
+        let level_count = read_u32(&data, 40) as usize;
+        assert_eq!(level_count, 5);
+
+        // every level holds a 16 byte block per 4x4 pixels, rounded up
+        for (level, (width, height)) in [(16, 8), (8, 4), (4, 2), (2, 1), (1, 1)]
+            .into_iter()
+            .enumerate()
+        {
+            let index = HEADER_SIZE + level * LEVEL_INDEX_SIZE;
+            let offset = read_u64(&data, index);
+            let length = read_u64(&data, index + 8);
+            let blocks = (width as usize).div_ceil(4) * (height as usize).div_ceil(4);
+            assert_eq!(length, blocks * BLOCK_BYTES, "level {level}");
+            assert_eq!(offset % BLOCK_BYTES, 0);
+            assert!(offset + length <= data.len());
+        }
+
+        let dfd_offset = read_u32(&data, 48) as usize;
+        assert_eq!(read_u32(&data, 52), 44);
+        assert_eq!(read_u32(&data, dfd_offset + 12) & 0xff, KHR_DF_MODEL_UASTC);
+    }
+
+    #[test]
+    fn resize_to_block_size() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::new(6, 3));
+        let data = encode_ktx2(&image, ColorSpace::Linear).unwrap();
+        assert_eq!(read_u32(&data, 20), 8);
+        assert_eq!(read_u32(&data, 24), 4);
+    }
+}
@@ -0,0 +1,46 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// Groups of content in the exported scene, which can be toggled or excluded as a whole
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layer {
+    /// World brushes, overlays and decals
+    World,
+    /// Brushes tied to entities like `func_brush` or `func_door`
+    BrushEntities,
+    /// Props from the static prop lump
+    StaticProps,
+    /// Props placed as entities, like `prop_dynamic` and `prop_physics`
+    DynamicProps,
+    /// Brushes and props in the 3d skybox
+    Skybox,
+    /// Visible brushes with tool textures
+    Tools,
+}
+
+impl Layer {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layer::World => "world",
+            Layer::BrushEntities => "brush-entities",
+            Layer::StaticProps => "static-props",
+            Layer::DynamicProps => "dynamic-props",
+            Layer::Skybox => "skybox",
+            Layer::Tools => "tools",
+        }
+    }
+
+    /// The layer of a brush face with the given texture
+    pub fn for_face(texture: &str, world: bool, in_skybox: bool) -> Layer {
+        let is_tool = texture
+            .get(.."tools/".len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case("tools/"));
+        match (is_tool, world, in_skybox) {
+            (true, _, _) => Layer::Tools,
+            (false, false, _) => Layer::BrushEntities,
+            (false, true, true) => Layer::Skybox,
+            (false, true, false) => Layer::World,
+        }
+    }
+}
@@ -1,15 +1,49 @@
+mod animation;
 mod bsp;
 pub mod convert;
+mod cubemap;
+mod decal;
+mod encode;
 mod error;
 pub mod gltf_builder;
+mod ktx2;
+mod layers;
+mod lumps;
 mod materials;
+mod obj;
+mod overlay;
 mod prop;
+mod proxies;
+mod query;
+mod recenter;
+mod region;
+mod report;
+pub mod scene;
+mod separate;
+mod visibility;
+mod water;
 
 use ahash::RandomState;
-pub use convert::export;
+pub use convert::{
+    convert, convert_model, convert_with_lumps, export, export_model, export_with_lumps,
+    write_gltf, CoordinateSystem, GltfOutput, Units,
+};
+pub use cubemap::ProbeLayout;
+pub use encode::TextureFormat;
 pub use error::Error;
+pub use layers::Layer;
+pub use lumps::ExtraLumps;
+pub use materials::FallbackMode;
+pub use obj::{export_obj, write_obj};
+pub use prop::ModelOptions;
+pub use recenter::Recenter;
+pub use region::Region;
+pub use report::{ConversionReport, ReportEntry};
+pub use scene::Scene;
+pub use separate::SeparateWriter;
 use serde::Deserialize;
 use std::hash::{BuildHasher, Hash, Hasher};
+pub use visibility::{cluster_data, Cluster, ClusterData, Culling, LeafData};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ConvertOptions {
@@ -17,6 +51,71 @@ pub struct ConvertOptions {
     pub textures: bool,
     #[serde(default = "default_scale")]
     pub texture_scale: f32,
+    /// Maximum width or height of the embedded textures, applied after `texture_scale`
+    #[serde(default)]
+    pub max_texture_size: Option<u32>,
+    #[serde(default)]
+    pub texture_format: TextureFormat,
+    /// Embed a png copy of textures in formats that need a gltf extension, for viewers without support for it
+    #[serde(default = "default_enable")]
+    pub texture_fallback: bool,
+    /// Pack animated textures into sprite sheets and export texture proxies as animations
+    #[serde(default)]
+    pub texture_animations: bool,
+    /// Export the pre-built cubemaps as reflection probe nodes
+    #[serde(default)]
+    pub reflection_probes: Option<ProbeLayout>,
+    /// Add an `EXT_lights_image_based` light from the cubemap closest to the center of the map
+    #[serde(default)]
+    pub image_based_light: bool,
+    /// How to replace materials that fail to load
+    #[serde(default)]
+    pub fallback: FallbackMode,
+    /// Material to use in place of materials that fail to load, before applying `fallback`
+    #[serde(default)]
+    pub fallback_material: Option<String>,
+    /// Unit to export the map in
+    #[serde(default)]
+    pub units: Units,
+    /// Custom number of meters per hammer unit, overrides `units`
+    #[serde(default)]
+    pub unit_scale: Option<f32>,
+    /// Coordinate system to export the map in
+    #[serde(default)]
+    pub coordinates: CoordinateSystem,
+    /// Move a point of the map to the origin
+    #[serde(default)]
+    pub recenter: Option<Recenter>,
+    /// Class or target name of the entity to recenter on
+    #[serde(default)]
+    pub recenter_entity: Option<String>,
+    /// Point to recenter on, in map coordinates
+    #[serde(default, deserialize_with = "query::point")]
+    pub recenter_point: Option<[f32; 3]>,
+    /// Leave out world faces based on the visibility data of the map
+    #[serde(default)]
+    pub culling: Option<Culling>,
+    /// Tag world face primitives with their face index and the indices of the clusters containing them
+    #[serde(default)]
+    pub clusters: bool,
+    /// Group the world faces and props by bsp area
+    #[serde(default)]
+    pub areas: bool,
+    /// Group the top level nodes by layer
+    #[serde(default)]
+    pub layers: bool,
+    /// Layers to leave out of the output
+    #[serde(default, deserialize_with = "query::list")]
+    pub exclude: Vec<Layer>,
+    /// Only export faces and props intersecting the region
+    #[serde(default, deserialize_with = "query::region")]
+    pub region: Option<Region>,
+    /// Clip world faces at the boundary of the region
+    #[serde(default)]
+    pub clip_region: bool,
+    /// Export all skins of a model as `KHR_materials_variants` of a single mesh
+    #[serde(default)]
+    pub material_variants: bool,
 }
 
 impl ConvertOptions {
@@ -24,8 +123,59 @@ impl ConvertOptions {
         let mut hasher = RandomState::with_seeds(1, 2, 3, 4).build_hasher();
         self.textures.hash(&mut hasher);
         self.texture_scale.to_le_bytes().hash(&mut hasher);
+        self.max_texture_size.hash(&mut hasher);
+        self.texture_format.hash(&mut hasher);
+        self.texture_fallback.hash(&mut hasher);
+        self.texture_animations.hash(&mut hasher);
+        self.reflection_probes.hash(&mut hasher);
+        self.image_based_light.hash(&mut hasher);
+        self.fallback.hash(&mut hasher);
+        self.fallback_material.hash(&mut hasher);
+        self.units.hash(&mut hasher);
+        self.unit_scale
+            .map(|scale| scale.to_le_bytes())
+            .hash(&mut hasher);
+        self.coordinates.hash(&mut hasher);
+        self.recenter.hash(&mut hasher);
+        self.recenter_entity.hash(&mut hasher);
+        self.recenter_point
+            .map(|point| point.map(f32::to_le_bytes))
+            .hash(&mut hasher);
+        self.culling.hash(&mut hasher);
+        self.clusters.hash(&mut hasher);
+        self.areas.hash(&mut hasher);
+        self.layers.hash(&mut hasher);
+        self.exclude.hash(&mut hasher);
+        self.region.hash(&mut hasher);
+        self.clip_region.hash(&mut hasher);
+        self.material_variants.hash(&mut hasher);
         hasher.finish()
     }
+
+    /// Check if a layer is included in the output
+    pub fn includes(&self, layer: Layer) -> bool {
+        !self.exclude.contains(&layer)
+    }
+
+    /// Get the factor to scale hammer units by
+    pub fn unit_scale(&self) -> f32 {
+        self.unit_scale.unwrap_or_else(|| self.units.scale())
+    }
+
+    /// Get the output size for a texture with the given source size
+    pub fn texture_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let mut scale = self.texture_scale;
+        if let Some(max_size) = self.max_texture_size {
+            let largest = width.max(height) as f32 * scale;
+            if largest > max_size as f32 {
+                scale *= max_size as f32 / largest;
+            }
+        }
+        (
+            ((width as f32 * scale) as u32).max(1),
+            ((height as f32 * scale) as u32).max(1),
+        )
+    }
 }
 
 impl Default for ConvertOptions {
@@ -33,6 +183,28 @@ impl Default for ConvertOptions {
         ConvertOptions {
             textures: true,
             texture_scale: 1.0,
+            max_texture_size: None,
+            texture_format: TextureFormat::default(),
+            texture_fallback: true,
+            texture_animations: false,
+            reflection_probes: None,
+            image_based_light: false,
+            fallback: FallbackMode::default(),
+            fallback_material: None,
+            units: Units::default(),
+            unit_scale: None,
+            coordinates: CoordinateSystem::default(),
+            recenter: None,
+            recenter_entity: None,
+            recenter_point: None,
+            culling: None,
+            clusters: false,
+            areas: false,
+            layers: false,
+            exclude: Vec::new(),
+            region: None,
+            clip_region: false,
+            material_variants: false,
         }
     }
 }
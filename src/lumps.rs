@@ -0,0 +1,442 @@
+use crate::Error;
+use lzma_rs::decompress::{Options, UnpackedSize};
+use std::borrow::Cow;
+use std::io::Cursor;
+use vbsp::Vector;
+use zip::ZipArchive;
+
+const HEADER_SIZE: usize = 8;
+const LUMP_ENTRY_SIZE: usize = 16;
+const LUMP_COUNT: usize = 64;
+
+const LUMP_AREAS: usize = 20;
+const LUMP_AREA_PORTALS: usize = 21;
+const LUMP_CLIP_PORTAL_VERTICES: usize = 22;
+const LUMP_PAKFILE: usize = 40;
+const LUMP_CUBEMAPS: usize = 42;
+const LUMP_OVERLAYS: usize = 45;
+const LUMP_WATER_OVERLAYS: usize = 50;
+
+const OVERLAY_FACE_COUNT: usize = 64;
+const WATER_OVERLAY_FACE_COUNT: usize = 256;
+
+/// Lumps from the bsp file that aren't parsed by `vbsp`
+///
+/// The cubemap and pakfile lumps are only needed for reflection probes, so they're kept as they are stored in the
+/// file and only parsed when requested.
+#[derive(Debug, Default, Clone)]
+pub struct ExtraLumps {
+    pub overlays: Vec<OverlayLump>,
+    pub areas: Vec<AreaLump>,
+    cubemaps: LazyLump,
+    pakfile: LazyLump,
+}
+
+impl ExtraLumps {
+    pub fn read(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < HEADER_SIZE + LUMP_ENTRY_SIZE * LUMP_COUNT || &data[0..4] != b"VBSP" {
+            return Err(Error::Other("invalid bsp header".into()));
+        }
+
+        let mut overlays = read_overlays(&lump(data, LUMP_OVERLAYS)?, OVERLAY_FACE_COUNT);
+        overlays.extend(read_overlays(
+            &lump(data, LUMP_WATER_OVERLAYS)?,
+            WATER_OVERLAY_FACE_COUNT,
+        ));
+
+        let areas = read_areas(
+            &lump(data, LUMP_AREAS)?,
+            &lump(data, LUMP_AREA_PORTALS)?,
+            &lump(data, LUMP_CLIP_PORTAL_VERTICES)?,
+        );
+
+        Ok(ExtraLumps {
+            overlays,
+            areas,
+            cubemaps: LazyLump::read(data, LUMP_CUBEMAPS),
+            pakfile: LazyLump::read(data, LUMP_PAKFILE),
+        })
+    }
+
+    /// Parse the locations of the pre-built cubemaps
+    pub fn cubemaps(&self) -> Result<Vec<CubemapSample>, Error> {
+        Ok(self
+            .cubemaps
+            .data()?
+            .chunks_exact(16)
+            .map(|sample| CubemapSample {
+                origin: [0, 1, 2].map(|axis| read_u32(sample, axis * 4) as i32),
+                size: read_u32(sample, 12) as i32,
+            })
+            .collect())
+    }
+
+    /// List the names of all files in the embedded pakfile
+    pub fn pack_files(&self) -> Result<Vec<String>, Error> {
+        let pakfile = self.pakfile.data()?;
+        if pakfile.is_empty() {
+            return Ok(Vec::new());
+        }
+        ZipArchive::new(Cursor::new(pakfile))
+            .map(|zip| zip.file_names().map(String::from).collect())
+            .map_err(|e| Error::Other(format!("failed to read pakfile: {e}")))
+    }
+}
+
+/// The raw data of a lump, or the reason it couldn't be read from the file
+#[derive(Debug, Default, Clone)]
+struct LazyLump {
+    data: Vec<u8>,
+    uncompressed_length: usize,
+    error: Option<String>,
+}
+
+impl LazyLump {
+    fn read(data: &[u8], index: usize) -> Self {
+        match lump_entry(data, index) {
+            Ok((raw, uncompressed_length)) => LazyLump {
+                data: raw.to_vec(),
+                uncompressed_length,
+                error: None,
+            },
+            Err(e) => LazyLump {
+                error: Some(e.to_string()),
+                ..LazyLump::default()
+            },
+        }
+    }
+
+    fn data(&self) -> Result<Cow<'_, [u8]>, Error> {
+        match &self.error {
+            Some(error) => Err(Error::Other(error.clone())),
+            None => decompressed(&self.data, self.uncompressed_length),
+        }
+    }
+}
+
+/// An overlay as stored in the overlay or water overlay lump
+#[derive(Debug, Clone)]
+pub struct OverlayLump {
+    pub texture_info: usize,
+    pub faces: Vec<usize>,
+    pub u: [f32; 2],
+    pub v: [f32; 2],
+    pub uv_points: [Vector; 4],
+    pub origin: Vector,
+    pub basis_normal: Vector,
+}
+
+/// The location of a pre-built cubemap
+#[derive(Debug, Clone)]
+pub struct CubemapSample {
+    pub origin: [i32; 3],
+    /// Size of the cubemap as power of two, 0 for the default size
+    pub size: i32,
+}
+
+/// An area of the map with the portals connecting it to other areas
+#[derive(Debug, Clone)]
+pub struct AreaLump {
+    pub portals: Vec<AreaPortalLump>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AreaPortalLump {
+    /// Key linking the portal to its `func_areaportal` entity
+    pub portal_key: u16,
+    pub other_area: u16,
+    pub vertices: Vec<Vector>,
+}
+
+fn lump(data: &[u8], index: usize) -> Result<Cow<'_, [u8]>, Error> {
+    let (raw, uncompressed_length) = lump_entry(data, index)?;
+    decompressed(raw, uncompressed_length)
+}
+
+/// Get the data of a lump as stored in the file, with its uncompressed length if the lump is compressed
+fn lump_entry(data: &[u8], index: usize) -> Result<(&[u8], usize), Error> {
+    let entry = HEADER_SIZE + index * LUMP_ENTRY_SIZE;
+    let offset = read_u32(data, entry) as usize;
+    let length = read_u32(data, entry + 4) as usize;
+    let uncompressed_length = read_u32(data, entry + 12) as usize;
+
+    let raw = data
+        .get(offset..offset + length)
+        .ok_or_else(|| Error::Other(format!("lump {index} out of bounds")))?;
+    Ok((raw, uncompressed_length))
+}
+
+fn decompressed(raw: &[u8], uncompressed_length: usize) -> Result<Cow<'_, [u8]>, Error> {
+    match uncompressed_length {
+        0 => Ok(Cow::Borrowed(raw)),
+        _ => decompress_lump(raw, uncompressed_length).map(Cow::Owned),
+    }
+}
+
+/// Decompress a lump with the lzma header used by source
+fn decompress_lump(data: &[u8], expected_length: usize) -> Result<Vec<u8>, Error> {
+    if data.len() < 12 || &data[0..4] != b"LZMA" {
+        return Err(Error::Other("invalid lzma header".into()));
+    }
+    let actual_size = read_u32(data, 4);
+
+    let mut output = Vec::with_capacity(expected_length);
+    lzma_rs::lzma_decompress_with_options(
+        &mut Cursor::new(&data[12..]),
+        &mut output,
+        &Options {
+            unpacked_size: UnpackedSize::UseProvided(Some(actual_size as u64)),
+            allow_incomplete: false,
+            memlimit: None,
+        },
+    )
+    .map_err(|e| Error::Other(format!("failed to decompress lump: {e}")))?;
+    Ok(output)
+}
+
+fn read_overlays(data: &[u8], face_count: usize) -> Vec<OverlayLump> {
+    // id, texinfo, face count and render order, faces, u, v, uv points, origin, normal
+    let size = 8 + face_count * 4 + 16 + 48 + 12 + 12;
+    data.chunks_exact(size)
+        .map(move |overlay| {
+            let faces_end = 8 + face_count * 4;
+            let float =
+                |offset: usize| f32::from_le_bytes(overlay[offset..offset + 4].try_into().unwrap());
+            let vector = |offset: usize| Vector {
+                x: float(offset),
+                y: float(offset + 4),
+                z: float(offset + 8),
+            };
+            let used_faces = (read_u16(overlay, 6) & 0x3fff) as usize;
+
+            OverlayLump {
+                texture_info: read_u16(overlay, 4) as usize,
+                faces: (0..used_faces.min(face_count))
+                    .map(|face| read_u32(overlay, 8 + face * 4) as usize)
+                    .collect(),
+                u: [float(faces_end), float(faces_end + 4)],
+                v: [float(faces_end + 8), float(faces_end + 12)],
+                uv_points: [0, 1, 2, 3].map(|point| vector(faces_end + 16 + point * 12)),
+                origin: vector(faces_end + 64),
+                basis_normal: vector(faces_end + 76),
+            }
+        })
+        .collect()
+}
+
+fn read_areas(areas: &[u8], portals: &[u8], vertices: &[u8]) -> Vec<AreaLump> {
+    let vertices: Vec<Vector> = vertices
+        .chunks_exact(12)
+        .map(|vertex| Vector {
+            x: read_f32(vertex, 0),
+            y: read_f32(vertex, 4),
+            z: read_f32(vertex, 8),
+        })
+        .collect();
+    // portal key, other area, first clip vertex, clip vertex count, plane
+    let portals: Vec<AreaPortalLump> = portals
+        .chunks_exact(12)
+        .map(|portal| {
+            let first = read_u16(portal, 4) as usize;
+            let count = read_u16(portal, 6) as usize;
+            AreaPortalLump {
+                portal_key: read_u16(portal, 0),
+                other_area: read_u16(portal, 2),
+                vertices: vertices
+                    .get(first..first + count)
+                    .unwrap_or_default()
+                    .to_vec(),
+            }
+        })
+        .collect();
+    // portal count, first portal
+    areas
+        .chunks_exact(8)
+        .map(|area| {
+            let count = read_u32(area, 0) as usize;
+            let first = read_u32(area, 4) as usize;
+            AreaLump {
+                portals: portals
+                    .get(first..first + count)
+                    .unwrap_or_default()
+                    .to_vec(),
+            }
+        })
+        .collect()
+}
+
+fn read_f32(data: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a bsp file with the given lumps and empty lumps for the rest
+    fn bsp_file(lumps: &[(usize, Vec<u8>, usize)]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE + LUMP_ENTRY_SIZE * LUMP_COUNT + 4];
+        data[0..4].copy_from_slice(b"VBSP");
+        data[4..8].copy_from_slice(&20u32.to_le_bytes());
+        for (index, lump, uncompressed_length) in lumps {
+            let entry = HEADER_SIZE + index * LUMP_ENTRY_SIZE;
+            let offset = data.len() as u32;
+            data[entry..entry + 4].copy_from_slice(&offset.to_le_bytes());
+            data[entry + 4..entry + 8].copy_from_slice(&(lump.len() as u32).to_le_bytes());
+            data[entry + 12..entry + 16]
+                .copy_from_slice(&(*uncompressed_length as u32).to_le_bytes());
+            data.extend_from_slice(lump);
+        }
+        data
+    }
+
+    /// Compress data into a lump with the lzma header used by source
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        lzma_rs::lzma_compress(&mut Cursor::new(data), &mut compressed).unwrap();
+        // the lzma stream starts with 5 property bytes and the 8 byte size, source doesn't store the size
+        let (properties, stream) = (&compressed[0..5], &compressed[13..]);
+
+        let mut lump = b"LZMA".to_vec();
+        lump.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        lump.extend_from_slice(&(stream.len() as u32).to_le_bytes());
+        lump.extend_from_slice(properties);
+        lump.extend_from_slice(stream);
+        lump
+    }
+
+    fn floats(values: &[f32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn read_uncompressed_lump() {
+        let data = bsp_file(&[(LUMP_OVERLAYS, vec![1, 2, 3, 4], 0)]);
+        assert_eq!(&*lump(&data, LUMP_OVERLAYS).unwrap(), &[1, 2, 3, 4]);
+        assert!(lump(&data, LUMP_WATER_OVERLAYS).unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_compressed_lump() {
+        let content: Vec<u8> = (0..1000).map(|i| (i % 7) as u8).collect();
+        let data = bsp_file(&[(LUMP_OVERLAYS, compress(&content), content.len())]);
+        assert_eq!(&*lump(&data, LUMP_OVERLAYS).unwrap(), content.as_slice());
+    }
+
+    #[test]
+    fn lump_out_of_bounds() {
+        let mut data = bsp_file(&[(LUMP_OVERLAYS, vec![0; 16], 0)]);
+        data.truncate(data.len() - 8);
+        assert!(lump(&data, LUMP_OVERLAYS).is_err());
+    }
+
+    #[test]
+    fn invalid_lzma_header() {
+        assert!(decompress_lump(b"LZMB\0\0\0\0\0\0\0\0\0\0\0\0\0", 1).is_err());
+        assert!(decompress_lump(b"LZMA", 1).is_err());
+    }
+
+    #[test]
+    fn read_overlay_lumps() {
+        // id, texinfo and a face count of 2 with render order 1
+        let mut overlay: Vec<u8> = [1u16, 0, 3, 2 | (1 << 14)]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        overlay.resize(8 + OVERLAY_FACE_COUNT * 4, 0);
+        overlay[8..12].copy_from_slice(&5u32.to_le_bytes());
+        overlay[12..16].copy_from_slice(&9u32.to_le_bytes());
+        overlay.extend(floats(&[0.0, 1.0, 0.0, 1.0]));
+        overlay.extend(floats(&[
+            -8.0, -8.0, 0.0, -8.0, 8.0, 0.0, 8.0, 8.0, 0.0, 8.0, -8.0, 0.0,
+        ]));
+        overlay.extend(floats(&[1.0, 2.0, 3.0, 0.0, 0.0, 1.0]));
+
+        let data = bsp_file(&[(LUMP_OVERLAYS, compress(&overlay), overlay.len())]);
+        let lumps = ExtraLumps::read(&data).unwrap();
+
+        assert_eq!(lumps.overlays.len(), 1);
+        let overlay = &lumps.overlays[0];
+        assert_eq!(overlay.texture_info, 3);
+        assert_eq!(overlay.faces, [5, 9]);
+        assert_eq!(overlay.v, [0.0, 1.0]);
+        assert_eq!(overlay.uv_points[2].x, 8.0);
+        assert_eq!(overlay.origin.z, 3.0);
+        assert_eq!(overlay.basis_normal.z, 1.0);
+    }
+
+    #[test]
+    fn read_lumps() {
+        let cubemaps: Vec<u8> = [10i32, -20, 30, 6]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        // area 0 has no portals, area 1 has a portal with 4 vertices to area 2
+        let areas: Vec<u8> = [0u32, 0, 1, 0]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let mut portals: Vec<u8> = [7u16, 2, 0, 4]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        portals.extend(floats(&[0.0]));
+        let vertices = floats(&[
+            0.0, 0.0, 0.0, 0.0, 64.0, 0.0, 0.0, 64.0, 64.0, 0.0, 0.0, 64.0,
+        ]);
+
+        let data = bsp_file(&[
+            (LUMP_CUBEMAPS, compress(&cubemaps), cubemaps.len()),
+            (LUMP_AREAS, areas, 0),
+            (LUMP_AREA_PORTALS, portals, 0),
+            (LUMP_CLIP_PORTAL_VERTICES, vertices, 0),
+        ]);
+        let lumps = ExtraLumps::read(&data).unwrap();
+
+        assert!(lumps.overlays.is_empty());
+        let cubemaps = lumps.cubemaps().unwrap();
+        assert_eq!(cubemaps.len(), 1);
+        assert_eq!(cubemaps[0].origin, [10, -20, 30]);
+        assert_eq!(cubemaps[0].size, 6);
+        assert!(lumps.pack_files().unwrap().is_empty());
+
+        assert_eq!(lumps.areas.len(), 2);
+        assert!(lumps.areas[0].portals.is_empty());
+        let portal = &lumps.areas[1].portals[0];
+        assert_eq!(portal.portal_key, 7);
+        assert_eq!(portal.other_area, 2);
+        assert_eq!(portal.vertices.len(), 4);
+        assert_eq!(portal.vertices[2].y, 64.0);
+        assert_eq!(portal.vertices[2].z, 64.0);
+    }
+
+    #[test]
+    fn invalid_header() {
+        assert!(ExtraLumps::read(b"VBSP").is_err());
+        let mut data = bsp_file(&[]);
+        data[0..4].copy_from_slice(b"PSBV");
+        assert!(ExtraLumps::read(&data).is_err());
+    }
+
+    #[test]
+    fn invalid_lazy_lumps() {
+        let data = bsp_file(&[
+            (LUMP_CUBEMAPS, b"LZMA".to_vec(), 16),
+            (LUMP_PAKFILE, b"not a zip".to_vec(), 0),
+        ]);
+        let lumps = ExtraLumps::read(&data).unwrap();
+        assert!(lumps.cubemaps().is_err());
+        assert!(lumps.pack_files().is_err());
+    }
+}
@@ -1,32 +1,168 @@
+use crate::proxies::{parse_proxies, MaterialProxy};
+use crate::water::WaterParameters;
 use crate::{ConvertOptions, Error};
+use clap::ValueEnum;
 use image::imageops::FilterType;
-use image::DynamicImage;
+use image::{DynamicImage, GenericImage, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use tf_asset_loader::Loader;
-use tracing::{error, instrument};
-use vmt_parser::material::{Material, WaterMaterial};
+use tracing::{error, instrument, warn};
+use vdf_reader::entry::{Entry, Table};
+use vdf_reader::{from_entry, Event, Item, Reader, VdfError};
+use vmt_parser::material::{Material, ModulateMaterial, RefractMaterial, WaterMaterial};
 use vmt_parser::{from_str, TextureTransform};
+use vtf::header::VTFHeader;
+use vtf::image::VTFImage;
+use vtf::resources::ResourceType;
 use vtf::vtf::VTF;
 
-pub fn load_material_fallback(
-    name: &str,
-    search_dirs: &[String],
-    loader: &Loader,
-    options: &ConvertOptions,
-) -> MaterialData {
-    match load_material(name, search_dirs, loader, options) {
-        Ok(mat) => mat,
-        Err(e) => {
-            error!(error = ?e, "failed to load material");
-            MaterialData {
-                name: name.into(),
-                color: [255, 0, 255, 255],
-                ..MaterialData::default()
+/// Size of the generated checkerboard texture
+const CHECKERBOARD_SIZE: u32 = 64;
+const CHECKERBOARD_CELL: u32 = 8;
+/// Maximum number of patch materials including each other
+const MAX_PATCH_DEPTH: usize = 8;
+
+/// How to replace materials that fail to load
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FallbackMode {
+    /// Solid magenta
+    #[default]
+    Magenta,
+    /// The average color of the texture with the same name as the material, if the texture exists
+    Average,
+    /// A grey checkerboard texture
+    Checkerboard,
+    /// Don't export the faces using the material
+    Hide,
+}
+
+/// The fallback applied to a material, stored in the material extras
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fallback {
+    Magenta,
+    Average,
+    Checkerboard,
+    Hide,
+    /// Replaced by the configured fallback material
+    Material(String),
+}
+
+/// Material used in place of materials that failed to load
+pub fn fallback_material(name: &str, loader: &Loader, options: &ConvertOptions) -> MaterialData {
+    if let Some(path) = &options.fallback_material {
+        match load_material(path, &[String::new()], loader, options) {
+            Ok(material) => {
+                return MaterialData {
+                    name: name.into(),
+                    fallback: Some(Fallback::Material(path.clone())),
+                    ..material
+                }
             }
+            Err(e) => error!(error = ?e, material = path, "failed to load fallback material"),
+        }
+    }
+
+    let magenta = MaterialData {
+        name: name.into(),
+        color: [255, 0, 255, 255],
+        fallback: Some(Fallback::Magenta),
+        ..MaterialData::default()
+    };
+    match options.fallback {
+        FallbackMode::Magenta => magenta,
+        FallbackMode::Average => match average_color(name, loader) {
+            Ok(color) => MaterialData {
+                color,
+                fallback: Some(Fallback::Average),
+                ..magenta
+            },
+            Err(_) => magenta,
+        },
+        FallbackMode::Checkerboard => MaterialData {
+            color: [255; 4],
+            texture: Some(checkerboard()),
+            fallback: Some(Fallback::Checkerboard),
+            ..magenta
+        },
+        FallbackMode::Hide => MaterialData {
+            fallback: Some(Fallback::Hide),
+            ..magenta
+        },
+    }
+}
+
+/// Material used in place of a material with textures that failed to encode
+///
+/// The configured fallback material isn't loaded again at this point and the average color is taken from the
+/// texture that was already loaded.
+pub fn encoding_fallback(material: &MaterialData, options: &ConvertOptions) -> MaterialData {
+    let magenta = MaterialData {
+        name: material.name.clone(),
+        path: material.path.clone(),
+        color: [255, 0, 255, 255],
+        fallback: Some(Fallback::Magenta),
+        ..MaterialData::default()
+    };
+    match (options.fallback, &material.texture) {
+        (FallbackMode::Average, Some(texture)) => MaterialData {
+            color: image_average(&texture.image),
+            fallback: Some(Fallback::Average),
+            ..magenta
+        },
+        (FallbackMode::Magenta | FallbackMode::Average, _) => magenta,
+        (FallbackMode::Checkerboard, _) => MaterialData {
+            color: [255; 4],
+            texture: Some(checkerboard()),
+            fallback: Some(Fallback::Checkerboard),
+            ..magenta
+        },
+        (FallbackMode::Hide, _) => MaterialData {
+            fallback: Some(Fallback::Hide),
+            ..magenta
+        },
+    }
+}
+
+/// Get the average color of a texture from its smallest mip level
+fn average_color(name: &str, loader: &Loader) -> Result<[u8; 4], Error> {
+    let raw = load_vtf(name, loader)?;
+    let vtf = VTF::read(&raw)?;
+    let mip = vtf.header.mipmap_count.saturating_sub(1);
+    Ok(image_average(&decode_mip(&vtf, &raw, mip, 0)?))
+}
+
+/// Get the average color of an image, ignoring the alpha
+fn image_average(image: &DynamicImage) -> [u8; 4] {
+    let image = image.to_rgba8();
+    let mut sum = [0u64; 4];
+    for pixel in image.pixels() {
+        for (total, channel) in sum.iter_mut().zip(pixel.0) {
+            *total += channel as u64;
         }
     }
+    let count = (image.width() * image.height()).max(1) as u64;
+    let [r, g, b, _] = sum.map(|total| (total / count) as u8);
+    [r, g, b, 255]
 }
 
-#[derive(Default, Debug)]
+fn checkerboard() -> TextureData {
+    let image = RgbaImage::from_fn(CHECKERBOARD_SIZE, CHECKERBOARD_SIZE, |x, y| {
+        match (x / CHECKERBOARD_CELL + y / CHECKERBOARD_CELL) % 2 {
+            0 => Rgba([200, 200, 200, 255]),
+            _ => Rgba([120, 120, 120, 255]),
+        }
+    });
+    TextureData {
+        name: "fallback/checkerboard".into(),
+        image: DynamicImage::ImageRgba8(image),
+        sprite_sheet: None,
+    }
+}
+
+#[derive(Default, Debug, Clone)]
 pub struct MaterialData {
     pub name: String,
     pub path: String,
@@ -34,15 +170,74 @@ pub struct MaterialData {
     pub texture: Option<TextureData>,
     pub alpha_test: Option<f32>,
     pub bump_map: Option<TextureData>,
+    pub normal_map: Option<TextureData>,
     pub translucent: bool,
     pub no_cull: bool,
     pub transform: Option<TextureTransform>,
+    pub scroll: Option<TextureScroll>,
+    pub water: Option<WaterParameters>,
+    pub unlit: bool,
+    pub transmissive: bool,
+    pub blend: Option<BlendMode>,
+    pub fallback: Option<Fallback>,
 }
 
-#[derive(Debug)]
+/// Blend modes that can't be expressed directly in gltf and are approximated
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlendMode {
+    Modulate,
+}
+
+#[derive(Debug, Clone)]
 pub struct TextureData {
     pub name: String,
     pub image: DynamicImage,
+    pub sprite_sheet: Option<SpriteSheet>,
+}
+
+/// Layout of the frames of an animated texture packed into a single image
+#[derive(Debug, Clone, Serialize)]
+pub struct SpriteSheet {
+    pub frames: u32,
+    pub columns: u32,
+    pub rows: u32,
+    /// Frames per second, if the material animates the texture
+    pub frame_rate: Option<f32>,
+}
+
+impl SpriteSheet {
+    fn new(frames: u32) -> Self {
+        let columns = (frames as f32).sqrt().ceil() as u32;
+        SpriteSheet {
+            frames,
+            columns,
+            rows: frames.div_ceil(columns),
+            frame_rate: None,
+        }
+    }
+
+    pub fn scale(&self) -> [f32; 2] {
+        [1.0 / self.columns as f32, 1.0 / self.rows as f32]
+    }
+
+    /// Uv offset of a frame in the sheet
+    pub fn offset(&self, frame: u32) -> [f32; 2] {
+        let [scale_x, scale_y] = self.scale();
+        [
+            (frame % self.columns) as f32 * scale_x,
+            (frame / self.columns) as f32 * scale_y,
+        ]
+    }
+}
+
+/// Texture scrolling from a `TextureScroll` proxy
+#[derive(Debug, Clone, Serialize)]
+pub struct TextureScroll {
+    /// Texture widths per second
+    pub rate: f32,
+    /// Direction in degrees
+    pub angle: f32,
 }
 
 #[instrument(skip(loader))]
@@ -52,61 +247,71 @@ pub fn load_material(
     loader: &Loader,
     options: &ConvertOptions,
 ) -> Result<MaterialData, Error> {
-    let dirs = search_dirs
-        .iter()
-        .map(|dir| {
-            format!(
-                "materials/{}",
-                dir.to_ascii_lowercase().trim_start_matches('/')
-            )
-        })
-        .collect::<Vec<_>>();
-    let path = format!("{}.vmt", name.to_ascii_lowercase().trim_end_matches(".vmt"));
-    let path = loader
-        .find_in_paths(&path, &dirs)
-        .ok_or(Error::Other(format!("Can't find file {}", path)))?;
-    let raw = loader.load(&path)?.expect("didn't find found path?");
-    let vdf = String::from_utf8(raw)?;
+    let (path, table, material) = load_vmt(name, search_dirs, loader)?;
+    let proxies = if options.texture_animations {
+        parse_proxies(&table)
+    } else {
+        Vec::new()
+    };
 
-    let material = from_str(&vdf).map_err(|e| {
-        let report = miette::ErrReport::new(e);
-        println!("{:?}", report);
-        Error::Other(format!("Failed to load material {}", path))
-    })?;
-    let material = material.resolve(|path| {
-        let data = loader
-            .load(path)?
-            .ok_or(Error::Other(format!("Can't find file {}", path)))?;
-        let vdf = String::from_utf8(data)?;
-        Ok::<_, Error>(vdf)
-    })?;
-
-    if let Material::Water(WaterMaterial {
-        base_texture: None, ..
-    }) = &material
-    {
-        return Ok(MaterialData {
-            color: [82, 180, 217, 128],
-            name: name.into(),
-            path,
-            translucent: true,
-            ..MaterialData::default()
-        });
+    match &material {
+        Material::Water(water) => {
+            return load_water_material(name, path, water, &table, loader, options);
+        }
+        Material::Refract(refract) => {
+            return load_refract_material(name, path, refract, loader, options);
+        }
+        Material::Modulate(modulate) | Material::DecalModulate(modulate) => {
+            return load_modulate_material(name, path, modulate, loader, options);
+        }
+        _ => {}
     }
 
     let base_texture = material
         .base_texture()
-        .ok_or_else(|| Error::Other("no basetexture".into()))?;
+        .ok_or_else(|| Error::UnsupportedShader(shader_name(&material).into()))?;
+
+    // sprites are rendered without lighting and blended
+    let sprite = match &material {
+        Material::Sprite(sprite) => Some(sprite),
+        _ => None,
+    };
+    let color = match sprite {
+        Some(sprite) => {
+            let [r, g, b] = sprite.color.0.map(|channel| (channel * 255.0) as u8);
+            [r, g, b, (sprite.alpha * 255.0) as u8]
+        }
+        None => [255; 4],
+    };
 
-    let translucent = material.translucent();
+    let translucent = material.translucent() || sprite.is_some();
     let glass = material.surface_prop() == Some("glass");
     let alpha_test = material.alpha_test();
-    let texture = load_texture(base_texture, loader, options)?;
+    let (texture, mut sprite_sheet) = if options.texture_animations {
+        load_sprite_sheet(base_texture, loader, options)?
+    } else {
+        (load_texture(base_texture, loader, options)?, None)
+    };
+
+    let mut scroll = None;
+    for proxy in proxies {
+        match proxy {
+            MaterialProxy::AnimatedTexture { frame_rate } => {
+                if let Some(sprite_sheet) = sprite_sheet.as_mut() {
+                    sprite_sheet.frame_rate = Some(frame_rate);
+                }
+            }
+            MaterialProxy::TextureScroll { rate, angle } => {
+                scroll = Some(TextureScroll { rate, angle });
+            }
+        }
+    }
 
     let bump_map = material.bump_map().and_then(|path| {
         Some(TextureData {
             image: load_texture(path, loader, options).ok()?,
             name: path.into(),
+            sprite_sheet: None,
         })
     });
 
@@ -116,42 +321,616 @@ pub fn load_material(
         .cloned();
 
     Ok(MaterialData {
-        color: [255; 4],
+        color,
         name: name.into(),
         path,
         texture: Some(TextureData {
             name: base_texture.into(),
             image: texture,
+            sprite_sheet,
         }),
+        unlit: sprite.is_some(),
         bump_map,
         alpha_test,
         translucent: translucent | glass,
         no_cull: material.no_cull(),
         transform,
+        scroll,
+        ..MaterialData::default()
     })
 }
 
-fn load_texture(
+/// Get the size of a decal material in world units
+///
+/// Decals are drawn at the size of their base texture, scaled by `$decalscale`.
+pub fn decal_size(name: &str, loader: &Loader) -> Result<[f32; 2], Error> {
+    let (_, table, material) = load_vmt(name, &[String::new()], loader)?;
+    let base_texture = match &material {
+        Material::Modulate(modulate) | Material::DecalModulate(modulate) => {
+            Some(modulate.base_texture.as_str())
+        }
+        material => material.base_texture(),
+    }
+    .ok_or_else(|| Error::UnsupportedShader(shader_name(&material).into()))?;
+
+    let scale = table
+        .values()
+        .filter_map(Entry::as_table)
+        .find_map(|shader| shader.get("$decalscale"))
+        .and_then(Entry::as_str)
+        .and_then(|scale| scale.trim().parse::<f32>().ok())
+        .unwrap_or(1.0);
+
+    let raw = load_vtf(base_texture, loader)?;
+    let vtf = VTF::read(&raw)?;
+    Ok([
+        vtf.header.width as f32 * scale,
+        vtf.header.height as f32 * scale,
+    ])
+}
+
+fn load_refract_material(
     name: &str,
+    path: String,
+    material: &RefractMaterial,
     loader: &Loader,
     options: &ConvertOptions,
-) -> Result<DynamicImage, Error> {
+) -> Result<MaterialData, Error> {
+    let texture = material.base_texture.as_deref().and_then(|path| {
+        Some(TextureData {
+            image: load_texture(path, loader, options).ok()?,
+            name: path.into(),
+            sprite_sheet: None,
+        })
+    });
+    let normal_map = load_texture(&material.normal_map, loader, options)
+        .ok()
+        .map(|image| TextureData {
+            image,
+            name: material.normal_map.clone(),
+            sprite_sheet: None,
+        });
+
+    // the alpha is only used by viewers that don't support transmission
+    let [r, g, b] = material
+        .refract_tint
+        .0
+        .map(|channel| (channel.clamp(0.0, 1.0) * 255.0) as u8);
+
+    Ok(MaterialData {
+        color: [r, g, b, 128],
+        name: name.into(),
+        path,
+        texture,
+        normal_map,
+        translucent: true,
+        transmissive: true,
+        no_cull: material.no_cull,
+        ..MaterialData::default()
+    })
+}
+
+/// Modulate materials multiply the color behind them with the texture,
+/// which gets approximated by blending black with an opacity based on the texture brightness.
+fn load_modulate_material(
+    name: &str,
+    path: String,
+    material: &ModulateMaterial,
+    loader: &Loader,
+    options: &ConvertOptions,
+) -> Result<MaterialData, Error> {
+    let image = load_texture(&material.base_texture, loader, options)?;
+    let mut modulate = image.into_rgba8();
+    for pixel in modulate.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        let luminance = (0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32) / 255.0;
+        // with mod2x, 0.5 leaves the color unchanged, brightening can't be represented
+        let factor = if material.mod_2x {
+            (luminance * 2.0).min(1.0)
+        } else {
+            luminance
+        };
+        let opacity = (1.0 - factor) * (a as f32 / 255.0);
+        pixel.0 = [0, 0, 0, (opacity * 255.0) as u8];
+    }
+
+    Ok(MaterialData {
+        color: [255; 4],
+        name: name.into(),
+        path,
+        texture: Some(TextureData {
+            name: format!("{}_modulate", material.base_texture),
+            image: DynamicImage::ImageRgba8(modulate),
+            sprite_sheet: None,
+        }),
+        translucent: true,
+        no_cull: material.no_cull,
+        blend: Some(BlendMode::Modulate),
+        ..MaterialData::default()
+    })
+}
+
+fn shader_name(material: &Material) -> &'static str {
+    match material {
+        Material::LightMappedGeneric(_) => "LightmappedGeneric",
+        Material::VertexLitGeneric(_) => "VertexLitGeneric",
+        Material::VertexLitGenericDx6(_) => "VertexLitGeneric_DX6",
+        Material::UnlitGeneric(_) => "UnlitGeneric",
+        Material::UnlitTwoTexture(_) => "UnlitTwoTexture",
+        Material::Water(_) => "Water",
+        Material::WorldVertexTransition(_) => "WorldVertexTransition",
+        Material::EyeRefract(_) => "EyeRefract",
+        Material::SubRect(_) => "SubRect",
+        Material::Sprite(_) => "Sprite",
+        Material::SpriteCard(_) => "SpriteCard",
+        Material::Cable(_) => "Cable",
+        Material::Refract(_) => "Refract",
+        Material::Modulate(_) => "Modulate",
+        Material::DecalModulate(_) => "DecalModulate",
+        Material::Sky(_) => "Sky",
+        Material::Replacements(_) => "Replacements",
+        Material::Patch(_) => "Patch",
+        _ => "unknown",
+    }
+}
+
+fn load_water_material(
+    name: &str,
+    path: String,
+    material: &WaterMaterial,
+    table: &Table,
+    loader: &Loader,
+    options: &ConvertOptions,
+) -> Result<MaterialData, Error> {
+    let mut water = WaterParameters::new(material, table);
+    if let Some(bottom) = material.bottom_material.as_deref() {
+        match load_vmt(bottom, &[String::new()], loader) {
+            Ok((_, table, _)) => water.apply_bottom_material(&table),
+            Err(e) => warn!(error = ?e, material = bottom, "failed to load water bottom material"),
+        }
+    }
+
+    let texture = match material.base_texture.as_deref() {
+        Some(base_texture) => Some(TextureData {
+            name: base_texture.into(),
+            image: load_texture(base_texture, loader, options)?,
+            sprite_sheet: None,
+        }),
+        None => None,
+    };
+    let normal_map = water.normal_map.as_deref().and_then(|path| {
+        Some(TextureData {
+            image: load_texture(path, loader, options).ok()?,
+            name: path.into(),
+            sprite_sheet: None,
+        })
+    });
+
+    let color = match texture {
+        Some(_) => [255; 4],
+        None => {
+            let [r, g, b] = water.fog_color.map(|channel| (channel * 255.0) as u8);
+            [r, g, b, 128]
+        }
+    };
+
+    Ok(MaterialData {
+        color,
+        name: name.into(),
+        path,
+        texture,
+        normal_map,
+        translucent: true,
+        no_cull: true,
+        water: Some(water),
+        ..MaterialData::default()
+    })
+}
+
+/// Find, parse and resolve a material, returning the path, key values and parsed material
+///
+/// Patch materials are applied to the material they include, for both the key values and the parsed material.
+fn load_vmt(
+    name: &str,
+    search_dirs: &[String],
+    loader: &Loader,
+) -> Result<(String, Table, Material), Error> {
+    let dirs = search_dirs
+        .iter()
+        .map(|dir| {
+            format!(
+                "materials/{}",
+                dir.to_ascii_lowercase().trim_start_matches('/')
+            )
+        })
+        .collect::<Vec<_>>();
+    let path = format!("{}.vmt", name.to_ascii_lowercase().trim_end_matches(".vmt"));
+    let path = loader
+        .find_in_paths(&path, &dirs)
+        .ok_or(Error::ResourceNotFound(path))?;
+    let raw = loader.load(&path)?.expect("didn't find found path?");
+    let vdf = String::from_utf8(raw)?;
+
+    let material = from_str(&vdf)
+        .map_err(|e| Error::Other(format!("Failed to parse material {}: {}", path, e)))?;
+    match material {
+        Material::Patch(_) => {
+            let table = resolve_patch(&vdf, loader, 0)?;
+            let material = from_entry(Entry::Table(split_arrays(table.clone())))
+                .map_err(|e| Error::Other(format!("Failed to parse material {}: {}", path, e)))?;
+            Ok((path, table, material))
+        }
+        material => {
+            let table = load_table(&vdf.to_ascii_lowercase()).unwrap_or_else(|e| {
+                warn!(error = ?e, material = path, "failed to parse material key values");
+                Table::default()
+            });
+            Ok((path, table, material))
+        }
+    }
+}
+
+/// Parse the key values of a material, applying patch materials to the material they include
+///
+/// The `insert` block of the patch only adds parameters that aren't set yet, `replace` overwrites them.
+fn resolve_patch(vdf: &str, loader: &Loader, depth: usize) -> Result<Table, Error> {
+    let table = load_table(&vdf.to_ascii_lowercase())?;
+    let Some(patch) = table.get("patch").and_then(Entry::as_table) else {
+        return Ok(table);
+    };
+    if depth >= MAX_PATCH_DEPTH {
+        return Err(Error::Other("too many nested patch materials".into()));
+    }
+    let include = patch
+        .get("include")
+        .and_then(Entry::as_str)
+        .ok_or_else(|| Error::Other("patch material without include".into()))?
+        .replace('\\', "/");
+    let data = loader
+        .load(&include)?
+        .ok_or_else(|| Error::ResourceNotFound(include.clone()))?;
+    let mut base = resolve_patch(&String::from_utf8(data)?, loader, depth + 1)?;
+
+    let Some((_, Entry::Table(params))) = base.iter_mut().next() else {
+        return Err(Error::Other(format!(
+            "included material {include} doesn't look like a material"
+        )));
+    };
+    if let Some(insert) = patch.get("insert").and_then(Entry::as_table) {
+        for (key, value) in insert.iter() {
+            if !params.contains_key(key) {
+                params.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    if let Some(replace) = patch.get("replace").and_then(Entry::as_table) {
+        for (key, value) in replace.iter() {
+            params.insert(key.clone(), value.clone());
+        }
+    }
+    Ok(base)
+}
+
+/// Parse the key values of a material
+///
+/// Unlike `Table::load_from_str`, values in `{255 255 255}` or `[1 1 1]` notation are kept as string instead of being
+/// split into an array, since the brackets decide the range of colors.
+fn load_table(vdf: &str) -> Result<Table, VdfError> {
+    fn load_group(reader: &mut Reader) -> Result<Table, VdfError> {
+        let mut table = Table::default();
+        while let Some(event) = reader.event() {
+            let (key, value) = match event? {
+                Event::Entry(entry) => {
+                    let value = match entry.key {
+                        Item::Statement { .. } => {
+                            Entry::Statement(entry.value.into_content().into())
+                        }
+                        Item::Item { .. } => Entry::Value(entry.value.into_content().into()),
+                    };
+                    (entry.key.into_content().into_owned(), value)
+                }
+                Event::GroupStart(group) => {
+                    (group.name.into_owned(), Entry::Table(load_group(reader)?))
+                }
+                Event::GroupEnd(_) => break,
+            };
+            // repeated keys are collected into an array, like `Table::load_from_str` does
+            match table.remove(&key) {
+                Some(Entry::Array(mut array)) => {
+                    array.push(value);
+                    table.insert(key, Entry::Array(array))
+                }
+                Some(existing) => table.insert(key, Entry::Array(vec![existing, value].into())),
+                None => table.insert(key, value),
+            };
+        }
+        Ok(table)
+    }
+    load_group(&mut Reader::from(vdf))
+}
+
+/// Split the values in bracket notation into arrays, as expected when parsing the material from the key values
+fn split_arrays(table: Table) -> Table {
+    let mut split = Table::default();
+    for (key, entry) in HashMap::from(table) {
+        let entry = match entry {
+            Entry::Table(table) => Entry::Table(split_arrays(table)),
+            Entry::Value(value) if is_bracketed(&value) => Entry::Array(
+                value[1..value.len() - 1]
+                    .split_whitespace()
+                    .map(|item| Entry::Value(item.into()))
+                    .collect::<Vec<_>>()
+                    .into(),
+            ),
+            entry => entry,
+        };
+        split.insert(key, entry);
+    }
+    split
+}
+
+fn is_bracketed(value: &str) -> bool {
+    (value.starts_with('{') && value.ends_with('}'))
+        || (value.starts_with('[') && value.ends_with(']'))
+}
+
+pub fn load_vtf(name: &str, loader: &Loader) -> Result<Vec<u8>, Error> {
     let path = format!(
         "materials/{}.vtf",
         name.trim_end_matches(".vtf").trim_start_matches('/')
     );
-    let raw = loader
-        .load(&path)?
-        .ok_or(Error::Other(format!("Can't find file {}", path)))?;
+    loader.load(&path)?.ok_or(Error::ResourceNotFound(path))
+}
+
+fn load_texture(
+    name: &str,
+    loader: &Loader,
+    options: &ConvertOptions,
+) -> Result<DynamicImage, Error> {
+    let raw = load_vtf(name, loader)?;
     let vtf = VTF::read(&raw)?;
-    let image = vtf.highres_image.decode(0)?;
-    if options.texture_scale != 1.0 {
-        Ok(image.resize(
-            (image.width() as f32 * options.texture_scale) as u32,
-            (image.height() as f32 * options.texture_scale) as u32,
-            FilterType::CatmullRom,
-        ))
+    decode_texture(&vtf, &raw, 0, options)
+}
+
+/// Load a texture with all its frames packed into a single image
+fn load_sprite_sheet(
+    name: &str,
+    loader: &Loader,
+    options: &ConvertOptions,
+) -> Result<(DynamicImage, Option<SpriteSheet>), Error> {
+    let raw = load_vtf(name, loader)?;
+    let vtf = VTF::read(&raw)?;
+    let frames = vtf.header.frames as u32;
+    if frames <= 1 {
+        return Ok((decode_texture(&vtf, &raw, 0, options)?, None));
+    }
+
+    let sheet = SpriteSheet::new(frames);
+    let (width, height) = options.texture_size(vtf.header.width as u32, vtf.header.height as u32);
+    let mut image = RgbaImage::new(width * sheet.columns, height * sheet.rows);
+    for frame in 0..frames {
+        let frame_image = decode_texture(&vtf, &raw, frame, options)?.into_rgba8();
+        let x = (frame % sheet.columns) * width;
+        let y = (frame / sheet.columns) * height;
+        image.copy_from(&frame_image, x, y)?;
+    }
+
+    Ok((DynamicImage::ImageRgba8(image), Some(sheet)))
+}
+
+fn decode_texture(
+    vtf: &VTF,
+    raw: &Vec<u8>,
+    frame: u32,
+    options: &ConvertOptions,
+) -> Result<DynamicImage, Error> {
+    let (width, height) = options.texture_size(vtf.header.width as u32, vtf.header.height as u32);
+    let mip = pick_mip(&vtf.header, width, height);
+    let image = decode_mip(vtf, raw, mip, frame)?;
+
+    if image.width() != width || image.height() != height {
+        Ok(image.resize_exact(width, height, FilterType::CatmullRom))
     } else {
         Ok(image)
     }
 }
+
+/// Find the smallest stored mip level that is still at least the requested size
+pub fn pick_mip(header: &VTFHeader, width: u32, height: u32) -> u8 {
+    (0..header.mipmap_count.max(1))
+        .take_while(|mip| {
+            mip_size(header.width, *mip) >= width && mip_size(header.height, *mip) >= height
+        })
+        .last()
+        .unwrap_or(0)
+}
+
+pub fn mip_size(size: u16, mip: u8) -> u32 {
+    (size as u32 >> mip).max(1)
+}
+
+fn decode_mip(vtf: &VTF, raw: &Vec<u8>, mip: u8, frame: u32) -> Result<DynamicImage, Error> {
+    if mip == 0 {
+        return Ok(vtf.highres_image.decode(frame)?);
+    }
+
+    // the vtf crate can only decode the largest mip, so we pretend the image starts at the requested mip level
+    let mut header = vtf.header.clone();
+    header.width = mip_size(header.width, mip) as u16;
+    header.height = mip_size(header.height, mip) as u16;
+    header.mipmap_count -= mip;
+
+    let image = VTFImage::new(
+        header.clone(),
+        header.highres_image_format,
+        header.width,
+        header.height,
+        raw,
+        highres_offset(&vtf.header)? as usize,
+    );
+    Ok(image.decode(frame)?)
+}
+
+pub fn highres_offset(header: &VTFHeader) -> Result<u32, Error> {
+    if let Some(resource) = header
+        .resources
+        .get_by_type(ResourceType::VTF_LEGACY_RSRC_IMAGE)
+    {
+        return Ok(resource.data);
+    }
+    let lowres_offset = header
+        .resources
+        .get_by_type(ResourceType::VTF_LEGACY_RSRC_LOW_RES_IMAGE)
+        .map(|resource| resource.data)
+        .unwrap_or(header.header_size);
+    Ok(lowres_offset
+        + header.lowres_image_format.frame_size(
+            header.lowres_image_width as u32,
+            header.lowres_image_height as u32,
+        )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vtf::resources::ResourceList;
+    use vtf::ImageFormat;
+
+    fn header(width: u16, height: u16, mipmap_count: u8) -> VTFHeader {
+        VTFHeader {
+            signature: VTFHeader::SIGNATURE,
+            version: [7, 2],
+            header_size: 80,
+            width,
+            height,
+            flags: 0,
+            frames: 1,
+            first_frame: 0,
+            reflectivity: [0.0; 3],
+            bumpmap_scale: 1.0,
+            highres_image_format: ImageFormat::Rgba8888,
+            mipmap_count,
+            lowres_image_format: ImageFormat::Dxt1,
+            lowres_image_width: 0,
+            lowres_image_height: 0,
+            depth: 1,
+            resources: ResourceList::empty(),
+        }
+    }
+
+    fn max_size(max_texture_size: u32) -> ConvertOptions {
+        ConvertOptions {
+            max_texture_size: Some(max_texture_size),
+            ..ConvertOptions::default()
+        }
+    }
+
+    #[test]
+    fn keep_color_notation() {
+        let table = load_table(
+            r#"water { "$fogcolor" "{255 128 0}" "$reflecttint" "[1 0.5 0]" "$fogstart" "1" "$fogend" "2" }"#,
+        )
+        .unwrap();
+        let params = table.get("water").and_then(Entry::as_table).unwrap();
+        assert_eq!(
+            params.get("$fogcolor").and_then(Entry::as_str),
+            Some("{255 128 0}")
+        );
+        assert_eq!(
+            params.get("$reflecttint").and_then(Entry::as_str),
+            Some("[1 0.5 0]")
+        );
+
+        let split = split_arrays(table);
+        let params = split.get("water").and_then(Entry::as_table).unwrap();
+        let fog_color: Vec<_> = params
+            .get("$fogcolor")
+            .unwrap()
+            .as_slice()
+            .unwrap()
+            .iter()
+            .filter_map(Entry::as_str)
+            .collect();
+        assert_eq!(fog_color, ["255", "128", "0"]);
+        assert_eq!(params.get("$fogstart").and_then(Entry::as_str), Some("1"));
+    }
+
+    #[test]
+    fn repeated_keys_are_collected() {
+        let table = load_table(
+            r#"proxies { "animatedtexture" { } "animatedtexture" { } "animatedtexture" { } }"#,
+        )
+        .unwrap();
+        let proxies = table.get("proxies").and_then(Entry::as_table).unwrap();
+        assert_eq!(
+            proxies
+                .get("animatedtexture")
+                .unwrap()
+                .as_slice()
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn pick_exact_mip() {
+        let header = header(512, 256, 10);
+        assert_eq!(pick_mip(&header, 512, 256), 0);
+        assert_eq!(pick_mip(&header, 256, 128), 1);
+        assert_eq!(pick_mip(&header, 64, 32), 3);
+        assert_eq!(pick_mip(&header, 1, 1), 9);
+    }
+
+    #[test]
+    fn pick_larger_mip_for_sizes_between_mips() {
+        let header = header(512, 256, 10);
+        assert_eq!(pick_mip(&header, 200, 100), 1);
+        // the height limits the mip level for non square sizes
+        assert_eq!(pick_mip(&header, 100, 100), 1);
+        assert_eq!(pick_mip(&header, 1000, 500), 0);
+    }
+
+    #[test]
+    fn pick_mip_without_stored_mips() {
+        assert_eq!(pick_mip(&header(512, 256, 1), 64, 32), 0);
+        assert_eq!(pick_mip(&header(512, 256, 0), 64, 32), 0);
+        // only the stored levels are used
+        assert_eq!(pick_mip(&header(512, 256, 3), 64, 32), 2);
+    }
+
+    #[test]
+    fn clamp_texture_size() {
+        assert_eq!(ConvertOptions::default().texture_size(512, 256), (512, 256));
+        // a limit equal to the size leaves it unchanged
+        assert_eq!(max_size(512).texture_size(512, 256), (512, 256));
+        assert_eq!(max_size(1024).texture_size(512, 256), (512, 256));
+        assert_eq!(max_size(128).texture_size(512, 256), (128, 64));
+        assert_eq!(max_size(128).texture_size(256, 512), (64, 128));
+        assert_eq!(max_size(300).texture_size(512, 256), (300, 150));
+        assert_eq!(max_size(1).texture_size(512, 256), (1, 1));
+    }
+
+    #[test]
+    fn clamp_after_texture_scale() {
+        let options = ConvertOptions {
+            texture_scale: 0.5,
+            max_texture_size: Some(200),
+            ..ConvertOptions::default()
+        };
+        assert_eq!(options.texture_size(512, 256), (200, 100));
+        assert_eq!(options.texture_size(256, 128), (128, 64));
+    }
+
+    #[test]
+    fn pick_mip_for_clamped_size() {
+        let header = header(512, 256, 10);
+        let (width, height) = max_size(128).texture_size(512, 256);
+        assert_eq!(pick_mip(&header, width, height), 2);
+        // non power of two limits use the next larger mip and resample the rest
+        let (width, height) = max_size(300).texture_size(512, 256);
+        assert_eq!(pick_mip(&header, width, height), 0);
+        let (width, height) = max_size(100).texture_size(512, 256);
+        assert_eq!(pick_mip(&header, width, height), 2);
+    }
+}
@@ -0,0 +1,320 @@
+use crate::encode::{encode_image, TextureFormat};
+use crate::gltf_builder::{encode_textures, texture_key, EncodedTextures};
+use crate::materials::MaterialData;
+use crate::scene::{Node, Primitive, Scene};
+use crate::separate::image_path;
+use crate::{ConversionReport, ConvertOptions, Error, ExtraLumps};
+use ahash::HashSet;
+use cgmath::{One, Quaternion, Vector3};
+use std::fs::{create_dir_all, write, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tf_asset_loader::Loader;
+use vbsp::Bsp;
+
+/// Directory the textures are written to, relative to the obj file
+const TEXTURE_DIR: &str = "textures";
+
+/// Export the map as Wavefront obj, with the materials in an mtl file next to it
+///
+/// Returns a report of the assets that couldn't be converted.
+pub fn export_obj(
+    bsp: &Bsp,
+    lumps: &ExtraLumps,
+    loader: &Loader,
+    options: &ConvertOptions,
+    path: &Path,
+) -> Result<ConversionReport, Error> {
+    let mut scene = Scene::load(bsp, lumps, loader, options)?;
+    let report = std::mem::take(&mut scene.report);
+    write_obj(scene, options, path)?;
+    Ok(report)
+}
+
+/// Write the scene as Wavefront obj, with the materials in an mtl file next to it
+///
+/// Every node with a mesh is written as a separate group, textures are written as png.
+pub fn write_obj(mut scene: Scene, options: &ConvertOptions, path: &Path) -> Result<(), Error> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| Error::Other(format!("invalid output path {}", path.display())))?;
+    let mtl_name = format!("{stem}.mtl");
+
+    // obj has no normal maps, so there is no need to encode them
+    for material in scene.materials.iter_mut() {
+        material.normal_map = None;
+    }
+    let (encoded, failed) = encode_textures(&scene.materials, TextureFormat::Png, false);
+    scene.fallback_unencoded(&failed, options);
+
+    let mut obj = ObjWriter {
+        out: BufWriter::new(File::create(path)?),
+        scene: &scene,
+        vertex_count: 0,
+        normal_count: 0,
+    };
+    writeln!(obj.out, "mtllib {mtl_name}")?;
+
+    // the coordinate system is applied as transform of the root nodes
+    let root = Transform {
+        rotation: options.coordinates.rotation(),
+        ..Transform::default()
+    };
+    let children = scene.child_nodes();
+    for index in (0..scene.nodes.len()).filter(|index| !children.contains(index)) {
+        obj.node(index, root)?;
+    }
+    obj.out.flush()?;
+
+    let mut mtl = BufWriter::new(File::create(dir.join(&mtl_name))?);
+    let mut written = HashSet::default();
+    for material in scene.materials {
+        write_material(&mut mtl, dir, material, &encoded, &mut written)?;
+    }
+    mtl.flush()?;
+
+    Ok(())
+}
+
+/// Transform of a node into the output, combining the transforms of its parents
+#[derive(Debug, Clone, Copy)]
+struct Transform {
+    rotation: Quaternion<f32>,
+    scale: f32,
+    translation: Vector3<f32>,
+}
+
+impl Transform {
+    /// The transform of a child node with the given local transform
+    fn child(&self, node: &Node) -> Transform {
+        Transform {
+            rotation: self.rotation * node.rotation,
+            scale: self.scale * node.scale,
+            translation: self.apply(Vector3::from(node.translation)),
+        }
+    }
+
+    fn apply(&self, point: Vector3<f32>) -> Vector3<f32> {
+        self.rotation * (point * self.scale) + self.translation
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform {
+            rotation: Quaternion::one(),
+            scale: 1.0,
+            translation: Vector3::new(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+struct ObjWriter<'a> {
+    out: BufWriter<File>,
+    scene: &'a Scene,
+    /// Number of vertices written so far, obj indices are global for the file
+    vertex_count: u32,
+    normal_count: u32,
+}
+
+impl ObjWriter<'_> {
+    /// Write a node and its children, with the transform of the parent node
+    fn node(&mut self, index: usize, parent: Transform) -> Result<(), Error> {
+        let scene = self.scene;
+        let node = &scene.nodes[index];
+        let transform = parent.child(node);
+        if let Some(mesh) = node.mesh.and_then(|mesh| scene.meshes.get(mesh)) {
+            let name = group_name(node.name.as_deref().unwrap_or("node"));
+            writeln!(self.out, "g {name}_{index}")?;
+
+            for primitive in &mesh.primitives {
+                if let Some(material) = primitive.material {
+                    writeln!(
+                        self.out,
+                        "usemtl {}",
+                        material_name(&scene.materials[material].name)
+                    )?;
+                }
+                self.primitive(transform, primitive)?;
+            }
+        }
+        for child in &node.children {
+            self.node(*child, transform)?;
+        }
+        Ok(())
+    }
+
+    /// Write the vertices of the primitive, transformed into the output, and the faces referencing them
+    fn primitive(&mut self, transform: Transform, primitive: &Primitive) -> Result<(), Error> {
+        for (position, [u, v]) in primitive.positions.iter().zip(&primitive.uvs) {
+            let position = transform.apply(Vector3::from(*position));
+            writeln!(self.out, "v {} {} {}", position.x, position.y, position.z)?;
+            // obj has the texture origin in the bottom left
+            writeln!(self.out, "vt {} {}", u, 1.0 - v)?;
+        }
+        if let Some(normals) = &primitive.normals {
+            for normal in normals {
+                let normal = transform.rotation * Vector3::from(*normal);
+                writeln!(self.out, "vn {} {} {}", normal.x, normal.y, normal.z)?;
+            }
+        }
+
+        for triangle in primitive.triangles().chunks_exact(3) {
+            write!(self.out, "f")?;
+            for index in triangle {
+                let vertex = self.vertex_count + index + 1;
+                match primitive.normals {
+                    Some(_) => write!(
+                        self.out,
+                        " {vertex}/{vertex}/{}",
+                        self.normal_count + index + 1
+                    )?,
+                    None => write!(self.out, " {vertex}/{vertex}")?,
+                }
+            }
+            writeln!(self.out)?;
+        }
+
+        self.vertex_count += primitive.positions.len() as u32;
+        if let Some(normals) = &primitive.normals {
+            self.normal_count += normals.len() as u32;
+        }
+        Ok(())
+    }
+}
+
+/// Write a material to the mtl file, textures that are used by multiple materials are only written once
+fn write_material(
+    mtl: &mut impl Write,
+    dir: &Path,
+    material: MaterialData,
+    encoded: &EncodedTextures,
+    written: &mut HashSet<String>,
+) -> Result<(), Error> {
+    writeln!(mtl, "newmtl {}", material_name(&material.name))?;
+
+    let [r, g, b, a] = material.color.map(|channel| channel as f32 / 255.0);
+    writeln!(mtl, "Kd {r} {g} {b}")?;
+    if a < 1.0 {
+        writeln!(mtl, "d {a}")?;
+    }
+
+    if let Some(texture) = material.texture {
+        let texture_path = format!("{TEXTURE_DIR}/{}.png", image_path(&texture.name));
+        let key = texture_key(&texture.name);
+        if !written.contains(&key) {
+            let path = dir.join(&texture_path);
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+            // textures added as fallback after encoding aren't encoded yet
+            match encoded.get(&key) {
+                Some(encoded) => write(&path, &encoded.image.data)?,
+                None => write(
+                    &path,
+                    encode_image(&texture.image, TextureFormat::Png)?.data,
+                )?,
+            }
+            written.insert(key);
+        }
+
+        writeln!(mtl, "map_Kd {texture_path}")?;
+        if material.translucent || material.alpha_test.is_some() {
+            writeln!(mtl, "map_d {texture_path}")?;
+        }
+    }
+    writeln!(mtl)?;
+    Ok(())
+}
+
+/// Material names can't contain whitespace in obj
+fn material_name(name: &str) -> String {
+    name.replace(char::is_whitespace, "_")
+}
+
+fn group_name(model: &str) -> String {
+    model
+        .to_ascii_lowercase()
+        .trim_start_matches("models/")
+        .trim_end_matches(".mdl")
+        .replace(['/', '\\'], "_")
+        .replace(char::is_whitespace, "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::TextureData;
+    use crate::scene::Mesh;
+    use image::DynamicImage;
+    use std::fs::{read_dir, read_to_string, remove_dir_all};
+
+    fn material(name: &str) -> MaterialData {
+        MaterialData {
+            name: name.into(),
+            texture: Some(TextureData {
+                name: "shared/texture".into(),
+                image: DynamicImage::new_rgba8(2, 2),
+                sprite_sheet: None,
+            }),
+            ..MaterialData::default()
+        }
+    }
+
+    #[test]
+    fn write_nested_nodes() {
+        let scene = Scene {
+            meshes: vec![Mesh {
+                name: None,
+                primitives: vec![
+                    Primitive {
+                        positions: vec![[0.0; 3], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+                        uvs: vec![[0.0; 2]; 3],
+                        material: Some(0),
+                        ..Primitive::default()
+                    },
+                    Primitive {
+                        positions: vec![[0.0; 3]; 3],
+                        uvs: vec![[0.0; 2]; 3],
+                        material: Some(1),
+                        ..Primitive::default()
+                    },
+                ],
+            }],
+            materials: vec![material("first"), material("second")],
+            nodes: vec![
+                Node {
+                    mesh: Some(0),
+                    translation: [1.0, 0.0, 0.0],
+                    ..Node::default()
+                },
+                Node {
+                    children: vec![0],
+                    translation: [10.0, 20.0, 30.0],
+                    scale: 2.0,
+                    ..Node::default()
+                },
+            ],
+            ..Scene::default()
+        };
+        let dir = std::env::temp_dir().join("vbsp-to-gltf-obj-test");
+        create_dir_all(&dir).unwrap();
+        let path = dir.join("nested.obj");
+        write_obj(scene, &ConvertOptions::default(), &path).unwrap();
+
+        let obj = read_to_string(&path).unwrap();
+        let vertices: Vec<&str> = obj.lines().filter(|line| line.starts_with("v ")).collect();
+        // the y-up output rotates the scene by 90 degrees around the y axis
+        assert_eq!(vertices[0], "v 30 20 -12");
+        assert_eq!(vertices[1], "v 30 20 -14");
+        assert_eq!(vertices[2], "v 30 22 -12");
+
+        let mtl = read_to_string(dir.join("nested.mtl")).unwrap();
+        assert_eq!(mtl.matches("map_Kd textures/shared/texture.png").count(), 2);
+        let textures = read_dir(dir.join(TEXTURE_DIR).join("shared")).unwrap();
+        assert_eq!(textures.count(), 1);
+        remove_dir_all(&dir).unwrap();
+    }
+}
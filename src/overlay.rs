@@ -0,0 +1,433 @@
+use crate::convert::map_coords;
+use crate::lumps::OverlayLump;
+use crate::report::ConversionReport;
+use crate::scene::{Mesh, Primitive, SceneBuilder};
+use crate::ConvertOptions;
+use cgmath::{InnerSpace, Vector2, Vector3};
+use tf_asset_loader::Loader;
+use tracing::warn;
+use vbsp::{Bsp, RawEntity, Vector};
+
+/// Distance to move overlays away from the surface they're on, to prevent z-fighting
+const SURFACE_OFFSET: f32 = 0.1;
+
+/// An overlay, either from the overlay lumps or from an `info_overlay` entity
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    pub material: String,
+    /// The lump entry or entity the overlay was loaded from
+    pub reference: String,
+    pub faces: Vec<usize>,
+    pub origin: Vector3<f32>,
+    pub basis_u: Vector3<f32>,
+    pub basis_v: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    /// Corners of the overlay quad, in the basis of the overlay
+    pub points: [Vector2<f32>; 4],
+    /// Texture coordinates for each corner of the overlay quad
+    pub uvs: [Vector2<f32>; 4],
+}
+
+impl Overlay {
+    pub fn from_lump(bsp: &Bsp, index: usize, overlay: &OverlayLump) -> Option<Self> {
+        let material = bsp.textures().nth(overlay.texture_info)?.name().into();
+        let normal = Vector3::from(overlay.basis_normal).normalize();
+        // the u basis is stored in the z coordinates of the uv points, with the last one flagging a flipped v basis
+        let [a, b, c, d] = overlay.uv_points;
+        let basis_u = Vector3::new(a.z, b.z, c.z).normalize();
+        let basis_v = match d.z == 1.0 {
+            true => -normal.cross(basis_u),
+            false => normal.cross(basis_u),
+        };
+
+        Some(Overlay {
+            material,
+            reference: format!("overlay {index}"),
+            faces: overlay.faces.clone(),
+            origin: overlay.origin.into(),
+            basis_u,
+            basis_v,
+            normal,
+            points: overlay
+                .uv_points
+                .map(|point| Vector2::new(point.x, point.y)),
+            uvs: quad_uvs(overlay.u, overlay.v),
+        })
+    }
+
+    /// Load an `info_overlay` entity that wasn't compiled into the overlay lump
+    ///
+    /// Since the brush sides an overlay is placed on aren't stored in the bsp,
+    /// the overlay is applied to all world faces in its plane instead.
+    pub fn from_entity(bsp: &Bsp, index: usize, entity: &RawEntity) -> Option<Self> {
+        let vector = |key| {
+            entity
+                .prop(key)
+                .ok()?
+                .parse::<Vector>()
+                .ok()
+                .map(Vector3::from)
+        };
+        let float = |key| entity.prop(key).ok()?.parse::<f32>().ok();
+
+        let origin = vector("BasisOrigin").or_else(|| vector("origin"))?;
+        let normal = vector("BasisNormal")?.normalize();
+        let basis_u = vector("BasisU")?.normalize();
+        let basis_v = vector("BasisV")?.normalize();
+        let points = [
+            vector("uv0")?,
+            vector("uv1")?,
+            vector("uv2")?,
+            vector("uv3")?,
+        ]
+        .map(|point| point.truncate());
+
+        let world = bsp.models().next()?;
+        let first_face = world.first_face as usize;
+        let faces = world
+            .faces()
+            .enumerate()
+            .filter(|(_, face)| {
+                let plane = bsp.plane(face.plane_num as usize);
+                plane.is_some_and(|plane| {
+                    let plane_normal = Vector3::from(plane.normal);
+                    plane_normal.dot(normal) > 0.99
+                        && (plane_normal.dot(origin) - plane.dist).abs() < 1.0
+                })
+            })
+            .map(|(index, _)| first_face + index)
+            .collect();
+
+        Some(Overlay {
+            material: entity.prop("material").ok()?.into(),
+            reference: format!("entity {index} (info_overlay)"),
+            faces,
+            origin,
+            basis_u,
+            basis_v,
+            normal,
+            points,
+            uvs: quad_uvs(
+                [float("StartU")?, float("EndU")?],
+                [float("StartV")?, float("EndV")?],
+            ),
+        })
+    }
+
+    /// Corners of the overlay quad in map coordinates
+    pub fn corners(&self) -> [Vector3<f32>; 4] {
+        self.points
+            .map(|point| self.origin + self.basis_u * point.x + self.basis_v * point.y)
+    }
+
+    /// Project a point into the plane of the overlay
+    fn project(&self, point: Vector3<f32>) -> Vector2<f32> {
+        let relative = point - self.origin;
+        Vector2::new(relative.dot(self.basis_u), relative.dot(self.basis_v))
+    }
+
+    /// Get the texture coordinates for a point in the plane of the overlay
+    fn uv(&self, point: Vector2<f32>) -> [f32; 2] {
+        let [a, b, c, d] = self.points;
+        let (corners, weights) = match barycentric(point, a, b, c) {
+            Some(weights) if weights.iter().all(|weight| *weight >= -0.001) => ([0, 1, 2], weights),
+            _ => (
+                [0, 2, 3],
+                barycentric(point, a, c, d).unwrap_or([1.0, 0.0, 0.0]),
+            ),
+        };
+        let uv = corners
+            .iter()
+            .zip(weights)
+            .fold(Vector2::new(0.0, 0.0), |uv, (corner, weight)| {
+                uv + self.uvs[*corner] * weight
+            });
+        uv.into()
+    }
+}
+
+/// Load all overlays from the overlay lumps and any `info_overlay` entities
+pub fn overlays(bsp: &Bsp, lumps: &[OverlayLump], report: &mut ConversionReport) -> Vec<Overlay> {
+    let lump_overlays = lumps
+        .iter()
+        .enumerate()
+        .filter_map(|(index, overlay)| Overlay::from_lump(bsp, index, overlay));
+    let entity_overlays = bsp
+        .entities
+        .iter()
+        .enumerate()
+        .filter(|(_, entity)| entity.prop("classname").ok() == Some("info_overlay"))
+        .filter_map(|(index, entity)| {
+            let overlay = Overlay::from_entity(bsp, index, &entity);
+            if overlay.is_none() {
+                warn!(entity = ?entity, "invalid info_overlay");
+                report.skipped_entity(
+                    "info_overlay",
+                    "missing or invalid overlay properties",
+                    format!("entity {index}"),
+                );
+            }
+            overlay
+        });
+    lump_overlays.chain(entity_overlays).collect()
+}
+
+/// Create a mesh containing the overlays, clipped to the faces they are applied to
+pub fn overlay_mesh(
+    scene: &mut SceneBuilder,
+    loader: &Loader,
+    bsp: &Bsp,
+    overlays: &[Overlay],
+    options: &ConvertOptions,
+) -> Option<Mesh> {
+    let primitives: Vec<_> = overlays
+        .iter()
+        .filter_map(|overlay| {
+            let (positions, uvs): (Vec<_>, Vec<_>) =
+                overlay_vertices(bsp, overlay).into_iter().unzip();
+            if positions.is_empty() {
+                return None;
+            }
+
+            let material = options
+                .textures
+                .then(|| scene.overlay_material(&overlay.material, loader, options));
+
+            Some(Primitive {
+                positions,
+                uvs,
+                material,
+                ..Primitive::default()
+            })
+        })
+        .collect();
+
+    if primitives.is_empty() {
+        return None;
+    }
+
+    Some(Mesh {
+        name: None,
+        primitives,
+    })
+}
+
+/// Clip the overlay quad against every triangle of the faces it's applied to
+fn overlay_vertices(bsp: &Bsp, overlay: &Overlay) -> Vec<([f32; 3], [f32; 2])> {
+    let mut vertices = Vec::new();
+
+    for face in overlay.faces.iter().filter_map(|face| bsp.face(*face)) {
+        let face_normal = Vector3::from(face.normal());
+        let positions: Vec<Vector3<f32>> = face.vertex_positions().map(Vector3::from).collect();
+
+        for triangle in positions.chunks_exact(3) {
+            let winding = (triangle[1] - triangle[0]).cross(triangle[2] - triangle[0]);
+            let mut normal = winding.normalize();
+            if normal.dot(face_normal) < 0.0 {
+                normal = -normal;
+            }
+            if !normal.x.is_finite() || normal.dot(overlay.normal) <= 0.0 {
+                continue;
+            }
+
+            let projected = [0, 1, 2].map(|corner| overlay.project(triangle[corner]));
+            let polygon = clip(&overlay.points, projected);
+            if polygon.len() < 3 {
+                continue;
+            }
+
+            let vertex = |point: Vector2<f32>| {
+                let weights = barycentric(point, projected[0], projected[1], projected[2])
+                    .unwrap_or([1.0, 0.0, 0.0]);
+                let position = triangle[0] * weights[0]
+                    + triangle[1] * weights[1]
+                    + triangle[2] * weights[2]
+                    + normal * SURFACE_OFFSET;
+                (position, overlay.uv(point))
+            };
+
+            let first = vertex(polygon[0]);
+            for pair in polygon[1..].windows(2) {
+                let (mut b, mut c) = (vertex(pair[0]), vertex(pair[1]));
+                // match the winding of the face we're placed on
+                if (b.0 - first.0).cross(c.0 - first.0).dot(winding) < 0.0 {
+                    std::mem::swap(&mut b, &mut c);
+                }
+                vertices.extend(
+                    [first, b, c].map(|(position, uv)| {
+                        (map_coords([position.x, position.y, position.z]), uv)
+                    }),
+                );
+            }
+        }
+    }
+
+    vertices
+}
+
+/// Texture coordinates of the overlay corners
+pub fn quad_uvs(u: [f32; 2], v: [f32; 2]) -> [Vector2<f32>; 4] {
+    [
+        Vector2::new(u[0], v[0]),
+        Vector2::new(u[0], v[1]),
+        Vector2::new(u[1], v[1]),
+        Vector2::new(u[1], v[0]),
+    ]
+}
+
+fn cross_2d(a: Vector2<f32>, b: Vector2<f32>) -> f32 {
+    a.x * b.y - a.y * b.x
+}
+
+fn barycentric(
+    point: Vector2<f32>,
+    a: Vector2<f32>,
+    b: Vector2<f32>,
+    c: Vector2<f32>,
+) -> Option<[f32; 3]> {
+    let area = cross_2d(b - a, c - a);
+    if area.abs() < f32::EPSILON {
+        return None;
+    }
+    let wb = cross_2d(point - a, c - a) / area;
+    let wc = cross_2d(b - a, point - a) / area;
+    Some([1.0 - wb - wc, wb, wc])
+}
+
+/// Clip a polygon against a triangle
+fn clip(polygon: &[Vector2<f32>], mut triangle: [Vector2<f32>; 3]) -> Vec<Vector2<f32>> {
+    if cross_2d(triangle[1] - triangle[0], triangle[2] - triangle[0]) < 0.0 {
+        triangle.swap(1, 2);
+    }
+
+    let mut output = polygon.to_vec();
+    for edge in 0..3 {
+        let (a, b) = (triangle[edge], triangle[(edge + 1) % 3]);
+        let distance = |point: Vector2<f32>| cross_2d(b - a, point - a);
+
+        let input = std::mem::take(&mut output);
+        for (index, current) in input.iter().enumerate() {
+            let previous = input[(index + input.len() - 1) % input.len()];
+            let (current_distance, previous_distance) = (distance(*current), distance(previous));
+
+            if (current_distance >= 0.0) != (previous_distance >= 0.0) {
+                let t = previous_distance / (previous_distance - current_distance);
+                output.push(previous + (current - previous) * t);
+            }
+            if current_distance >= 0.0 {
+                output.push(*current);
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(polygon: &[Vector2<f32>]) -> f32 {
+        let twice_area: f32 = (0..polygon.len())
+            .map(|index| cross_2d(polygon[index], polygon[(index + 1) % polygon.len()]))
+            .sum();
+        twice_area.abs() / 2.0
+    }
+
+    fn square() -> [Vector2<f32>; 4] {
+        [
+            Vector2::new(0.0, 0.0),
+            Vector2::new(1.0, 0.0),
+            Vector2::new(1.0, 1.0),
+            Vector2::new(0.0, 1.0),
+        ]
+    }
+
+    #[test]
+    fn clip_inside_triangle() {
+        let triangle = [
+            Vector2::new(-1.0, -1.0),
+            Vector2::new(3.0, -1.0),
+            Vector2::new(-1.0, 3.0),
+        ];
+        let clipped = clip(&square(), triangle);
+        assert_eq!(clipped.len(), 4);
+        assert!((area(&clipped) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn clip_to_triangle() {
+        let triangle = [
+            Vector2::new(0.0, 0.0),
+            Vector2::new(1.0, 0.0),
+            Vector2::new(0.0, 1.0),
+        ];
+        let clipped = clip(&square(), triangle);
+        assert!((area(&clipped) - 0.5).abs() < 1e-5);
+
+        // the winding of the triangle doesn't matter
+        let reversed = [triangle[0], triangle[2], triangle[1]];
+        let clipped = clip(&square(), reversed);
+        assert!((area(&clipped) - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn clip_partial_overlap() {
+        let triangle = [
+            Vector2::new(0.5, -1.0),
+            Vector2::new(3.0, -1.0),
+            Vector2::new(0.5, 3.0),
+        ];
+        let clipped = clip(&square(), triangle);
+        assert!((area(&clipped) - 0.5).abs() < 1e-5);
+        assert!(clipped.iter().all(|point| point.x >= 0.5 - 1e-5));
+    }
+
+    #[test]
+    fn clip_outside_triangle() {
+        let triangle = [
+            Vector2::new(2.0, 2.0),
+            Vector2::new(3.0, 2.0),
+            Vector2::new(2.0, 3.0),
+        ];
+        assert!(clip(&square(), triangle).is_empty());
+    }
+
+    #[test]
+    fn barycentric_weights() {
+        let [a, b, c] = [
+            Vector2::new(0.0, 0.0),
+            Vector2::new(2.0, 0.0),
+            Vector2::new(0.0, 2.0),
+        ];
+        assert_eq!(barycentric(a, a, b, c), Some([1.0, 0.0, 0.0]));
+        assert_eq!(barycentric(b, a, b, c), Some([0.0, 1.0, 0.0]));
+        assert_eq!(barycentric(c, a, b, c), Some([0.0, 0.0, 1.0]));
+        let weights = barycentric(Vector2::new(1.0, 1.0), a, b, c).unwrap();
+        assert_eq!(weights, [0.0, 0.5, 0.5]);
+        assert_eq!(barycentric(a, a, a, c), None);
+    }
+
+    #[test]
+    fn uv_interpolation() {
+        let overlay = Overlay {
+            material: String::new(),
+            reference: String::new(),
+            faces: Vec::new(),
+            origin: Vector3::new(0.0, 0.0, 0.0),
+            basis_u: Vector3::new(1.0, 0.0, 0.0),
+            basis_v: Vector3::new(0.0, 1.0, 0.0),
+            normal: Vector3::new(0.0, 0.0, 1.0),
+            points: square(),
+            uvs: quad_uvs([0.0, 1.0], [0.0, 1.0]),
+        };
+        // the uvs are assigned to the corners in the order u0v0, u0v1, u1v1, u1v0
+        assert_eq!(overlay.uv(Vector2::new(1.0, 0.0)), [0.0, 1.0]);
+        assert_eq!(overlay.uv(Vector2::new(0.0, 1.0)), [1.0, 0.0]);
+        let [u, v] = overlay.uv(Vector2::new(0.5, 0.5));
+        assert!((u - 0.5).abs() < 1e-5 && (v - 0.5).abs() < 1e-5);
+        assert_eq!(
+            overlay.project(Vector3::new(0.25, 0.75, 5.0)),
+            Vector2::new(0.25, 0.75)
+        );
+    }
+}
@@ -1,282 +1,487 @@
 use crate::convert::map_coords;
-use crate::gltf_builder::push_or_get_material;
+use crate::layers::Layer;
+use crate::report::ConversionReport;
+use crate::scene::{Mesh, MeshKey, Primitive, SceneBuilder};
 use crate::{ConvertOptions, Error};
-use bytemuck::{offset_of, Pod, Zeroable};
-use gltf_json::accessor::{ComponentType, GenericComponentType, Type};
-use gltf_json::buffer::{Stride, Target, View};
-use gltf_json::mesh::{Mode, Primitive, Semantic};
-use gltf_json::validation::Checked::Valid;
-use gltf_json::validation::USize64;
-use gltf_json::{Accessor, Index, Mesh, Root, Value};
+use bytemuck::Pod;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
 use std::mem::size_of;
 use tf_asset_loader::Loader;
+use tracing::warn;
+use vbsp::PropPlacement;
+use vmdl::mdl::TextureInfo;
+use vmdl::vvd::Vertex;
 use vmdl::{Mdl, Model, SkinTable, Vtx, Vvd};
 
-#[derive(Copy, Clone, Debug, Default, Zeroable, Pod)]
-#[repr(C)]
-pub struct ModelVertex {
-    position: [f32; 3],
-    normal: [f32; 3],
-    uv: [f32; 2],
+pub fn load_prop(loader: &Loader, name: &str) -> Result<Model, Error> {
+    let (mdl, vtx, vvd) = load_model_parts(loader, name)?;
+    Ok(Model::from_parts(mdl, vtx, vvd))
 }
 
-impl ModelVertex {
-    fn from(vertex: &vmdl::vvd::Vertex, model: &Model) -> Self {
-        ModelVertex {
-            position: map_coords(model.apply_root_transform(vertex.position)),
-            uv: vertex.texture_coordinates,
-            normal: vertex.normal.into(),
+/// Parts of a model to convert when converting a single model
+#[derive(Debug, Clone, Default)]
+pub struct ModelOptions {
+    /// Skin to apply to the model
+    pub skin: i32,
+    /// Index of the model to use for every body part, the first model is used for body parts not listed
+    pub bodygroups: Vec<usize>,
+    /// Level of detail to use, models with fewer levels use their lowest detail
+    pub lod: usize,
+}
+
+/// Load a model with only the selected model of every body part, at the selected level of detail
+pub fn load_model(loader: &Loader, name: &str, options: &ModelOptions) -> Result<Model, Error> {
+    let [mdl_data, vtx_data, vvd_data] = load_model_data(loader, name)?;
+    let mut mdl = Mdl::read(&mdl_data)?;
+    let mut vtx = Vtx::read(&vtx_data)?;
+    let mut vvd = Vvd::read(&vvd_data)?;
+
+    if options.bodygroups.len() > mdl.body_parts.len() {
+        return Err(Error::Other(format!(
+            "{name} has only {} body parts",
+            mdl.body_parts.len()
+        )));
+    }
+
+    let lod = options
+        .lod
+        .min((vvd.header.lod_count as usize).saturating_sub(1));
+    if lod > 0 {
+        let vertex_count = set_root_lod_offsets(&mut mdl, &mdl_data, lod)?;
+        let (vertices, tangents) = lod_vertices(&vvd_data, lod)?;
+        if vertices.len() != vertex_count {
+            return Err(Error::Other(format!(
+                "{name} has {} vertices for lod {lod}, but its meshes use {vertex_count}",
+                vertices.len()
+            )));
+        }
+        vvd.vertices = vertices;
+        vvd.tangents = tangents;
+    }
+
+    for (index, (mdl_part, vtx_part)) in mdl
+        .body_parts
+        .iter_mut()
+        .zip(vtx.body_parts.iter_mut())
+        .enumerate()
+    {
+        let selected = options.bodygroups.get(index).copied().unwrap_or_default();
+        if selected >= mdl_part.models.len() || selected >= vtx_part.models.len() {
+            return Err(Error::Other(format!(
+                "body part {index} of {name} has no model {selected}"
+            )));
+        }
+        mdl_part.models = vec![mdl_part.models.swap_remove(selected)];
+        vtx_part.models = vec![vtx_part.models.swap_remove(selected)];
+
+        // the meshes always use the first lod of the vtx data
+        for model in &mut vtx_part.models {
+            model
+                .lods
+                .drain(..lod.min(model.lods.len().saturating_sub(1)));
         }
     }
+
+    let model = Model::from_parts(mdl, vtx, vvd);
+    // models without skin tables only have the default skin
+    let skin_count = model.skin_tables().count().max(1);
+    if options.skin < 0 || options.skin as usize >= skin_count {
+        return Err(Error::Other(format!("{name} has no skin {}", options.skin)));
+    }
+    Ok(model)
 }
 
-fn push_vertices(buffer: &mut Vec<u8>, gltf: &mut Root, model: &Model) {
-    let start = buffer.len() as u64;
-    let view_start = gltf.buffer_views.len() as u32;
-    let vertex_count = model.vertices().len() as u64;
+// offsets into the raw mdl structures, `studiohdr_t`, `mstudiobodyparts_t`, `mstudiomodel_t` and `mstudiomesh_t`
+const MDL_BODY_PART_COUNT: usize = 232;
+const BODY_PART_SIZE: usize = 16;
+const BODY_PART_MODEL_COUNT: usize = 4;
+const BODY_PART_MODEL_INDEX: usize = 12;
+const MODEL_SIZE: usize = 148;
+const MODEL_MESH_COUNT: usize = 72;
+const MESH_SIZE: usize = 116;
+const MESH_LOD_VERTEX_COUNT: usize = 52;
 
-    let (min, max) = model.bounding_box();
-    let min = map_coords(model.apply_root_transform(min));
-    let max = map_coords(model.apply_root_transform(max));
+// offsets into the raw vvd header
+const VVD_LOD_VERTEX_COUNT: usize = 16;
+const VVD_FIXUP_COUNT: usize = 48;
+const VVD_VERTEX_SIZE: usize = 48;
+const VVD_TANGENT_SIZE: usize = 16;
 
-    let vertex_data = model
-        .vertices()
-        .iter()
-        .map(|vert| ModelVertex::from(vert, model))
-        .flat_map(bytemuck::cast::<_, [u8; size_of::<ModelVertex>()]>);
-    buffer.extend(vertex_data);
-
-    let vertex_buffer_view = View {
-        buffer: Index::new(0),
-        byte_length: USize64(buffer.len() as u64 - start),
-        byte_offset: Some(USize64(start)),
-        byte_stride: Some(Stride(size_of::<ModelVertex>())),
-        extensions: Default::default(),
-        extras: Default::default(),
-        name: None,
-        target: Some(Valid(Target::ArrayBuffer)),
-    };
+/// Point the models and meshes to their vertices in the vertex list of a lower level of detail
+///
+/// This is the same as what the engine does when setting the root lod, every mesh only keeps its vertices used by the
+/// lod. Returns the total number of vertices used.
+fn set_root_lod_offsets(mdl: &mut Mdl, data: &[u8], lod: usize) -> Result<usize, Error> {
+    let counts = mesh_vertex_counts(data, lod)?;
+    let mismatch = || Error::Other("mismatched mdl structure".into());
+    if counts.len() != mdl.body_parts.len() {
+        return Err(mismatch());
+    }
+    let mut vertex_count = 0;
+    for (body_part, counts) in mdl.body_parts.iter_mut().zip(counts) {
+        if counts.len() != body_part.models.len() {
+            return Err(mismatch());
+        }
+        for (model, counts) in body_part.models.iter_mut().zip(counts) {
+            if counts.len() != model.meshes.len() {
+                return Err(mismatch());
+            }
+            model.vertex_offset = vertex_count as i32;
+            let mut model_vertex_count = 0;
+            for (mesh, count) in model.meshes.iter_mut().zip(counts) {
+                mesh.vertex_offset = model_vertex_count as i32;
+                model_vertex_count += count;
+            }
+            vertex_count += model_vertex_count;
+        }
+    }
+    Ok(vertex_count)
+}
 
-    gltf.buffer_views.push(vertex_buffer_view);
+/// The number of vertices every mesh uses at a level of detail, by body part and model
+fn mesh_vertex_counts(data: &[u8], lod: usize) -> Result<Vec<Vec<Vec<usize>>>, Error> {
+    let body_part_offset = read_offset(data, MDL_BODY_PART_COUNT + 4, 0)?;
+    (0..read_count(data, MDL_BODY_PART_COUNT)?)
+        .map(|body_part| {
+            let body_part_offset = body_part_offset + body_part * BODY_PART_SIZE;
+            let model_offset = read_offset(
+                data,
+                body_part_offset + BODY_PART_MODEL_INDEX,
+                body_part_offset,
+            )?;
+            (0..read_count(data, body_part_offset + BODY_PART_MODEL_COUNT)?)
+                .map(|model| {
+                    let model_offset = model_offset + model * MODEL_SIZE;
+                    let mesh_offset =
+                        read_offset(data, model_offset + MODEL_MESH_COUNT + 4, model_offset)?;
+                    (0..read_count(data, model_offset + MODEL_MESH_COUNT)?)
+                        .map(|mesh| {
+                            let mesh_offset = mesh_offset + mesh * MESH_SIZE;
+                            read_count(data, mesh_offset + MESH_LOD_VERTEX_COUNT + lod * 4)
+                        })
+                        .collect()
+                })
+                .collect()
+        })
+        .collect()
+}
 
-    let positions = Accessor {
-        buffer_view: Some(Index::new(view_start)),
-        byte_offset: Some(USize64(offset_of!(ModelVertex, position) as u64)),
-        count: USize64(vertex_count),
-        component_type: Valid(GenericComponentType(ComponentType::F32)),
-        extensions: Default::default(),
-        extras: Default::default(),
-        type_: Valid(Type::Vec3),
-        min: Some(Value::from(Vec::from(min))),
-        max: Some(Value::from(Vec::from(max))),
-        name: None,
-        normalized: false,
-        sparse: None,
-    };
-    let uvs = Accessor {
-        buffer_view: Some(Index::new(view_start)),
-        byte_offset: Some(USize64(offset_of!(ModelVertex, uv) as u64)),
-        count: USize64(vertex_count),
-        component_type: Valid(GenericComponentType(ComponentType::F32)),
-        extensions: Default::default(),
-        extras: Default::default(),
-        type_: Valid(Type::Vec2),
-        min: None,
-        max: None,
-        name: None,
-        normalized: false,
-        sparse: None,
-    };
-    let normals = Accessor {
-        buffer_view: Some(Index::new(view_start)),
-        byte_offset: Some(USize64(offset_of!(ModelVertex, normal) as u64)),
-        count: USize64(vertex_count),
-        component_type: Valid(GenericComponentType(ComponentType::F32)),
-        extensions: Default::default(),
-        extras: Default::default(),
-        type_: Valid(Type::Vec3),
-        min: None,
-        max: None,
-        name: None,
-        normalized: false,
-        sparse: None,
+/// Build the vertex list for a lower level of detail
+///
+/// `Vvd::read` applies the vertex fixups for the first lod, the other levels only use the vertices of the fixups
+/// with a lod of at least the selected one. Without fixups, every lod uses the first vertices of the full list.
+fn lod_vertices(data: &[u8], lod: usize) -> Result<(Vec<Vertex>, Vec<[f32; 4]>), Error> {
+    let fixup_count = read_count(data, VVD_FIXUP_COUNT)?;
+    let fixup_offset = read_offset(data, VVD_FIXUP_COUNT + 4, 0)?;
+    let vertex_offset = read_offset(data, VVD_FIXUP_COUNT + 8, 0)?;
+    let tangent_offset = read_offset(data, VVD_FIXUP_COUNT + 12, 0)?;
+
+    let ranges = if fixup_count == 0 {
+        vec![(0, read_count(data, VVD_LOD_VERTEX_COUNT + lod * 4)?)]
+    } else {
+        let mut ranges = Vec::with_capacity(fixup_count);
+        for fixup in 0..fixup_count {
+            let offset = fixup_offset + fixup * 12;
+            if read_count(data, offset)? >= lod {
+                ranges.push((read_count(data, offset + 4)?, read_count(data, offset + 8)?));
+            }
+        }
+        ranges
     };
 
-    gltf.accessors.extend([positions, uvs, normals]);
+    let mut vertices = Vec::new();
+    let mut tangents = Vec::new();
+    for (first, count) in ranges {
+        for vertex in first..first + count {
+            vertices.push(read_pod(data, vertex_offset + vertex * VVD_VERTEX_SIZE)?);
+            tangents.push(read_pod(data, tangent_offset + vertex * VVD_TANGENT_SIZE)?);
+        }
+    }
+    Ok((vertices, tangents))
 }
 
-#[tracing::instrument(skip(loader))]
-pub fn load_prop(loader: &Loader, name: &str) -> Result<Model, Error> {
+fn read_pod<T: Pod>(data: &[u8], offset: usize) -> Result<T, Error> {
+    data.get(offset..offset + size_of::<T>())
+        .map(bytemuck::pod_read_unaligned)
+        .ok_or_else(|| Error::Other(format!("model data out of bounds at {offset}")))
+}
+
+fn read_count(data: &[u8], offset: usize) -> Result<usize, Error> {
+    usize::try_from(read_pod::<i32>(data, offset)?)
+        .map_err(|_| Error::Other(format!("negative count at {offset}")))
+}
+
+/// Read an offset relative to the start of a structure
+fn read_offset(data: &[u8], offset: usize, base: usize) -> Result<usize, Error> {
+    let relative = read_pod::<i32>(data, offset)? as isize;
+    base.checked_add_signed(relative)
+        .ok_or_else(|| Error::Other(format!("invalid offset at {offset}")))
+}
+
+/// The raw mdl, vtx and vvd data of a model
+fn load_model_data(loader: &Loader, name: &str) -> Result<[Vec<u8>; 3], Error> {
     let load = |name: &str| -> Result<Vec<u8>, Error> {
         loader
             .load(name)?
             .ok_or(Error::ResourceNotFound(name.into()))
     };
-    let mdl = Mdl::read(&load(name)?)?;
-    let vtx = Vtx::read(&load(&name.replace(".mdl", ".dx90.vtx"))?)?;
-    let vvd = Vvd::read(&load(&name.replace(".mdl", ".vvd"))?)?;
+    Ok([
+        load(name)?,
+        load(&name.replace(".mdl", ".dx90.vtx"))?,
+        load(&name.replace(".mdl", ".vvd"))?,
+    ])
+}
 
-    Ok(Model::from_parts(mdl, vtx, vvd))
+#[tracing::instrument(skip(loader))]
+fn load_model_parts(loader: &Loader, name: &str) -> Result<(Mdl, Vtx, Vvd), Error> {
+    let [mdl, vtx, vvd] = load_model_data(loader, name)?;
+    Ok((Mdl::read(&mdl)?, Vtx::read(&vtx)?, Vvd::read(&vvd)?))
+}
+
+/// A prop placement with a description of where it came from
+pub struct PropSource<'a> {
+    pub placement: PropPlacement<'a>,
+    pub reference: String,
+    pub layer: Layer,
+}
+
+/// Load all models used by the props in parallel, skipping models that fail to load
+pub fn load_props<'a>(
+    loader: &Loader,
+    props: &[PropSource<'a>],
+    report: &mut ConversionReport,
+) -> BTreeMap<&'a str, Model> {
+    let mut names: Vec<&str> = props.iter().map(|prop| prop.placement.model).collect();
+    names.sort_unstable();
+    names.dedup();
+
+    let results: Vec<_> = names
+        .into_par_iter()
+        .map(|name| (name, load_prop(loader, name)))
+        .collect();
+
+    let mut models = BTreeMap::new();
+    for (name, result) in results {
+        match result {
+            Ok(model) => {
+                models.insert(name, model);
+            }
+            Err(e) => {
+                warn!(error = ?e, model = name, "failed to load prop");
+                let references = props
+                    .iter()
+                    .filter(|prop| prop.placement.model == name)
+                    .map(|prop| prop.reference.clone())
+                    .collect();
+                report.missing_model(name, &e, references);
+            }
+        }
+    }
+    models
 }
 
-pub fn push_or_get_model(
-    buffer: &mut Vec<u8>,
-    gltf: &mut Root,
+/// The materials used by a model with the given skin, or with any skin when exporting material variants
+pub fn prop_materials(
     loader: &Loader,
+    models: &BTreeMap<&str, Model>,
     model: &str,
     skin: i32,
     options: &ConvertOptions,
-) -> Option<Index<Mesh>> {
-    let skinned_name = format!("{model}_{skin}");
-    match get_mesh_index(&gltf.meshes, &skinned_name) {
+) -> Vec<String> {
+    let Some(model) = models.get(model) else {
+        return Vec::new();
+    };
+    let skin_tables: Vec<SkinTable> = model.skin_tables().collect();
+    let skins: Vec<Option<&SkinTable>> = if options.material_variants && !skin_tables.is_empty() {
+        skin_tables.iter().map(Some).collect()
+    } else {
+        vec![skin_table(&skin_tables, skin)]
+    };
+    skins
+        .into_iter()
+        .flat_map(|skin_table| {
+            model
+                .meshes()
+                .filter_map(move |mesh| mesh_material(loader, model, &mesh, skin_table))
+        })
+        .collect()
+}
+
+/// Get the index of the mesh for a model with the given skin, creating it if it's not used yet
+///
+/// When exporting material variants, all skins share the mesh of the first skin.
+pub fn load_or_get_model(
+    scene: &mut SceneBuilder,
+    loader: &Loader,
+    models: &BTreeMap<&str, Model>,
+    model: &str,
+    skin: i32,
+    options: &ConvertOptions,
+) -> Option<usize> {
+    let skin = if options.material_variants { 0 } else { skin };
+    let key = MeshKey::new(model, skin);
+    match scene.mesh(&key) {
         Some(index) => Some(index),
         None => {
-            let prop = load_prop(loader, model).ok()?;
+            let prop = models.get(model)?;
             if prop.vertices().is_empty() {
                 None
             } else {
-                let index = gltf.meshes.len() as u32;
-                let material = push_model(buffer, gltf, loader, &prop, skin, skinned_name, options);
-                gltf.meshes.push(material);
-                Some(Index::new(index))
+                let skinned_name = match options.material_variants {
+                    true => model.to_string(),
+                    false => format!("{model}_{skin}"),
+                };
+                let mesh = model_mesh(scene, loader, prop, skin, skinned_name, options);
+                Some(scene.push_keyed_mesh(key, mesh))
             }
         }
     }
 }
 
-fn get_mesh_index(meshes: &[Mesh], name: &str) -> Option<Index<Mesh>> {
-    meshes
-        .iter()
-        .enumerate()
-        .find_map(|(i, mat)| (mat.name.as_deref() == Some(name)).then_some(i))
-        .map(|i| Index::new(i as u32))
-}
-
-pub fn push_model(
-    buffer: &mut Vec<u8>,
-    gltf: &mut Root,
+/// Create a mesh with a primitive for every mesh in the model
+///
+/// When exporting material variants, the skin is used as default material and every skin is added as variant.
+pub fn model_mesh(
+    scene: &mut SceneBuilder,
     loader: &Loader,
     model: &Model,
     skin: i32,
     skinned_name: String,
     options: &ConvertOptions,
 ) -> Mesh {
-    let accessor_start = gltf.accessors.len() as u32;
-    push_vertices(buffer, gltf, model);
-    let skin_table = model
-        .skin_tables()
-        .nth(skin as usize)
-        .unwrap_or_else(|| model.skin_tables().next().unwrap());
+    let skin_tables: Vec<SkinTable> = model.skin_tables().collect();
+    let skin_table = skin_table(&skin_tables, skin);
+    let variants = options.material_variants && options.textures && skin_tables.len() > 1;
+    if variants {
+        while scene.scene.variants.len() < skin_tables.len() {
+            let name = format!("skin {}", scene.scene.variants.len());
+            scene.scene.variants.push(name);
+        }
+    }
 
     let primitives = model
         .meshes()
         .map(|mesh| {
-            push_primitive(
-                buffer,
-                gltf,
-                loader,
-                &mesh,
-                accessor_start,
-                &skin_table,
-                options,
-            )
+            let mut primitive = mesh_primitive(scene, loader, model, &mesh, skin_table, options);
+            if variants {
+                primitive.variants = skin_tables
+                    .iter()
+                    .map(|skin| {
+                        mesh_material(loader, model, &mesh, Some(skin))
+                            .map(|texture_path| scene.material(&texture_path, loader, options))
+                    })
+                    .collect();
+            }
+            primitive
         })
         .collect();
 
     Mesh {
-        extensions: Default::default(),
-        extras: Default::default(),
         name: Some(skinned_name),
         primitives,
-        weights: None,
     }
 }
 
-pub fn push_primitive(
-    buffer: &mut Vec<u8>,
-    gltf: &mut Root,
+/// The material variant to show for a prop with the given skin, if the mesh has variants
+///
+/// Invalid skins fall back to the first skin, like they do without variants.
+pub fn skin_variant(mesh: &Mesh, skin: i32) -> Option<i32> {
+    let variants = mesh
+        .primitives
+        .iter()
+        .map(|primitive| primitive.variants.len())
+        .max()
+        .filter(|variants| *variants > 0)?;
+    Some(match (0..variants as i32).contains(&skin) {
+        true => skin,
+        false => 0,
+    })
+}
+
+/// Create a primitive containing only the vertices used by the mesh
+fn mesh_primitive(
+    scene: &mut SceneBuilder,
     loader: &Loader,
+    model: &Model,
     mesh: &vmdl::Mesh,
-    vertex_accessor_start: u32,
-    skin: &SkinTable,
+    skin: Option<&SkinTable>,
     options: &ConvertOptions,
 ) -> Primitive {
-    let buffer_start = buffer.len() as u64;
-    let view_start = gltf.buffer_views.len() as u32;
-    let accessor_start = gltf.accessors.len() as u32;
-
-    buffer.extend(
-        mesh.vertex_strip_indices()
-            .flatten()
-            .flat_map(|index| (index as u32).to_le_bytes()),
-    );
-
-    let byte_length = buffer.len() as u64 - buffer_start;
-
-    let view = View {
-        buffer: Index::new(0),
-        byte_length: USize64(byte_length),
-        byte_offset: Some(USize64(buffer_start)),
-        byte_stride: None,
-        extensions: Default::default(),
-        extras: Default::default(),
-        name: None,
-        target: Some(Valid(Target::ElementArrayBuffer)),
-    };
-    gltf.buffer_views.push(view);
-
-    let accessor = Accessor {
-        buffer_view: Some(Index::new(view_start)),
-        byte_offset: Some(USize64(0)),
-        count: USize64(byte_length / size_of::<u32>() as u64),
-        component_type: Valid(GenericComponentType(ComponentType::U32)),
-        extensions: Default::default(),
-        extras: Default::default(),
-        type_: Valid(Type::Scalar),
-        min: None,
-        max: None,
-        name: None,
-        normalized: false,
-        sparse: None,
-    };
-    gltf.accessors.push(accessor);
+    let model_indices: Vec<usize> = mesh.vertex_strip_indices().flatten().collect();
+    let mut used = model_indices.clone();
+    used.sort_unstable();
+    used.dedup();
+
+    let vertices = model.vertices();
+    let positions = used
+        .iter()
+        .map(|index| map_coords(model.apply_root_transform(vertices[*index].position)))
+        .collect();
+    let normals = used
+        .iter()
+        .map(|index| map_coords(vertices[*index].normal))
+        .collect();
+    let uvs = used
+        .iter()
+        .map(|index| vertices[*index].texture_coordinates)
+        .collect();
+    let indices = model_indices
+        .iter()
+        .map(|index| used.binary_search(index).unwrap() as u32)
+        .collect();
 
     let material = if options.textures {
-        let texture = skin.texture_info(mesh.material_index());
-        let texture_path =
-            texture.and_then(|texture| find_material(&texture.name, &texture.search_paths, loader));
-        texture_path
-            .map(|texture_path| push_or_get_material(buffer, gltf, loader, &texture_path, options))
+        mesh_material(loader, model, mesh, skin)
+            .map(|texture_path| scene.material(&texture_path, loader, options))
     } else {
         None
     };
 
     Primitive {
-        attributes: {
-            let mut map = std::collections::BTreeMap::new();
-            map.insert(
-                Valid(Semantic::Positions),
-                Index::new(vertex_accessor_start),
-            );
-            map.insert(
-                Valid(Semantic::TexCoords(0)),
-                Index::new(vertex_accessor_start + 1),
-            );
-            map.insert(
-                Valid(Semantic::Normals),
-                Index::new(vertex_accessor_start + 2),
-            );
-            map
-        },
-        extensions: Default::default(),
-        extras: Default::default(),
-        indices: Some(Index::new(accessor_start)),
+        positions,
+        normals: Some(normals),
+        uvs,
+        indices: Some(indices),
         material,
-        mode: Valid(Mode::Triangles),
-        targets: None,
+        variants: Vec::new(),
+        extras: Default::default(),
     }
 }
 
+/// The skin table for a skin, falling back to the first skin for props with a skin the model doesn't have
+///
+/// Returns `None` for models without any skin table.
+fn skin_table<'a>(skin_tables: &'a [SkinTable<'a>], skin: i32) -> Option<&'a SkinTable<'a>> {
+    usize::try_from(skin)
+        .ok()
+        .and_then(|skin| skin_tables.get(skin))
+        .or(skin_tables.first())
+}
+
+/// Find the material used by a mesh for the skin
+pub fn mesh_material(
+    loader: &Loader,
+    model: &Model,
+    mesh: &vmdl::Mesh,
+    skin: Option<&SkinTable>,
+) -> Option<String> {
+    let texture = mesh_texture(model.textures(), skin, mesh.material_index())?;
+    find_material(&texture.name, &texture.search_paths, loader)
+}
+
+/// Look up the texture of a material index in the skin table
+///
+/// Models without a skin table, or with a table that doesn't cover the material, use the texture at the material index.
+fn mesh_texture<'a>(
+    textures: &'a [TextureInfo],
+    skin: Option<&SkinTable<'a>>,
+    material: i32,
+) -> Option<&'a TextureInfo> {
+    skin.and_then(|skin| skin.texture_info(material))
+        .or_else(|| textures.get(usize::try_from(material).ok()?))
+}
+
 fn find_material(name: &str, paths: &[String], loader: &Loader) -> Option<String> {
     for dir in paths {
         let full_name = format!(
@@ -291,3 +496,177 @@ fn find_material(name: &str, paths: &[String], loader: &Loader) -> Option<String
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_i32(data: &mut Vec<u8>, offset: usize, value: i32) {
+        if data.len() < offset + 4 {
+            data.resize(offset + 4, 0);
+        }
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// A vvd file with 6 vertices, where the x position of every vertex and tangent is its index
+    fn vvd_data(lod_vertex_counts: &[i32], fixups: &[[i32; 3]]) -> Vec<u8> {
+        let fixup_offset = 64;
+        let vertex_offset = fixup_offset + fixups.len() * 12;
+        let tangent_offset = vertex_offset + 6 * VVD_VERTEX_SIZE;
+
+        let mut data = Vec::new();
+        write_i32(&mut data, 12, lod_vertex_counts.len() as i32);
+        for (lod, count) in lod_vertex_counts.iter().enumerate() {
+            write_i32(&mut data, VVD_LOD_VERTEX_COUNT + lod * 4, *count);
+        }
+        write_i32(&mut data, VVD_FIXUP_COUNT, fixups.len() as i32);
+        write_i32(&mut data, VVD_FIXUP_COUNT + 4, fixup_offset as i32);
+        write_i32(&mut data, VVD_FIXUP_COUNT + 8, vertex_offset as i32);
+        write_i32(&mut data, VVD_FIXUP_COUNT + 12, tangent_offset as i32);
+        for (index, fixup) in fixups.iter().enumerate() {
+            for (field, value) in fixup.iter().enumerate() {
+                write_i32(&mut data, fixup_offset + index * 12 + field * 4, *value);
+            }
+        }
+        for vertex in 0..6 {
+            let position = (vertex as f32).to_bits() as i32;
+            // the position follows the bone weights
+            write_i32(
+                &mut data,
+                vertex_offset + vertex * VVD_VERTEX_SIZE + 16,
+                position,
+            );
+            write_i32(
+                &mut data,
+                tangent_offset + vertex * VVD_TANGENT_SIZE,
+                position,
+            );
+        }
+        data.resize(tangent_offset + 6 * VVD_TANGENT_SIZE, 0);
+        data
+    }
+
+    fn vertex_ids(vertices: &[Vertex], tangents: &[[f32; 4]]) -> Vec<usize> {
+        assert_eq!(vertices.len(), tangents.len());
+        vertices
+            .iter()
+            .zip(tangents)
+            .map(|(vertex, tangent)| {
+                assert_eq!(vertex.position.x, tangent[0]);
+                vertex.position.x as usize
+            })
+            .collect()
+    }
+
+    fn texture(name: &str) -> TextureInfo {
+        TextureInfo {
+            name: name.into(),
+            name_index: 0,
+            search_paths: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn mesh_texture_without_skin_table() {
+        let textures = [texture("first"), texture("second")];
+        assert_eq!(mesh_texture(&textures, None, 1).unwrap().name, "second");
+        assert!(mesh_texture(&textures, None, 2).is_none());
+        assert!(mesh_texture(&textures, None, -1).is_none());
+        assert!(mesh_texture(&[], None, 0).is_none());
+    }
+
+    fn variant_mesh(variants: Vec<Option<usize>>) -> Mesh {
+        Mesh {
+            name: None,
+            primitives: vec![
+                Primitive::default(),
+                Primitive {
+                    variants,
+                    ..Primitive::default()
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn skin_variants() {
+        let mesh = variant_mesh(vec![Some(0), Some(1), Some(2)]);
+        assert_eq!(skin_variant(&mesh, 0), Some(0));
+        assert_eq!(skin_variant(&mesh, 2), Some(2));
+        assert_eq!(skin_variant(&mesh, 3), Some(0));
+        assert_eq!(skin_variant(&mesh, -1), Some(0));
+        assert_eq!(skin_variant(&variant_mesh(Vec::new()), 1), None);
+    }
+
+    #[test]
+    fn lod_vertices_with_fixups() {
+        // vertices 0 and 1 are only used by lod 0, 2 and 3 by lods 0 and 1 and 4 and 5 by all lods
+        let data = vvd_data(&[6, 4, 2], &[[0, 0, 2], [1, 2, 2], [2, 4, 2]]);
+        for (lod, expected) in [
+            (0, vec![0, 1, 2, 3, 4, 5]),
+            (1, vec![2, 3, 4, 5]),
+            (2, vec![4, 5]),
+        ] {
+            let (vertices, tangents) = lod_vertices(&data, lod).unwrap();
+            assert_eq!(vertex_ids(&vertices, &tangents), expected, "lod {lod}");
+        }
+    }
+
+    #[test]
+    fn lod_vertices_without_fixups() {
+        let data = vvd_data(&[6, 3], &[]);
+        let (vertices, tangents) = lod_vertices(&data, 1).unwrap();
+        assert_eq!(vertex_ids(&vertices, &tangents), [0, 1, 2]);
+    }
+
+    #[test]
+    fn lod_vertices_out_of_bounds() {
+        let data = vvd_data(&[6, 4], &[[1, 4, 8]]);
+        assert!(lod_vertices(&data, 1).is_err());
+    }
+
+    #[test]
+    fn mesh_vertex_counts_per_lod() {
+        // one body part with two models, with one and two meshes
+        let body_part_offset = 240;
+        let model_offset = body_part_offset + BODY_PART_SIZE;
+        let mesh_offset = model_offset + 2 * MODEL_SIZE;
+        let meshes = [[5, 3], [4, 2], [7, 1]];
+
+        let mut data = Vec::new();
+        write_i32(&mut data, MDL_BODY_PART_COUNT, 1);
+        write_i32(&mut data, MDL_BODY_PART_COUNT + 4, body_part_offset as i32);
+        write_i32(&mut data, body_part_offset + BODY_PART_MODEL_COUNT, 2);
+        write_i32(
+            &mut data,
+            body_part_offset + BODY_PART_MODEL_INDEX,
+            (model_offset - body_part_offset) as i32,
+        );
+        for (model, mesh_count, first_mesh) in [(0, 1, 0), (1, 2, 1)] {
+            let offset = model_offset + model * MODEL_SIZE;
+            write_i32(&mut data, offset + MODEL_MESH_COUNT, mesh_count);
+            let meshes = mesh_offset + first_mesh * MESH_SIZE;
+            write_i32(
+                &mut data,
+                offset + MODEL_MESH_COUNT + 4,
+                (meshes - offset) as i32,
+            );
+        }
+        for (index, counts) in meshes.iter().enumerate() {
+            for (lod, count) in counts.iter().enumerate() {
+                let offset = mesh_offset + index * MESH_SIZE + MESH_LOD_VERTEX_COUNT + lod * 4;
+                write_i32(&mut data, offset, *count);
+            }
+        }
+
+        assert_eq!(
+            mesh_vertex_counts(&data, 0).unwrap(),
+            [vec![vec![5], vec![4, 7]]]
+        );
+        assert_eq!(
+            mesh_vertex_counts(&data, 1).unwrap(),
+            [vec![vec![3], vec![2, 1]]]
+        );
+        assert!(mesh_vertex_counts(&data[..300], 0).is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use vdf_reader::entry::{Entry, Table};
+
+/// Material proxies that affect the base texture and can be expressed as an animation
+#[derive(Debug, Clone, PartialEq)]
+pub enum MaterialProxy {
+    AnimatedTexture { frame_rate: f32 },
+    TextureScroll { rate: f32, angle: f32 },
+}
+
+/// Parse the proxies from the key values of the material
+///
+/// Proxies that don't target the base texture are ignored.
+pub fn parse_proxies(table: &Table) -> Vec<MaterialProxy> {
+    let Some(proxies) = table
+        .values()
+        .filter_map(Entry::as_table)
+        .find_map(|shader| shader.get("proxies"))
+        .and_then(Entry::as_table)
+    else {
+        return Vec::new();
+    };
+
+    proxies
+        .iter()
+        .flat_map(|(name, entry)| {
+            entry
+                .as_slice()
+                .into_iter()
+                .flatten()
+                .map(move |entry| (name, entry))
+        })
+        .filter_map(|(name, entry)| parse_proxy(name, entry.as_table()?))
+        .collect()
+}
+
+fn parse_proxy(name: &str, params: &Table) -> Option<MaterialProxy> {
+    let param = |key: &str| params.get(key).and_then(Entry::as_str);
+    let float = |key: &str| param(key).and_then(|value| value.trim().parse::<f32>().ok());
+
+    match name {
+        "animatedtexture" if param("animatedtexturevar") == Some("$basetexture") => {
+            Some(MaterialProxy::AnimatedTexture {
+                frame_rate: float("animatedtextureframerate").unwrap_or(15.0),
+            })
+        }
+        "texturescroll" if param("texturescrollvar") == Some("$basetexturetransform") => {
+            Some(MaterialProxy::TextureScroll {
+                rate: float("texturescrollrate").unwrap_or(1.0),
+                angle: float("texturescrollangle").unwrap_or(0.0),
+            })
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,140 @@
+//! Deserialization of options that can't be expressed directly in a query string
+//!
+//! The values are accepted either in their regular serde form or as comma separated string.
+
+use crate::Region;
+use serde::de::value::StrDeserializer;
+use serde::de::{DeserializeOwned, Error};
+use serde::{Deserialize, Deserializer};
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ValueOrString<T> {
+    Value(T),
+    String(String),
+}
+
+/// A point, or a string of comma separated coordinates like `"x,y,z"`
+pub fn point<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<[f32; 3]>, D::Error> {
+    match ValueOrString::<[f32; 3]>::deserialize(deserializer)? {
+        ValueOrString::Value(point) => Ok(Some(point)),
+        ValueOrString::String(value) => parse_point(&value).map(Some).map_err(D::Error::custom),
+    }
+}
+
+/// A list of values, or a string of comma separated values like `"a,b"`
+pub fn list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    match ValueOrString::<Vec<T>>::deserialize(deserializer)? {
+        ValueOrString::Value(list) => Ok(list),
+        ValueOrString::String(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| T::deserialize(StrDeserializer::<D::Error>::new(item)))
+            .collect(),
+    }
+}
+
+/// A region, or a string as parsed by [`Region::from_str`](std::str::FromStr::from_str)
+pub fn region<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Region>, D::Error> {
+    match ValueOrString::<Region>::deserialize(deserializer)? {
+        ValueOrString::Value(region) => Ok(Some(region)),
+        ValueOrString::String(value) => value.parse().map(Some).map_err(D::Error::custom),
+    }
+}
+
+fn parse_point(value: &str) -> Result<[f32; 3], String> {
+    let coordinates = value
+        .split(',')
+        .map(|coordinate| coordinate.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid point {value}: {e}"))?;
+    coordinates
+        .try_into()
+        .map_err(|_| format!("invalid point {value}: expected 3 coordinates"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ConvertOptions, Layer, Region};
+
+    fn query(query: &str) -> Result<ConvertOptions, serde_urlencoded::de::Error> {
+        serde_urlencoded::from_str(query)
+    }
+
+    #[test]
+    fn query_point() {
+        let options = query("recenter_point=1,-2.5,3").unwrap();
+        assert_eq!(options.recenter_point, Some([1.0, -2.5, 3.0]));
+        assert_eq!(query("").unwrap().recenter_point, None);
+        assert!(query("recenter_point=1,2").is_err());
+        assert!(query("recenter_point=1,2,x").is_err());
+    }
+
+    #[test]
+    fn query_list() {
+        let options = query("exclude=skybox,tools").unwrap();
+        assert_eq!(options.exclude, [Layer::Skybox, Layer::Tools]);
+        assert_eq!(
+            query("exclude=static-props").unwrap().exclude,
+            [Layer::StaticProps]
+        );
+        assert!(query("").unwrap().exclude.is_empty());
+        assert!(query("exclude=sky").is_err());
+    }
+
+    #[test]
+    fn json_list() {
+        let options: ConvertOptions =
+            serde_json::from_str(r#"{"exclude": ["world", "dynamic-props"]}"#).unwrap();
+        assert_eq!(options.exclude, [Layer::World, Layer::DynamicProps]);
+    }
+
+    #[test]
+    fn query_region() {
+        let options = query("region=0,0,0,100,100,100&clip_region=true").unwrap();
+        assert_eq!(
+            options.region,
+            Some(Region::Box {
+                min: [0.0; 3],
+                max: [100.0; 3],
+            })
+        );
+        assert!(options.clip_region);
+        let options = query("region=cp_center,256").unwrap();
+        assert_eq!(
+            options.region,
+            Some(Region::Sphere {
+                entity: "cp_center".into(),
+                radius: 256.0,
+            })
+        );
+        assert!(query("region=1,2,3").is_err());
+    }
+
+    #[test]
+    fn json_region() {
+        let options: ConvertOptions = serde_json::from_str(
+            r#"{"region": {"sphere": {"entity": "cp_center", "radius": 64}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            options.region,
+            Some(Region::Sphere {
+                entity: "cp_center".into(),
+                radius: 64.0,
+            })
+        );
+    }
+
+    #[test]
+    fn json_point() {
+        let options: ConvertOptions =
+            serde_json::from_str(r#"{"recenter_point": [1, 2, 3]}"#).unwrap();
+        assert_eq!(options.recenter_point, Some([1.0, 2.0, 3.0]));
+    }
+}
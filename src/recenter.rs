@@ -0,0 +1,75 @@
+use crate::convert::unmap_coords;
+use crate::scene::Scene;
+use crate::{ConvertOptions, Error};
+use clap::ValueEnum;
+use serde::Deserialize;
+use vbsp::{Bsp, Vector};
+
+/// Entities used to recenter on when no entity is specified
+const DEFAULT_ENTITIES: &[&str] = &["info_player_teamspawn", "team_control_point"];
+
+/// Point of the map that is moved to the origin
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Recenter {
+    /// The center of the bounding box
+    Center,
+    /// The center of the bottom of the bounding box
+    Floor,
+    /// The origin of the first entity with the configured class or target name
+    Entity,
+    /// The configured point
+    Point,
+}
+
+/// Find the point, in map coordinates, that should become the origin of the scene
+pub fn recenter_point(
+    bsp: &Bsp,
+    scene: &Scene,
+    mode: Recenter,
+    options: &ConvertOptions,
+) -> Result<Vector, Error> {
+    match mode {
+        Recenter::Center | Recenter::Floor => {
+            let Some((min, max)) = scene.bounds() else {
+                return Ok(Vector::default());
+            };
+            let mut center: [f32; 3] = std::array::from_fn(|axis| (min[axis] + max[axis]) / 2.0);
+            if mode == Recenter::Floor {
+                // the scene is y-up
+                center[1] = min[1];
+            }
+            Ok(unmap_coords(center).into())
+        }
+        Recenter::Entity => {
+            let names = match options.recenter_entity.as_deref() {
+                Some(name) => vec![name],
+                None => DEFAULT_ENTITIES.to_vec(),
+            };
+            names
+                .iter()
+                .find_map(|name| entity_origin(bsp, name))
+                .ok_or_else(|| {
+                    Error::Other(format!(
+                        "no entity {} found to recenter on",
+                        names.join(" or ")
+                    ))
+                })
+        }
+        Recenter::Point => options
+            .recenter_point
+            .map(Vector::from)
+            .ok_or_else(|| Error::Other("no point set to recenter on".into())),
+    }
+}
+
+/// Find the origin of the first entity with the class or target name
+pub fn entity_origin(bsp: &Bsp, name: &str) -> Option<Vector> {
+    bsp.entities
+        .iter()
+        .filter(|entity| {
+            entity.prop("classname").ok() == Some(name)
+                || entity.prop("targetname").ok() == Some(name)
+        })
+        .find_map(|entity| entity.prop("origin").ok()?.parse().ok())
+}
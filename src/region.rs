@@ -0,0 +1,395 @@
+use crate::convert::map_coords;
+use crate::recenter::entity_origin;
+use crate::scene::Primitive;
+use crate::Error;
+use cgmath::{InnerSpace, Quaternion, Vector3};
+use serde::Deserialize;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use vbsp::{Bsp, Vector};
+use vmdl::Model;
+
+/// Part of the map to export, in map coordinates
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Region {
+    /// An axis aligned box
+    Box { min: [f32; 3], max: [f32; 3] },
+    /// A sphere around the origin of the first entity with the class or target name
+    Sphere { entity: String, radius: f32 },
+}
+
+impl Hash for Region {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Region::Box { min, max } => {
+                min.map(f32::to_le_bytes).hash(state);
+                max.map(f32::to_le_bytes).hash(state);
+            }
+            Region::Sphere { entity, radius } => {
+                entity.hash(state);
+                radius.to_le_bytes().hash(state);
+            }
+        }
+    }
+}
+
+/// Parses a box as `"min_x,min_y,min_z,max_x,max_y,max_z"` or a sphere as `"entity"` or `"entity,radius"`
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = value.split(',').map(str::trim).collect();
+        let numbers: Option<Vec<f32>> = parts.iter().map(|part| part.parse().ok()).collect();
+        match (parts.as_slice(), numbers) {
+            (_, Some(numbers)) if numbers.len() == 6 => Ok(Region::Box {
+                min: [numbers[0], numbers[1], numbers[2]],
+                max: [numbers[3], numbers[4], numbers[5]],
+            }),
+            ([entity], _) if !entity.is_empty() => Ok(Region::Sphere {
+                entity: entity.to_string(),
+                radius: Region::DEFAULT_RADIUS,
+            }),
+            ([entity, radius], _) if !entity.is_empty() => Ok(Region::Sphere {
+                entity: entity.to_string(),
+                radius: radius
+                    .parse()
+                    .map_err(|e| format!("invalid region radius {radius}: {e}"))?,
+            }),
+            _ => Err(format!(
+                "invalid region {value}: expected a box as min and max coordinates or an entity name and radius"
+            )),
+        }
+    }
+}
+
+impl Region {
+    /// Radius used for spheres around an entity when none is given
+    pub const DEFAULT_RADIUS: f32 = 1024.0;
+
+    /// Resolve the region in scene coordinates
+    pub fn shape(&self, bsp: &Bsp) -> Result<RegionShape, Error> {
+        Ok(match self {
+            Region::Box { min, max } => {
+                // swapping the axes keeps the box axis aligned
+                let [min, max] = [map_coords(*min), map_coords(*max)];
+                RegionShape::Box {
+                    min: std::array::from_fn(|axis| min[axis].min(max[axis])),
+                    max: std::array::from_fn(|axis| min[axis].max(max[axis])),
+                }
+            }
+            Region::Sphere { entity, radius } => {
+                let origin = entity_origin(bsp, entity)
+                    .ok_or_else(|| Error::Other(format!("no entity {entity} found for region")))?;
+                RegionShape::Sphere {
+                    center: map_coords(origin),
+                    radius: *radius,
+                }
+            }
+        })
+    }
+}
+
+/// A region in scene coordinates
+#[derive(Debug, Clone)]
+pub enum RegionShape {
+    Box { min: [f32; 3], max: [f32; 3] },
+    Sphere { center: [f32; 3], radius: f32 },
+}
+
+impl RegionShape {
+    /// Check if an axis aligned box intersects the region
+    pub fn intersects(&self, min: [f32; 3], max: [f32; 3]) -> bool {
+        match self {
+            RegionShape::Box {
+                min: region_min,
+                max: region_max,
+            } => (0..3).all(|axis| min[axis] <= region_max[axis] && max[axis] >= region_min[axis]),
+            RegionShape::Sphere { center, radius } => {
+                let distance: f32 = (0..3)
+                    .map(|axis| {
+                        let closest = center[axis].clamp(min[axis], max[axis]);
+                        (closest - center[axis]).powi(2)
+                    })
+                    .sum();
+                distance <= radius * radius
+            }
+        }
+    }
+
+    /// Check if the bounding box of the points intersects the region
+    pub fn intersects_points(&self, points: impl IntoIterator<Item = [f32; 3]>) -> bool {
+        bounds(points).is_some_and(|(min, max)| self.intersects(min, max))
+    }
+
+    /// Check if any triangle of a triangle list intersects the region
+    pub fn intersects_triangles(&self, positions: &[[f32; 3]]) -> bool {
+        positions
+            .chunks_exact(3)
+            .any(|triangle| self.intersects_points(triangle.iter().copied()))
+    }
+
+    /// Check if a prop intersects the region, based on the bounding box of the model
+    pub fn intersects_prop(
+        &self,
+        model: &Model,
+        origin: Vector,
+        rotation: Quaternion<f32>,
+        scale: f32,
+    ) -> bool {
+        let (min, max) = model.bounding_box();
+        let translation = Vector3::from(map_coords(origin));
+        let corners = (0..8).map(|corner| {
+            let point = vmdl::Vector {
+                x: if corner & 1 == 0 { min.x } else { max.x },
+                y: if corner & 2 == 0 { min.y } else { max.y },
+                z: if corner & 4 == 0 { min.z } else { max.z },
+            };
+            let point = Vector3::from(map_coords(model.apply_root_transform(point)));
+            (rotation * (point * scale) + translation).into()
+        });
+        self.intersects_points(corners)
+    }
+
+    /// The planes faces are clipped to, as normal and distance of the half space that is kept
+    ///
+    /// Spheres are approximated by the 26 planes touching the sphere along the axes and the edge and corner diagonals.
+    fn clip_planes(&self) -> Vec<([f32; 3], f32)> {
+        match self {
+            RegionShape::Box { min, max } => (0..3)
+                .flat_map(|axis| {
+                    let normal: [f32; 3] = std::array::from_fn(|i| (i == axis) as u8 as f32);
+                    [
+                        (normal, min[axis]),
+                        (normal.map(|value| -value), -max[axis]),
+                    ]
+                })
+                .collect(),
+            RegionShape::Sphere { center, radius } => (0..27)
+                .map(|index| [index % 3, index / 3 % 3, index / 9].map(|value| value as f32 - 1.0))
+                .filter(|direction| direction.iter().any(|value| *value != 0.0))
+                .map(|direction| {
+                    let direction = Vector3::from(direction).normalize();
+                    // keep the side of the plane facing the center
+                    let normal = -direction;
+                    let distance = normal.dot(Vector3::from(*center)) - radius;
+                    (normal.into(), distance)
+                })
+                .collect(),
+        }
+    }
+
+    /// Clip the triangles of a primitive without indices to the region
+    ///
+    /// Positions are relative to `offset`.
+    pub fn clip(&self, primitive: &mut Primitive, offset: [f32; 3]) {
+        let planes = self.clip_planes();
+        let mut positions = Vec::with_capacity(primitive.positions.len());
+        let mut uvs = Vec::with_capacity(primitive.uvs.len());
+        for (triangle, triangle_uvs) in primitive
+            .positions
+            .chunks_exact(3)
+            .zip(primitive.uvs.chunks_exact(3))
+        {
+            let mut polygon: Vec<([f32; 3], [f32; 2])> = triangle
+                .iter()
+                .map(|position| std::array::from_fn(|axis| position[axis] + offset[axis]))
+                .zip(triangle_uvs.iter().copied())
+                .collect();
+            for (normal, distance) in &planes {
+                polygon = clip_polygon(&polygon, *normal, *distance);
+            }
+            for index in 1..polygon.len().saturating_sub(1) {
+                for (position, uv) in [polygon[0], polygon[index], polygon[index + 1]] {
+                    positions.push(std::array::from_fn(|axis| position[axis] - offset[axis]));
+                    uvs.push(uv);
+                }
+            }
+        }
+        primitive.positions = positions;
+        primitive.uvs = uvs;
+    }
+}
+
+/// Clip a polygon to the half space where `dot(position, normal) >= distance`
+fn clip_polygon(
+    polygon: &[([f32; 3], [f32; 2])],
+    normal: [f32; 3],
+    distance: f32,
+) -> Vec<([f32; 3], [f32; 2])> {
+    let distance = |(position, _): &([f32; 3], [f32; 2])| {
+        Vector3::from(*position).dot(Vector3::from(normal)) - distance
+    };
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+    for (index, current) in polygon.iter().enumerate() {
+        let previous = &polygon[(index + polygon.len() - 1) % polygon.len()];
+        let (current_distance, previous_distance) = (distance(current), distance(previous));
+        if (current_distance >= 0.0) != (previous_distance >= 0.0) {
+            let t = previous_distance / (previous_distance - current_distance);
+            let lerp = |a: f32, b: f32| a + (b - a) * t;
+            clipped.push((
+                std::array::from_fn(|i| lerp(previous.0[i], current.0[i])),
+                std::array::from_fn(|i| lerp(previous.1[i], current.1[i])),
+            ));
+        }
+        if current_distance >= 0.0 {
+            clipped.push(*current);
+        }
+    }
+    clipped
+}
+
+fn bounds(points: impl IntoIterator<Item = [f32; 3]>) -> Option<([f32; 3], [f32; 3])> {
+    points.into_iter().fold(None, |bounds, point| {
+        let (min, max) = bounds.unwrap_or((point, point));
+        Some((
+            std::array::from_fn(|axis| min[axis].min(point[axis])),
+            std::array::from_fn(|axis| max[axis].max(point[axis])),
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const X: [f32; 3] = [1.0, 0.0, 0.0];
+
+    fn square() -> Vec<([f32; 3], [f32; 2])> {
+        vec![
+            ([0.0, 0.0, 0.0], [0.0, 0.0]),
+            ([2.0, 0.0, 0.0], [1.0, 0.0]),
+            ([2.0, 2.0, 0.0], [1.0, 1.0]),
+            ([0.0, 2.0, 0.0], [0.0, 1.0]),
+        ]
+    }
+
+    fn triangle(points: [[f32; 3]; 3]) -> Primitive {
+        Primitive {
+            positions: points.to_vec(),
+            uvs: vec![[0.0; 2]; 3],
+            ..Primitive::default()
+        }
+    }
+
+    #[test]
+    fn parse_region() {
+        assert_eq!(
+            "-1,-2,-3,4,5,6".parse(),
+            Ok(Region::Box {
+                min: [-1.0, -2.0, -3.0],
+                max: [4.0, 5.0, 6.0],
+            })
+        );
+        assert_eq!(
+            "team_control_point".parse(),
+            Ok(Region::Sphere {
+                entity: "team_control_point".into(),
+                radius: Region::DEFAULT_RADIUS,
+            })
+        );
+        assert_eq!(
+            "cp_center, 512".parse(),
+            Ok(Region::Sphere {
+                entity: "cp_center".into(),
+                radius: 512.0,
+            })
+        );
+        assert!("1,2,3".parse::<Region>().is_err());
+        assert!("cp_center,large".parse::<Region>().is_err());
+        assert!("".parse::<Region>().is_err());
+    }
+
+    #[test]
+    fn clip_polygon_inside() {
+        assert_eq!(clip_polygon(&square(), X, -1.0), square());
+    }
+
+    #[test]
+    fn clip_polygon_outside() {
+        assert!(clip_polygon(&square(), X, 3.0).is_empty());
+    }
+
+    #[test]
+    fn clip_polygon_half() {
+        let clipped = clip_polygon(&square(), X, 1.0);
+        assert_eq!(
+            clipped,
+            [
+                ([1.0, 0.0, 0.0], [0.5, 0.0]),
+                ([2.0, 0.0, 0.0], [1.0, 0.0]),
+                ([2.0, 2.0, 0.0], [1.0, 1.0]),
+                ([1.0, 2.0, 0.0], [0.5, 1.0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn clip_polygon_negative_normal() {
+        let clipped = clip_polygon(&square(), [-1.0, 0.0, 0.0], -0.5);
+        assert_eq!(clipped.len(), 4);
+        assert!(clipped.iter().all(|(position, _)| position[0] <= 0.5));
+        assert!(clipped.contains(&([0.5, 0.0, 0.0], [0.25, 0.0])));
+    }
+
+    #[test]
+    fn clip_box() {
+        let region = RegionShape::Box {
+            min: [0.0; 3],
+            max: [1.0; 3],
+        };
+        let mut primitive = triangle([[-1.0, 0.5, 0.5], [2.0, 0.5, 0.5], [0.5, 2.0, 0.5]]);
+        region.clip(&mut primitive, [0.0; 3]);
+        assert!(!primitive.positions.is_empty());
+        assert_eq!(primitive.positions.len(), primitive.uvs.len());
+        for position in &primitive.positions {
+            assert!(position
+                .iter()
+                .all(|value| (-1e-5..=1.0 + 1e-5).contains(value)));
+        }
+    }
+
+    #[test]
+    fn clip_sphere() {
+        let region = RegionShape::Sphere {
+            center: [10.0, 0.0, 0.0],
+            radius: 1.0,
+        };
+        // a triangle through the center, larger than the sphere
+        let mut primitive = triangle([[5.0, -5.0, 0.0], [15.0, -5.0, 0.0], [10.0, 5.0, 0.0]]);
+        region.clip(&mut primitive, [0.0; 3]);
+        assert!(!primitive.positions.is_empty());
+        for position in &primitive.positions {
+            let distance = (Vector3::from(*position) - Vector3::new(10.0, 0.0, 0.0)).magnitude();
+            // the bounding box of the sphere would keep points up to sqrt(2) away in the plane
+            assert!(distance < 1.1, "{position:?} is {distance} from the center");
+        }
+    }
+
+    #[test]
+    fn clip_sphere_corner() {
+        // a triangle in the corner of the bounding box, but outside of the sphere
+        let region = RegionShape::Sphere {
+            center: [0.0; 3],
+            radius: 1.0,
+        };
+        let mut primitive = triangle([[0.9, 0.9, 0.9], [1.0, 0.9, 0.9], [0.9, 1.0, 0.9]]);
+        region.clip(&mut primitive, [0.0; 3]);
+        assert!(primitive.positions.is_empty());
+    }
+
+    #[test]
+    fn clip_with_offset() {
+        let region = RegionShape::Box {
+            min: [10.0, 0.0, 0.0],
+            max: [11.0, 1.0, 1.0],
+        };
+        let mut primitive = triangle([[0.0, 0.5, 0.5], [2.0, 0.5, 0.5], [0.0, 0.9, 0.5]]);
+        region.clip(&mut primitive, [10.0, 0.0, 0.0]);
+        assert!(!primitive.positions.is_empty());
+        assert!(primitive
+            .positions
+            .iter()
+            .all(|position| (0.0..=1.0).contains(&position[0])));
+    }
+}
@@ -0,0 +1,182 @@
+use crate::Error;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+
+/// Number of references shown per entry when printing the report
+const DISPLAY_REFERENCES: usize = 3;
+
+/// Assets and entities that couldn't be converted
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ConversionReport {
+    pub missing_models: Vec<ReportEntry>,
+    pub missing_materials: Vec<ReportEntry>,
+    pub missing_textures: Vec<ReportEntry>,
+    pub unsupported_shaders: Vec<ReportEntry>,
+    pub skipped_entities: Vec<ReportEntry>,
+    pub invalid_lumps: Vec<ReportEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportEntry {
+    /// Path of the asset or class of the entity
+    pub name: String,
+    pub error: String,
+    /// The faces, props or entities referencing the asset
+    pub references: Vec<String>,
+}
+
+impl ConversionReport {
+    pub fn is_empty(&self) -> bool {
+        self.missing_models.is_empty()
+            && self.missing_materials.is_empty()
+            && self.missing_textures.is_empty()
+            && self.unsupported_shaders.is_empty()
+            && self.skipped_entities.is_empty()
+            && self.invalid_lumps.is_empty()
+    }
+
+    /// Record a material that failed to load, sorted by the cause of the error
+    pub fn material_error(&mut self, material: &str, error: &Error, references: Vec<String>) {
+        let (list, name, error) = match error {
+            Error::UnsupportedShader(shader) => (
+                &mut self.unsupported_shaders,
+                material.to_string(),
+                shader.clone(),
+            ),
+            Error::ResourceNotFound(path) if path.ends_with(".vtf") => (
+                &mut self.missing_textures,
+                path.clone(),
+                format!("used by material {material}"),
+            ),
+            error => (
+                &mut self.missing_materials,
+                material.to_string(),
+                error.to_string(),
+            ),
+        };
+        list.push(ReportEntry {
+            name,
+            error,
+            references,
+        });
+    }
+
+    pub fn missing_model(&mut self, model: &str, error: &Error, references: Vec<String>) {
+        self.missing_models.push(ReportEntry {
+            name: model.into(),
+            error: error.to_string(),
+            references,
+        });
+    }
+
+    pub fn skipped_entity(&mut self, class: &str, error: impl Into<String>, reference: String) {
+        self.skipped_entities.push(ReportEntry {
+            name: class.into(),
+            error: error.into(),
+            references: vec![reference],
+        });
+    }
+
+    pub fn invalid_lump(&mut self, lump: &str, error: &Error) {
+        self.invalid_lumps.push(ReportEntry {
+            name: lump.into(),
+            error: error.to_string(),
+            references: Vec::new(),
+        });
+    }
+}
+
+impl Display for ConversionReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let sections = [
+            ("Missing models", &self.missing_models),
+            ("Missing materials", &self.missing_materials),
+            ("Missing textures", &self.missing_textures),
+            ("Unsupported shaders", &self.unsupported_shaders),
+            ("Skipped entities", &self.skipped_entities),
+            ("Invalid lumps", &self.invalid_lumps),
+        ];
+        for (title, entries) in sections {
+            if entries.is_empty() {
+                continue;
+            }
+            writeln!(f, "{title}:")?;
+            for entry in entries {
+                write!(f, "  {}: {}", entry.name, entry.error)?;
+                if !entry.references.is_empty() {
+                    let shown = &entry.references[..entry.references.len().min(DISPLAY_REFERENCES)];
+                    write!(f, " (referenced by {}", shown.join(", "))?;
+                    if entry.references.len() > shown.len() {
+                        write!(f, " and {} more", entry.references.len() - shown.len())?;
+                    }
+                    write!(f, ")")?;
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_material_errors() {
+        let mut report = ConversionReport::default();
+        report.material_error(
+            "tools/water",
+            &Error::UnsupportedShader("water".into()),
+            vec!["face 1".into()],
+        );
+        report.material_error(
+            "brick/wall",
+            &Error::ResourceNotFound("materials/brick/wall.vtf".into()),
+            vec!["face 2".into()],
+        );
+        report.material_error(
+            "brick/missing",
+            &Error::ResourceNotFound("materials/brick/missing.vmt".into()),
+            vec!["face 3".into()],
+        );
+
+        assert_eq!(report.unsupported_shaders.len(), 1);
+        assert_eq!(report.unsupported_shaders[0].name, "tools/water");
+        assert_eq!(report.unsupported_shaders[0].error, "water");
+
+        assert_eq!(report.missing_textures.len(), 1);
+        assert_eq!(report.missing_textures[0].name, "materials/brick/wall.vtf");
+        assert_eq!(
+            report.missing_textures[0].error,
+            "used by material brick/wall"
+        );
+
+        assert_eq!(report.missing_materials.len(), 1);
+        assert_eq!(report.missing_materials[0].name, "brick/missing");
+        assert_eq!(report.missing_materials[0].references, ["face 3"]);
+    }
+
+    #[test]
+    fn display_truncates_references() {
+        let mut report = ConversionReport::default();
+        let references = (0..5).map(|index| format!("face {index}")).collect();
+        report.material_error("brick/wall", &Error::Other("broken".into()), references);
+        report.skipped_entity("infodecal", "missing texture", "entity 7".into());
+
+        assert_eq!(
+            report.to_string(),
+            "Missing materials:\n  \
+             brick/wall: broken (referenced by face 0, face 1, face 2 and 2 more)\n\
+             Skipped entities:\n  \
+             infodecal: missing texture (referenced by entity 7)\n"
+        );
+    }
+
+    #[test]
+    fn empty_report() {
+        let report = ConversionReport::default();
+        assert!(report.is_empty());
+        assert_eq!(report.to_string(), "");
+    }
+}
@@ -0,0 +1,1225 @@
+use crate::bsp::{bsp_models, load_bsp_model, model_faces};
+use crate::convert::map_coords;
+pub use crate::cubemap::Cubemap;
+use crate::cubemap::{environment_cubemap, load_cubemaps};
+use crate::decal::{decal_mesh, decals};
+use crate::gltf_builder::texture_key;
+use crate::layers::Layer;
+use crate::materials::{encoding_fallback, fallback_material, load_material, Fallback};
+pub use crate::materials::{MaterialData, TextureData};
+use crate::overlay::{overlay_mesh, overlays, Overlay};
+use crate::prop::{
+    load_model, load_or_get_model, load_props, model_mesh, prop_materials, skin_variant,
+    ModelOptions, PropSource,
+};
+use crate::recenter::recenter_point;
+use crate::report::ConversionReport;
+use crate::visibility::{leaf_area, FaceVisibility};
+use crate::{ConvertOptions, Error, ExtraLumps};
+use ahash::{HashMap, HashSet};
+use cgmath::{One, Quaternion, Vector3};
+use rayon::prelude::*;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::ptr;
+use tf_asset_loader::Loader;
+use tracing::{error, warn};
+use vbsp::{Bsp, Entity as BspEntity, Handle, Model, PropPlacement, Vector};
+
+/// A converted map, independent of the output format
+///
+/// All positions are in gltf coordinates, see [`map_coords`](crate::convert::map_coords),
+/// the selected [`CoordinateSystem`](crate::CoordinateSystem) is applied by the writers.
+/// Textures are stored in the materials using them and are deduplicated by name when writing the output.
+#[derive(Default)]
+pub struct Scene {
+    pub meshes: Vec<Mesh>,
+    pub materials: Vec<MaterialData>,
+    pub nodes: Vec<Node>,
+    pub entities: Vec<Entity>,
+    pub cubemaps: Vec<Cubemap>,
+    /// Names of the material variants, see [`Primitive::variants`]
+    pub variants: Vec<String>,
+    /// Areas of the map, only filled when splitting the map into areas
+    pub areas: Vec<Area>,
+    /// Index of the cubemap used for image based lighting
+    pub environment: Option<usize>,
+    /// Extra data stored on the exported scene
+    pub extras: Map<String, Value>,
+    /// Offset the scene was moved by when recentering, in map coordinates
+    pub origin_offset: Vector,
+    pub report: ConversionReport,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Mesh {
+    pub name: Option<String>,
+    pub primitives: Vec<Primitive>,
+}
+
+/// A triangle list with its vertex streams
+#[derive(Debug, Default, Clone)]
+pub struct Primitive {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Option<Vec<[f32; 3]>>,
+    pub uvs: Vec<[f32; 2]>,
+    /// Triangle indices, the vertices are used in order if there are none
+    pub indices: Option<Vec<u32>>,
+    /// Index into the scene materials
+    pub material: Option<usize>,
+    /// Material used by every material variant of the scene, by variant index
+    pub variants: Vec<Option<usize>>,
+    pub extras: Map<String, Value>,
+}
+
+impl Primitive {
+    /// The vertex indices of every triangle
+    pub fn triangles(&self) -> Vec<u32> {
+        match &self.indices {
+            Some(indices) => indices.clone(),
+            None => (0..self.positions.len() as u32).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub name: Option<String>,
+    /// Index into the scene meshes
+    pub mesh: Option<usize>,
+    pub translation: [f32; 3],
+    pub rotation: Quaternion<f32>,
+    pub scale: f32,
+    /// Indices of the child nodes, nodes with children don't have a transform of their own
+    pub children: Vec<usize>,
+    pub layer: Option<Layer>,
+    pub extras: Map<String, Value>,
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Node {
+            name: None,
+            mesh: None,
+            translation: [0.0; 3],
+            rotation: Quaternion::one(),
+            scale: 1.0,
+            children: Vec::new(),
+            layer: None,
+            extras: Map::new(),
+        }
+    }
+}
+
+/// A group node for the geometry in a bsp area, areas are separated by area portals
+#[derive(Debug, Clone)]
+pub struct Area {
+    pub index: usize,
+    /// Index of the node grouping the area
+    pub node: usize,
+    pub portals: Vec<AreaPortal>,
+}
+
+/// A portal connecting two areas
+#[derive(Debug, Clone)]
+pub struct AreaPortal {
+    pub other_area: usize,
+    pub vertices: Vec<[f32; 3]>,
+}
+
+/// The key values of an entity in the map
+#[derive(Debug, Default, Clone)]
+pub struct Entity {
+    pub class_name: String,
+    pub properties: Vec<(String, String)>,
+}
+
+impl Scene {
+    /// Load all geometry, materials and entities from the map
+    pub fn load(
+        bsp: &Bsp,
+        lumps: &ExtraLumps,
+        loader: &Loader,
+        options: &ConvertOptions,
+    ) -> Result<Self, Error> {
+        let mut scene = SceneBuilder::default();
+
+        let report = &mut scene.scene.report;
+        let visibility = FaceVisibility::new(bsp, options)?;
+        let bsp_models = bsp_models(bsp)?;
+        let (overlays, decals) = match options.includes(Layer::World) {
+            true => (
+                overlays(bsp, &lumps.overlays, report),
+                decals(bsp, loader, report),
+            ),
+            false => (Vec::new(), Vec::new()),
+        };
+        let in_region = |overlay: &Overlay| {
+            visibility.region().map_or(true, |region| {
+                region.intersects_points(overlay.corners().map(map_coords))
+            })
+        };
+        let overlays: Vec<_> = overlays.into_iter().filter(in_region).collect();
+        let decals: Vec<_> = decals.into_iter().filter(in_region).collect();
+        let mut props = prop_placements(bsp, visibility.sky_area(), report);
+        props.retain(|prop| options.includes(prop.layer));
+        let models = load_props(loader, &props, report);
+        if let Some(region) = visibility.region() {
+            props.retain(|prop| {
+                let placement = &prop.placement;
+                models.get(placement.model).map_or(true, |model| {
+                    region.intersects_prop(
+                        model,
+                        placement.origin,
+                        placement.rotation,
+                        placement.scale,
+                    )
+                })
+            });
+        }
+
+        if options.textures {
+            // collect the materials in the order they're used, to keep the output stable
+            let face_materials = bsp_models.iter().flat_map(|(model, offset)| {
+                let world = is_world(bsp, model);
+                model_faces(model, *offset, world, &visibility, options).map(|(index, face, _)| {
+                    (face.texture().name().to_string(), format!("face {index}"))
+                })
+            });
+            let overlay_materials = overlays
+                .iter()
+                .chain(decals.iter())
+                .map(|overlay| (overlay.material.clone(), overlay.reference.clone()));
+            // props share their model and skin a lot, only look up the materials once for each
+            let mut skin_materials: HashMap<(&str, i32), Vec<String>> = HashMap::default();
+            for prop in &props {
+                let placement = &prop.placement;
+                skin_materials
+                    .entry((placement.model, placement.skin))
+                    .or_insert_with(|| {
+                        prop_materials(loader, &models, placement.model, placement.skin, options)
+                    });
+            }
+            let prop_materials = props.iter().flat_map(|prop| {
+                let placement = &prop.placement;
+                skin_materials[&(placement.model, placement.skin)]
+                    .iter()
+                    .map(|material| (material.clone(), prop.reference.clone()))
+            });
+            let names: Vec<(String, String)> = face_materials
+                .chain(overlay_materials)
+                .chain(prop_materials)
+                .collect();
+            scene.preload_materials(&names, loader, options);
+        }
+
+        // nodes in every area, nodes outside of any area are in area 0
+        let mut area_nodes: BTreeMap<u16, Vec<usize>> = BTreeMap::new();
+        for (model, offset) in bsp_models {
+            let world = is_world(bsp, &model);
+            let nodes = load_bsp_model(
+                &mut scene,
+                loader,
+                &model,
+                offset,
+                world,
+                &visibility,
+                options,
+            );
+            for (area, node) in nodes {
+                let node = scene.push_node(node);
+                if area > 0 {
+                    area_nodes.entry(area).or_default().push(node);
+                }
+            }
+        }
+
+        if let Some(mesh) = overlay_mesh(&mut scene, loader, bsp, &overlays, options) {
+            scene.push_mesh_node("overlays", mesh, Layer::World);
+        }
+
+        if let Some(mesh) = decal_mesh(&mut scene, loader, bsp, &decals, options) {
+            scene.push_mesh_node("decals", mesh, Layer::World);
+        }
+
+        for PropSource {
+            placement: prop,
+            layer,
+            ..
+        } in props
+        {
+            if let Some(mesh) =
+                load_or_get_model(&mut scene, loader, &models, prop.model, prop.skin, options)
+            {
+                let mut extras = Map::new();
+                if let Some(variant) = skin_variant(&scene.scene.meshes[mesh], prop.skin) {
+                    extras.insert("variant".into(), variant.into());
+                }
+                let node = scene.push_node(Node {
+                    name: Some(prop.model.into()),
+                    mesh: Some(mesh),
+                    translation: map_coords(prop.origin),
+                    rotation: prop.rotation,
+                    scale: prop.scale,
+                    layer: Some(layer),
+                    extras,
+                    ..Node::default()
+                });
+                if options.areas {
+                    let area = leaf_area(&bsp.leaf_at(prop.origin));
+                    if area > 0 {
+                        area_nodes.entry(area).or_default().push(node);
+                    }
+                }
+            }
+        }
+
+        scene.scene.entities = bsp
+            .entities
+            .iter()
+            .map(|entity| Entity {
+                class_name: entity.prop("classname").unwrap_or_default().into(),
+                properties: entity
+                    .properties()
+                    .map(|(key, value)| (key.into(), value.into()))
+                    .collect(),
+            })
+            .collect();
+
+        for (area, children) in area_nodes {
+            let mut extras = Map::new();
+            extras.insert("area".into(), area.into());
+            let layer = match Some(area) == visibility.sky_area() {
+                true => Layer::Skybox,
+                false => Layer::World,
+            };
+            let node = scene.push_node(Node {
+                name: Some(format!("area {area}")),
+                children,
+                layer: Some(layer),
+                extras,
+                ..Node::default()
+            });
+            let portals = lumps
+                .areas
+                .get(area as usize)
+                .map(|area| area.portals.as_slice())
+                .unwrap_or_default()
+                .iter()
+                .map(|portal| AreaPortal {
+                    other_area: portal.other_area as usize,
+                    vertices: portal.vertices.iter().copied().map(map_coords).collect(),
+                })
+                .collect();
+            scene.scene.areas.push(Area {
+                index: area as usize,
+                node,
+                portals,
+            });
+        }
+
+        if options.layers {
+            scene.group_layers();
+        }
+
+        let mut scene = scene.finish();
+        if options.reflection_probes.is_some() || options.image_based_light {
+            scene.cubemaps = load_cubemaps(bsp, lumps, options, &mut scene.report);
+        }
+        if options.image_based_light {
+            scene.environment = environment_cubemap(bsp, &scene.cubemaps);
+        }
+
+        if let Some(mode) = options.recenter {
+            let point = recenter_point(bsp, &scene, mode, options)?;
+            scene.translate(point * -1.0);
+            scene.origin_offset = point * -1.0;
+            // the extras describe the output, so the offset is stored in output units and axes
+            let offset = options.coordinates.rotation()
+                * (Vector3::from(map_coords(point * -1.0)) * options.unit_scale());
+            scene.extras.insert(
+                "origin_offset".into(),
+                json!([offset.x, offset.y, offset.z]),
+            );
+        }
+
+        let unit_scale = options.unit_scale();
+        if unit_scale != 1.0 {
+            scene.scale(unit_scale);
+        }
+
+        Ok(scene)
+    }
+
+    /// Load a single model with the selected skin, body groups and level of detail
+    pub fn load_model(
+        loader: &Loader,
+        name: &str,
+        model_options: &ModelOptions,
+        options: &ConvertOptions,
+    ) -> Result<Self, Error> {
+        let model = load_model(loader, name, model_options)?;
+
+        let mut scene = SceneBuilder::default();
+        let mesh = model_mesh(
+            &mut scene,
+            loader,
+            &model,
+            model_options.skin,
+            name.into(),
+            options,
+        );
+        let mesh = scene.push_mesh(mesh);
+        scene.push_node(Node {
+            name: Some(name.into()),
+            mesh: Some(mesh),
+            ..Node::default()
+        });
+
+        let mut scene = scene.finish();
+        let unit_scale = options.unit_scale();
+        if unit_scale != 1.0 {
+            scene.scale(unit_scale);
+        }
+
+        Ok(scene)
+    }
+}
+
+impl Scene {
+    /// Bounding box of all meshes placed in the scene
+    pub fn bounds(&self) -> Option<([f32; 3], [f32; 3])> {
+        let mut bounds: Option<([f32; 3], [f32; 3])> = None;
+        for node in &self.nodes {
+            let Some(mesh) = node.mesh.and_then(|mesh| self.meshes.get(mesh)) else {
+                continue;
+            };
+            let translation = Vector3::from(node.translation);
+            for position in mesh
+                .primitives
+                .iter()
+                .flat_map(|primitive| &primitive.positions)
+            {
+                let position: [f32; 3] =
+                    (node.rotation * (Vector3::from(*position) * node.scale) + translation).into();
+                let (min, max) = bounds.get_or_insert((position, position));
+                for axis in 0..3 {
+                    min[axis] = min[axis].min(position[axis]);
+                    max[axis] = max[axis].max(position[axis]);
+                }
+            }
+        }
+        bounds
+    }
+
+    /// Move the scene by an offset in map coordinates
+    ///
+    /// Only the nodes without children are moved, so group nodes keep having no transform of their own and writers
+    /// that flatten the node hierarchy don't need to apply the transform of the groups.
+    pub fn translate(&mut self, offset: Vector) {
+        let mapped = map_coords(offset);
+        for node in self
+            .nodes
+            .iter_mut()
+            .filter(|node| node.children.is_empty())
+        {
+            node.translation = std::array::from_fn(|axis| node.translation[axis] + mapped[axis]);
+        }
+        for cubemap in self.cubemaps.iter_mut() {
+            cubemap.origin = cubemap.origin + offset;
+        }
+        for vertex in self.portal_vertices() {
+            *vertex = std::array::from_fn(|axis| vertex[axis] + mapped[axis]);
+        }
+    }
+
+    /// Scale all positions and distances in the scene
+    ///
+    /// The translation of every node is scaled, not just those of the root nodes. Since a uniform scale commutes with
+    /// the rotation and scale of the parent nodes, this scales the world positions of nested nodes by the same factor.
+    pub fn scale(&mut self, factor: f32) {
+        let scale = |position: &mut [f32; 3]| *position = position.map(|axis| axis * factor);
+        for primitive in self
+            .meshes
+            .iter_mut()
+            .flat_map(|mesh| mesh.primitives.iter_mut())
+        {
+            primitive.positions.iter_mut().for_each(scale);
+        }
+        for node in self.nodes.iter_mut() {
+            scale(&mut node.translation);
+        }
+        for cubemap in self.cubemaps.iter_mut() {
+            cubemap.origin = cubemap.origin * factor;
+        }
+        self.portal_vertices().for_each(scale);
+        for water in self
+            .materials
+            .iter_mut()
+            .filter_map(|material| material.water.as_mut())
+        {
+            water.fog_start *= factor;
+            water.fog_end *= factor;
+        }
+    }
+
+    /// Replace the materials using a texture that failed to encode with their fallback
+    ///
+    /// The errors of the failed textures are given by [`texture_key`].
+    pub fn fallback_unencoded(
+        &mut self,
+        failed: &HashMap<String, Error>,
+        options: &ConvertOptions,
+    ) {
+        if failed.is_empty() {
+            return;
+        }
+        for material in self.materials.iter_mut() {
+            let error = material
+                .texture
+                .iter()
+                .chain(material.normal_map.iter())
+                .find_map(|texture| failed.get(&texture_key(&texture.name)));
+            if let Some(error) = error {
+                error!(error = ?error, material = material.name, "failed to encode material");
+                self.report
+                    .material_error(&material.name, error, Vec::new());
+                *material = encoding_fallback(material, options);
+            }
+        }
+        self.remove_hidden();
+    }
+
+    /// Remove the primitives using materials that are hidden by their fallback
+    fn remove_hidden(&mut self) {
+        let hidden: Vec<bool> = self
+            .materials
+            .iter()
+            .map(|material| material.fallback == Some(Fallback::Hide))
+            .collect();
+        if hidden.contains(&true) {
+            self.retain_primitives(|primitive| {
+                primitive
+                    .material
+                    .map_or(true, |material| !hidden[material])
+            });
+        }
+    }
+
+    /// Split the nodes of every area into a scene of their own
+    ///
+    /// Returns the scene with everything outside of the areas, which also keeps the entities, cubemaps and report,
+    /// and the scene for every area.
+    pub fn split_areas(self) -> (Scene, Vec<(usize, Scene)>) {
+        let areas = self
+            .areas
+            .iter()
+            .map(|area| {
+                let mut scene = self.extract(&[area.node], &HashSet::default());
+                // children are added before their parent
+                let node = scene.nodes.len() - 1;
+                scene.areas = vec![Area {
+                    node,
+                    ..area.clone()
+                }];
+                (area.index, scene)
+            })
+            .collect();
+
+        let children = self.child_nodes();
+        let area_nodes: HashSet<usize> = self.areas.iter().map(|area| area.node).collect();
+        let remaining: Vec<usize> = (0..self.nodes.len())
+            .filter(|node| !children.contains(node) && !area_nodes.contains(node))
+            .collect();
+        let mut rest = self.extract(&remaining, &area_nodes);
+        rest.entities = self.entities;
+        rest.cubemaps = self.cubemaps;
+        rest.environment = self.environment;
+        rest.extras = self.extras;
+        rest.origin_offset = self.origin_offset;
+        rest.report = self.report;
+
+        (rest, areas)
+    }
+
+    /// Indices of all nodes that are the child of another node
+    pub fn child_nodes(&self) -> HashSet<usize> {
+        self.nodes
+            .iter()
+            .flat_map(|node| node.children.iter().copied())
+            .collect()
+    }
+
+    /// Copy the given nodes with their children and the meshes and materials they use into a new scene
+    ///
+    /// Nodes in `skip` are left out together with their children.
+    fn extract(&self, roots: &[usize], skip: &HashSet<usize>) -> Scene {
+        let mut extract = Extract {
+            source: self,
+            skip,
+            scene: Scene {
+                variants: self.variants.clone(),
+                ..Scene::default()
+            },
+            meshes: HashMap::default(),
+            materials: HashMap::default(),
+        };
+        for root in roots {
+            extract.node(*root);
+        }
+        extract.scene
+    }
+
+    fn portal_vertices(&mut self) -> impl Iterator<Item = &mut [f32; 3]> {
+        self.areas
+            .iter_mut()
+            .flat_map(|area| area.portals.iter_mut())
+            .flat_map(|portal| portal.vertices.iter_mut())
+    }
+
+    /// Remove all primitives not matching the filter
+    ///
+    /// Meshes that are left without primitives are removed together with the nodes using them.
+    pub fn retain_primitives(&mut self, mut filter: impl FnMut(&Primitive) -> bool) {
+        for mesh in self.meshes.iter_mut() {
+            mesh.primitives.retain(&mut filter);
+        }
+
+        let mut mesh_map = Vec::with_capacity(self.meshes.len());
+        let mut kept = 0;
+        for mesh in &self.meshes {
+            mesh_map.push((!mesh.primitives.is_empty()).then_some(kept));
+            if !mesh.primitives.is_empty() {
+                kept += 1;
+            }
+        }
+        self.meshes.retain(|mesh| !mesh.primitives.is_empty());
+
+        let mut node_map = Vec::with_capacity(self.nodes.len());
+        let mut kept = 0;
+        for node in self.nodes.iter_mut() {
+            let keep = match node.mesh {
+                Some(mesh) => {
+                    node.mesh = mesh_map[mesh];
+                    node.mesh.is_some()
+                }
+                None => true,
+            };
+            node_map.push(keep.then_some(kept));
+            if keep {
+                kept += 1;
+            }
+        }
+        let mut keep = node_map.iter().map(Option::is_some);
+        self.nodes.retain(|_| keep.next().unwrap_or_default());
+        for node in self.nodes.iter_mut() {
+            node.children = node
+                .children
+                .iter()
+                .filter_map(|child| node_map[*child])
+                .collect();
+        }
+        for area in self.areas.iter_mut() {
+            area.node = node_map[area.node].expect("nodes without mesh are always kept");
+        }
+    }
+}
+
+/// State for copying part of a scene into a new scene
+struct Extract<'a> {
+    source: &'a Scene,
+    skip: &'a HashSet<usize>,
+    scene: Scene,
+    /// Index of the copied meshes and materials in the new scene
+    meshes: HashMap<usize, usize>,
+    materials: HashMap<usize, usize>,
+}
+
+impl Extract<'_> {
+    /// Copy a node with its children, returns the index in the new scene
+    fn node(&mut self, index: usize) -> usize {
+        let source = self.source;
+        let mut node = source.nodes[index].clone();
+        node.mesh = node.mesh.map(|mesh| self.mesh(mesh));
+        node.children = node
+            .children
+            .iter()
+            .filter(|child| !self.skip.contains(child))
+            .map(|child| self.node(*child))
+            .collect();
+        self.scene.nodes.push(node);
+        self.scene.nodes.len() - 1
+    }
+
+    fn mesh(&mut self, index: usize) -> usize {
+        if let Some(mesh) = self.meshes.get(&index) {
+            return *mesh;
+        }
+        let mut mesh = self.source.meshes[index].clone();
+        for primitive in mesh.primitives.iter_mut() {
+            primitive.material = primitive.material.map(|material| self.material(material));
+            for variant in primitive.variants.iter_mut() {
+                *variant = variant.map(|material| self.material(material));
+            }
+        }
+        self.scene.meshes.push(mesh);
+        self.meshes.insert(index, self.scene.meshes.len() - 1);
+        self.scene.meshes.len() - 1
+    }
+
+    fn material(&mut self, index: usize) -> usize {
+        *self.materials.entry(index).or_insert_with(|| {
+            self.scene
+                .materials
+                .push(self.source.materials[index].clone());
+            self.scene.materials.len() - 1
+        })
+    }
+}
+
+/// Key for the mesh of a model with a specific skin
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MeshKey {
+    pub model: String,
+    pub skin: i32,
+}
+
+impl MeshKey {
+    pub fn new(model: &str, skin: i32) -> Self {
+        MeshKey {
+            model: normalize_path(model),
+            skin,
+        }
+    }
+}
+
+/// Builds a scene while keeping hash indexes of the loaded materials and meshes
+#[derive(Default)]
+pub struct SceneBuilder {
+    pub scene: Scene,
+    materials: HashMap<String, usize>,
+    meshes: HashMap<MeshKey, usize>,
+}
+
+impl SceneBuilder {
+    pub fn finish(mut self) -> Scene {
+        self.scene.remove_hidden();
+        self.scene
+    }
+
+    /// Get the index of a material, loading it if it's not used yet
+    pub fn material(&mut self, name: &str, loader: &Loader, options: &ConvertOptions) -> usize {
+        let name = name.to_ascii_lowercase();
+        if let Some(index) = self.materials.get(&normalize_path(&name)) {
+            return *index;
+        }
+        let result = load_material(&name, &[String::new()], loader, options);
+        let material = self.material_or_fallback(&name, result, Vec::new(), loader, options);
+        self.push_material(material)
+    }
+
+    /// Get the index of a blended copy of a material, for overlays drawn on top of a surface
+    ///
+    /// Overlays are blended even if their material isn't translucent,
+    /// the material itself is left unchanged for any faces or props using it directly.
+    pub fn overlay_material(
+        &mut self,
+        name: &str,
+        loader: &Loader,
+        options: &ConvertOptions,
+    ) -> usize {
+        let index = self.material(name, loader, options);
+        let material = &self.scene.materials[index];
+        if material.translucent || material.alpha_test.is_some() {
+            return index;
+        }
+        let overlay_name = format!("{} (overlay)", material.name);
+        if let Some(index) = self.materials.get(&normalize_path(&overlay_name)) {
+            return *index;
+        }
+        let overlay = MaterialData {
+            name: overlay_name,
+            translucent: true,
+            ..material.clone()
+        };
+        self.push_material(overlay)
+    }
+
+    /// Load all materials that aren't loaded yet in parallel
+    ///
+    /// Every material is given with a description of what references it, for the report.
+    pub fn preload_materials(
+        &mut self,
+        names: &[(String, String)],
+        loader: &Loader,
+        options: &ConvertOptions,
+    ) {
+        let mut references: HashMap<String, Vec<String>> = HashMap::default();
+        let mut new_names = Vec::new();
+        for (name, reference) in names {
+            let name = name.to_ascii_lowercase();
+            let key = normalize_path(&name);
+            if self.materials.contains_key(&key) {
+                continue;
+            }
+            let material_references = references.entry(key).or_default();
+            if material_references.is_empty() {
+                new_names.push(name);
+            }
+            if material_references.last() != Some(reference) {
+                material_references.push(reference.clone());
+            }
+        }
+
+        let results: Vec<_> = new_names
+            .par_iter()
+            .map(|name| load_material(name, &[String::new()], loader, options))
+            .collect();
+        for (name, result) in new_names.iter().zip(results) {
+            let references = references.remove(&normalize_path(name)).unwrap_or_default();
+            let material = self.material_or_fallback(name, result, references, loader, options);
+            self.push_material(material);
+        }
+    }
+
+    fn material_or_fallback(
+        &mut self,
+        name: &str,
+        result: Result<MaterialData, Error>,
+        references: Vec<String>,
+        loader: &Loader,
+        options: &ConvertOptions,
+    ) -> MaterialData {
+        match result {
+            Ok(material) => material,
+            Err(e) => {
+                error!(error = ?e, material = name, "failed to load material");
+                self.scene.report.material_error(name, &e, references);
+                fallback_material(name, loader, options)
+            }
+        }
+    }
+
+    fn push_material(&mut self, material: MaterialData) -> usize {
+        let index = self.scene.materials.len();
+        self.materials.insert(normalize_path(&material.name), index);
+        self.scene.materials.push(material);
+        index
+    }
+
+    pub fn mesh(&self, key: &MeshKey) -> Option<usize> {
+        self.meshes.get(key).copied()
+    }
+
+    pub fn push_mesh(&mut self, mesh: Mesh) -> usize {
+        self.scene.meshes.push(mesh);
+        self.scene.meshes.len() - 1
+    }
+
+    /// Add a mesh that can later be found by its key
+    pub fn push_keyed_mesh(&mut self, key: MeshKey, mesh: Mesh) -> usize {
+        let index = self.push_mesh(mesh);
+        self.meshes.insert(key, index);
+        index
+    }
+
+    pub fn push_node(&mut self, node: Node) -> usize {
+        self.scene.nodes.push(node);
+        self.scene.nodes.len() - 1
+    }
+
+    fn push_mesh_node(&mut self, name: &str, mesh: Mesh, layer: Layer) {
+        let mesh = self.push_mesh(mesh);
+        self.push_node(Node {
+            name: Some(name.into()),
+            mesh: Some(mesh),
+            layer: Some(layer),
+            ..Node::default()
+        });
+    }
+
+    /// Group all top level nodes under a node for their layer
+    fn group_layers(&mut self) {
+        let children = self.scene.child_nodes();
+        let mut layers: BTreeMap<Layer, Vec<usize>> = BTreeMap::new();
+        for (index, node) in self.scene.nodes.iter().enumerate() {
+            if let Some(layer) = node.layer.filter(|_| !children.contains(&index)) {
+                layers.entry(layer).or_default().push(index);
+            }
+        }
+        for (layer, children) in layers {
+            let mut extras = Map::new();
+            extras.insert("layer".into(), layer.name().into());
+            self.push_node(Node {
+                name: Some(layer.name().into()),
+                children,
+                layer: Some(layer),
+                extras,
+                ..Node::default()
+            });
+        }
+    }
+}
+
+fn is_world(bsp: &Bsp, model: &Handle<Model>) -> bool {
+    ptr::eq(model.as_ref(), &bsp.models[0])
+}
+
+/// Normalize an asset path for use as lookup key
+pub fn normalize_path(path: &str) -> String {
+    let path = path.to_ascii_lowercase().replace('\\', "/");
+    let path = path.trim_start_matches('/');
+    path.strip_suffix(".vmt").unwrap_or(path).to_string()
+}
+
+/// All static props and prop entities in the map
+/// Entity classes that are loaded as props
+const PROP_CLASSES: [&str; 3] = ["prop_dynamic", "prop_physics", "prop_dynamic_override"];
+
+fn prop_placements<'a>(
+    bsp: &'a Bsp,
+    sky_area: Option<u16>,
+    report: &mut ConversionReport,
+) -> Vec<PropSource<'a>> {
+    let layer = |placement: &PropPlacement, layer: Layer| match sky_area.is_some()
+        && Some(leaf_area(&bsp.leaf_at(placement.origin))) == sky_area
+    {
+        true => Layer::Skybox,
+        false => layer,
+    };
+    let static_props = bsp.static_props().enumerate().map(|(index, prop)| {
+        let placement = prop.as_prop_placement();
+        PropSource {
+            layer: layer(&placement, Layer::StaticProps),
+            placement,
+            reference: format!("static prop {index}"),
+        }
+    });
+    let entity_props = bsp
+        .entities
+        .iter()
+        .enumerate()
+        .filter_map(|(index, ent)| match ent.parse() {
+            Ok(parsed) => Some((index, parsed)),
+            Err(e) => {
+                let class = ent.prop("classname").ok()?;
+                if PROP_CLASSES.contains(&class) {
+                    warn!(error = ?e, index, class, "invalid prop entity");
+                    report.skipped_entity(
+                        class,
+                        e.to_string(),
+                        format!("entity {index} ({class})"),
+                    );
+                }
+                None
+            }
+        })
+        .filter_map(|(index, ent)| {
+            let (placement, class) = match ent {
+                BspEntity::PropDynamic(prop) => (prop.as_prop_placement(), "prop_dynamic"),
+                BspEntity::PropPhysics(prop) => (prop.as_prop_placement(), "prop_physics"),
+                BspEntity::PropDynamicOverride(prop) => {
+                    (prop.as_prop_placement(), "prop_dynamic_override")
+                }
+                _ => return None,
+            };
+            Some(PropSource {
+                layer: layer(&placement, Layer::DynamicProps),
+                placement,
+                reference: format!("entity {index} ({class})"),
+            })
+        });
+    static_props.chain(entity_props).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FallbackMode;
+    use cgmath::{Deg, Rotation3};
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    /// A scene with a single node using the material
+    fn material_scene(material: MaterialData) -> Scene {
+        Scene {
+            materials: vec![material],
+            meshes: vec![Mesh {
+                name: None,
+                primitives: vec![Primitive {
+                    positions: vec![[0.0; 3], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+                    material: Some(0),
+                    ..Primitive::default()
+                }],
+            }],
+            nodes: vec![Node {
+                mesh: Some(0),
+                ..Node::default()
+            }],
+            ..Scene::default()
+        }
+    }
+
+    /// A scene with a mesh placed by a root node and by a node in an area group
+    fn area_scene() -> Scene {
+        let mesh = Mesh {
+            name: None,
+            primitives: vec![Primitive {
+                positions: vec![[0.0; 3], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+                ..Primitive::default()
+            }],
+        };
+        let nodes = vec![
+            Node {
+                mesh: Some(0),
+                translation: [1.0, 2.0, 3.0],
+                ..Node::default()
+            },
+            Node {
+                children: vec![0],
+                ..Node::default()
+            },
+            Node {
+                mesh: Some(0),
+                translation: [-1.0, 0.0, 0.0],
+                ..Node::default()
+            },
+        ];
+        Scene {
+            meshes: vec![mesh],
+            nodes,
+            areas: vec![Area {
+                index: 2,
+                node: 1,
+                portals: Vec::new(),
+            }],
+            ..Scene::default()
+        }
+    }
+
+    /// The world position of a node, including the transforms of its parents
+    fn world_position(scene: &Scene, index: usize) -> [f32; 3] {
+        let node = &scene.nodes[index];
+        let local = Vector3::from(node.translation);
+        match scene
+            .nodes
+            .iter()
+            .position(|parent| parent.children.contains(&index))
+        {
+            Some(parent_index) => {
+                let parent = &scene.nodes[parent_index];
+                let parent_position = Vector3::from(world_position(scene, parent_index));
+                (parent.rotation * (local * parent.scale) + parent_position).into()
+            }
+            None => local.into(),
+        }
+    }
+
+    #[test]
+    fn translate_grouped_nodes() {
+        let mut scene = area_scene();
+        // map coordinates, [y, z, x] in the scene
+        scene.translate(Vector {
+            x: 30.0,
+            y: 10.0,
+            z: 20.0,
+        });
+        assert_eq!(world_position(&scene, 0), [11.0, 22.0, 33.0]);
+        assert_eq!(scene.nodes[1].translation, [0.0; 3]);
+        assert_eq!(world_position(&scene, 2), [9.0, 20.0, 30.0]);
+    }
+
+    #[test]
+    fn translate_nested_groups() {
+        // a prop in an area group within another group, next to a prop in a group
+        let nodes = vec![
+            Node {
+                translation: [1.0, 2.0, 3.0],
+                ..Node::default()
+            },
+            Node {
+                children: vec![0],
+                ..Node::default()
+            },
+            Node {
+                children: vec![1],
+                ..Node::default()
+            },
+            Node {
+                translation: [0.0, 0.0, 5.0],
+                ..Node::default()
+            },
+            Node {
+                children: vec![3],
+                ..Node::default()
+            },
+        ];
+        let mut scene = Scene {
+            nodes,
+            ..Scene::default()
+        };
+        scene.translate(Vector {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        });
+
+        assert_eq!(world_position(&scene, 0), [2.0, 2.0, 3.0]);
+        assert_eq!(world_position(&scene, 3), [1.0, 0.0, 5.0]);
+        for group in [1, 2, 4] {
+            assert_eq!(scene.nodes[group].translation, [0.0; 3]);
+        }
+    }
+
+    #[test]
+    fn translate_and_scale_layers() {
+        let mut builder = SceneBuilder::default();
+        for (translation, layer) in [
+            ([1.0, 2.0, 3.0], Layer::World),
+            ([4.0, 0.0, 0.0], Layer::StaticProps),
+            ([0.0, 0.0, 5.0], Layer::StaticProps),
+        ] {
+            builder.push_node(Node {
+                translation,
+                layer: Some(layer),
+                ..Node::default()
+            });
+        }
+        builder.group_layers();
+        let mut scene = builder.finish();
+        assert_eq!(scene.nodes.len(), 5);
+
+        scene.translate(Vector {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        });
+        scene.scale(2.0);
+        assert_eq!(world_position(&scene, 0), [4.0, 4.0, 6.0]);
+        assert_eq!(world_position(&scene, 1), [10.0, 0.0, 0.0]);
+        assert_eq!(world_position(&scene, 2), [2.0, 0.0, 10.0]);
+    }
+
+    #[test]
+    fn scale_nested_nodes() {
+        let mut scene = Scene {
+            nodes: vec![
+                Node {
+                    translation: [1.0, 0.0, 0.0],
+                    ..Node::default()
+                },
+                Node {
+                    translation: [0.0, 10.0, 0.0],
+                    rotation: Quaternion::from_angle_y(Deg(90.0)),
+                    scale: 3.0,
+                    children: vec![0],
+                    ..Node::default()
+                },
+            ],
+            ..Scene::default()
+        };
+        let before = world_position(&scene, 0);
+        scene.scale(0.5);
+        let after = world_position(&scene, 0);
+        for axis in 0..3 {
+            assert!((after[axis] - before[axis] * 0.5).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn split_translated_areas() {
+        let mut scene = area_scene();
+        scene.translate(Vector {
+            x: 0.0,
+            y: 10.0,
+            z: 0.0,
+        });
+        let (rest, areas) = scene.split_areas();
+
+        assert_eq!(rest.nodes.len(), 1);
+        assert_eq!(rest.nodes[0].translation, [9.0, 0.0, 0.0]);
+        assert_eq!(rest.meshes.len(), 1);
+
+        assert_eq!(areas.len(), 1);
+        let (index, area) = &areas[0];
+        assert_eq!(*index, 2);
+        assert_eq!(area.nodes.len(), 2);
+        assert_eq!(area.areas[0].node, 1);
+        assert_eq!(area.nodes[1].children, [0]);
+        assert_eq!(world_position(area, 0), [11.0, 2.0, 3.0]);
+        assert_eq!(area.meshes.len(), 1);
+    }
+
+    #[test]
+    fn fallback_unencoded_materials() {
+        let red = MaterialData {
+            name: "red".into(),
+            texture: Some(TextureData {
+                name: "red".into(),
+                image: DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+                    2,
+                    2,
+                    Rgba([255, 0, 0, 255]),
+                )),
+                sprite_sheet: None,
+            }),
+            ..MaterialData::default()
+        };
+        let failed =
+            HashMap::from_iter([(texture_key("red"), Error::Encode("broken encoder".into()))]);
+
+        let mut scene = material_scene(red.clone());
+        scene.fallback_unencoded(&failed, &ConvertOptions::default());
+        assert_eq!(scene.materials[0].name, "red");
+        assert!(scene.materials[0].texture.is_none());
+        assert_eq!(scene.materials[0].color, [255, 0, 255, 255]);
+        assert_eq!(scene.materials[0].fallback, Some(Fallback::Magenta));
+
+        let options = ConvertOptions {
+            fallback: FallbackMode::Average,
+            ..ConvertOptions::default()
+        };
+        let mut scene = material_scene(red.clone());
+        scene.fallback_unencoded(&failed, &options);
+        assert!(scene.materials[0].texture.is_none());
+        assert_eq!(scene.materials[0].color, [255, 0, 0, 255]);
+        assert_eq!(scene.materials[0].fallback, Some(Fallback::Average));
+        assert_eq!(scene.meshes[0].primitives.len(), 1);
+
+        let options = ConvertOptions {
+            fallback: FallbackMode::Hide,
+            ..ConvertOptions::default()
+        };
+        let mut hidden = material_scene(red);
+        hidden.fallback_unencoded(&failed, &options);
+        assert!(hidden.meshes.is_empty());
+        assert!(hidden.nodes.is_empty());
+    }
+
+    #[test]
+    fn hide_unencoded_area_nodes() {
+        let mut scene = area_scene();
+        scene.meshes[0].primitives[0].material = Some(0);
+        scene.materials = vec![MaterialData {
+            name: "red".into(),
+            texture: Some(TextureData {
+                name: "red".into(),
+                image: DynamicImage::new_rgba8(1, 1),
+                sprite_sheet: None,
+            }),
+            ..MaterialData::default()
+        }];
+        let failed =
+            HashMap::from_iter([(texture_key("red"), Error::Encode("broken encoder".into()))]);
+        let options = ConvertOptions {
+            fallback: FallbackMode::Hide,
+            ..ConvertOptions::default()
+        };
+        scene.fallback_unencoded(&failed, &options);
+
+        // the area group is kept without the hidden nodes
+        assert!(scene.meshes.is_empty());
+        assert_eq!(scene.nodes.len(), 1);
+        assert!(scene.nodes[0].children.is_empty());
+        assert_eq!(scene.areas[0].node, 0);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::convert::{pad_byte_vector, GltfOutput};
+use crate::encode::TextureFormat;
+use crate::Error;
+use ahash::{HashMap, RandomState};
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use std::fs::{create_dir_all, write};
+use std::path::Path;
+
+/// Writes converted maps as a text `.gltf` with an external `.bin` buffer and separate image files
+///
+/// Images are written into a directory next to the gltf files, named after their source texture path.
+/// Images that are already written by a previous map with the same content are shared.
+pub struct SeparateWriter {
+    image_dir: String,
+    /// Content hash of every image written so far, by relative path
+    images: HashMap<String, u64>,
+}
+
+impl SeparateWriter {
+    /// Create a writer storing images in `image_dir`, relative to the gltf files
+    pub fn new(image_dir: impl Into<String>) -> Self {
+        SeparateWriter {
+            image_dir: image_dir.into(),
+            images: HashMap::default(),
+        }
+    }
+
+    /// Write the map to `path`, with the buffer next to it and images into the image directory
+    pub fn write(&mut self, output: GltfOutput, path: &Path) -> Result<(), Error> {
+        let GltfOutput {
+            mut json, buffer, ..
+        } = output;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let stem = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| Error::Other(format!("invalid output path {}", path.display())))?;
+
+        let views = take_array(&mut json, "bufferViews");
+        let mut images = take_array(&mut json, "images");
+
+        let image_views: Vec<u64> = images
+            .iter()
+            .filter_map(|image| image["bufferView"].as_u64())
+            .collect();
+
+        for image in images.iter_mut() {
+            let Some(view) = image["bufferView"].as_u64() else {
+                continue;
+            };
+            let data = view_data(&views[view as usize], &buffer);
+            let name = image["name"].as_str().unwrap_or("image").to_string();
+            let extension = image_extension(image["mimeType"].as_str().unwrap_or_default());
+            let uri = self.write_image(dir, &name, extension.unwrap_or("bin"), data)?;
+
+            let image = image.as_object_mut().expect("image isn't an object");
+            image.remove("bufferView");
+            // the type of images with an unknown type can't be derived from the file extension
+            if extension.is_some() {
+                image.remove("mimeType");
+            }
+            image.insert("uri".into(), uri.into());
+        }
+
+        // copy the remaining views into a new buffer without the images
+        let mut new_buffer = Vec::new();
+        let mut new_views = Vec::new();
+        let mut view_map = vec![None; views.len()];
+        for (index, mut view) in views.into_iter().enumerate() {
+            if image_views.contains(&(index as u64)) {
+                continue;
+            }
+            let data = view_data(&view, &buffer);
+            view["byteOffset"] = new_buffer.len().into();
+            new_buffer.extend_from_slice(data);
+            pad_byte_vector(&mut new_buffer);
+
+            view_map[index] = Some(new_views.len());
+            new_views.push(view);
+        }
+
+        if let Some(accessors) = json["accessors"].as_array_mut() {
+            for accessor in accessors {
+                if let Some(view) = accessor["bufferView"].as_u64() {
+                    accessor["bufferView"] = view_map[view as usize].into();
+                }
+            }
+        }
+
+        let bin_name = format!("{stem}.bin");
+        json["buffers"] = json!([{
+            "byteLength": new_buffer.len(),
+            "uri": bin_name,
+        }]);
+        json["bufferViews"] = Value::Array(new_views);
+        if !images.is_empty() {
+            json["images"] = Value::Array(images);
+        }
+
+        write(dir.join(&bin_name), &new_buffer)?;
+        write(
+            path,
+            serde_json::to_string_pretty(&json).expect("Serialization error"),
+        )?;
+        Ok(())
+    }
+
+    /// Write an image unless an image with the same name and content has already been written
+    ///
+    /// Returns the uri of the image, relative to the output directory.
+    fn write_image(
+        &mut self,
+        dir: &Path,
+        name: &str,
+        extension: &str,
+        data: &[u8],
+    ) -> Result<String, Error> {
+        let hash = RandomState::with_seeds(1, 2, 3, 4).hash_one(data);
+        let name = image_path(name);
+
+        let mut suffix = 0;
+        let uri = loop {
+            let uri = match suffix {
+                0 => format!("{}/{name}.{extension}", self.image_dir),
+                _ => format!("{}/{name}_{suffix}.{extension}", self.image_dir),
+            };
+            match self.images.get(&uri) {
+                Some(existing) if *existing == hash => return Ok(uri),
+                Some(_) => suffix += 1,
+                None => break uri,
+            }
+        };
+
+        let path = dir.join(&uri);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        write(&path, data)?;
+        self.images.insert(uri.clone(), hash);
+        Ok(uri)
+    }
+}
+
+fn take_array(json: &mut Value, key: &str) -> Vec<Value> {
+    match json.as_object_mut().and_then(|json| json.remove(key)) {
+        Some(Value::Array(items)) => items,
+        _ => Vec::new(),
+    }
+}
+
+fn view_data<'a>(view: &Value, buffer: &'a [u8]) -> &'a [u8] {
+    let offset = view["byteOffset"].as_u64().unwrap_or_default() as usize;
+    let length = view["byteLength"].as_u64().unwrap_or_default() as usize;
+    &buffer[offset..offset + length]
+}
+
+/// Turn a texture path into a relative path that is safe to use as uri
+pub fn image_path(name: &str) -> String {
+    name.to_ascii_lowercase()
+        .replace('\\', "/")
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != "." && *part != "..")
+        .map(|part| {
+            part.chars()
+                .map(|c| match c {
+                    'a'..='z' | '0'..='9' | '_' | '-' | '.' => c,
+                    _ => '_',
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The file extension for an image with the given mime type
+fn image_extension(mime_type: &str) -> Option<&'static str> {
+    TextureFormat::value_variants()
+        .iter()
+        .find(|format| format.mime_type() == mime_type)
+        .map(TextureFormat::file_extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_extensions() {
+        assert_eq!(image_extension("image/png"), Some("png"));
+        assert_eq!(image_extension("image/jpeg"), Some("jpg"));
+        assert_eq!(image_extension("image/webp"), Some("webp"));
+        assert_eq!(image_extension("image/ktx2"), Some("ktx2"));
+        assert_eq!(image_extension("image/bmp"), None);
+    }
+}
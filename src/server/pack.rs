@@ -23,7 +23,6 @@ pub async fn pack(map: &str, data: &[u8]) -> Result<Vec<u8>> {
     let out = Command::new(pack_cmd)
         .arg("-kn")
         .arg("-mm")
-        .arg("-tc")
         .arg("-i")
         .arg(input.file_path())
         .arg("-o")
@@ -29,7 +29,7 @@ use tracing_subscriber::EnvFilter;
 use tracing_tree::HierarchicalLayer;
 use url::Url;
 use vbsp::{Bsp, BspError};
-use vbsp_to_gltf::{export, ConvertOptions, Error};
+use vbsp_to_gltf::{export_with_lumps, ConvertOptions, Error, ExtraLumps};
 
 type Result<T, E = ServerError> = std::result::Result<T, E>;
 
@@ -39,12 +39,19 @@ struct Config {
     map_server: Url,
     #[serde(default = "default_port")]
     port: u16,
+    /// Optimize the converted maps with gltfpack, textures are compressed by the converter itself
+    #[serde(default = "default_gltfpack")]
+    gltfpack: bool,
 }
 
 fn default_port() -> u16 {
     3030
 }
 
+fn default_gltfpack() -> bool {
+    true
+}
+
 #[derive(Debug, Error)]
 pub enum ServerError {
     #[error(transparent)]
@@ -136,6 +143,7 @@ async fn main() -> Result<()> {
     let app = App {
         cache_dir: config.cache_dir,
         map_server: config.map_server,
+        gltfpack: config.gltfpack,
         client: Client::default(),
         loader: Loader::new()?,
     };
@@ -146,6 +154,7 @@ async fn main() -> Result<()> {
 
     let app = Router::new()
         .route("/gltf/:map", get(convert))
+        .route("/report/:map", get(report))
         .route("/view/:map", get(view))
         .route("/assets/:asset", get(viewer_asset))
         .route("/transcoders/:asset", get(transcoder_asset))
@@ -173,6 +182,7 @@ async fn main() -> Result<()> {
 struct App {
     cache_dir: PathBuf,
     map_server: Url,
+    gltfpack: bool,
     client: Client,
     loader: Loader,
 }
@@ -307,8 +317,31 @@ pub fn guess_mime(path: &str) -> &'static str {
 async fn convert(
     State(app): State<Arc<App>>,
     Path(map): Path<String>,
-    Query(mut options): Query<ConvertOptions>,
-) -> impl IntoResponse {
+    Query(options): Query<ConvertOptions>,
+) -> Result<Vec<u8>> {
+    let (glb, _) = convert_map(&app, map, options).await?;
+    Ok(glb)
+}
+
+/// Serve the report of assets that couldn't be converted as json
+async fn report(
+    State(app): State<Arc<App>>,
+    Path(map): Path<String>,
+    Query(options): Query<ConvertOptions>,
+) -> Result<impl IntoResponse> {
+    let (_, report) = convert_map(&app, map, options).await?;
+    Ok((
+        [(CONTENT_TYPE, HeaderValue::from_static("application/json"))],
+        report,
+    ))
+}
+
+/// Convert and optimize a map, returning the packed glb and the json conversion report
+async fn convert_map(
+    app: &App,
+    map: String,
+    mut options: ConvertOptions,
+) -> Result<(Vec<u8>, Vec<u8>)> {
     if options.texture_scale > 1.0 {
         options.texture_scale = 1.0;
     }
@@ -316,9 +349,13 @@ async fn convert(
     if !map.is_ascii() || map.contains('/') || !map.ends_with(".glb") {
         return Err(ServerError::InvalidMapName(map));
     }
-    if let Some(cached) = app.cached(&map, options_key)? {
+    let report_name = format!("{map}.report.json");
+    if let (Some(cached), Some(report)) = (
+        app.cached(&map, options_key)?,
+        app.cached(&report_name, options_key)?,
+    ) {
         info!(map = map, "serving cached model");
-        return Ok(cached);
+        return Ok((cached, report));
     }
 
     let bsp_name = format!("{}.bsp", map.strip_suffix(".glb").unwrap());
@@ -327,22 +364,29 @@ async fn convert(
     let mut loader = app.loader.clone();
 
     let bsp = Bsp::read(&bsp_data).map_err(Error::from)?;
+    let lumps = ExtraLumps::read(&bsp_data)?;
     loader.add_source(bsp.pack.clone().into_zip());
 
-    let glb = export(bsp, &loader, options)?;
+    let (glb, report) = export_with_lumps(bsp, &lumps, &loader, options)?;
     let glb = glb.to_vec().map_err(Error::from)?;
-    let packed = pack(&map, &glb).await?;
-
-    info!(
-        unoptimized = glb.len(),
-        optimized = packed.len(),
-        map = map,
-        "optimized model"
-    );
+    let packed = if app.gltfpack {
+        let packed = pack(&map, &glb).await?;
+        info!(
+            unoptimized = glb.len(),
+            optimized = packed.len(),
+            map = map,
+            "optimized model"
+        );
+        packed
+    } else {
+        glb
+    };
 
+    let report = serde_json::to_vec(&report).expect("Serialization error");
     app.cache(&map, &packed, options_key)?;
+    app.cache(&report_name, &report, options_key)?;
 
-    Ok(packed)
+    Ok((packed, report))
 }
 
 async fn shutdown_signal() {
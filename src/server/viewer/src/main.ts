@@ -67,9 +67,10 @@ if (window.location.pathname.startsWith('/view/')) {
 }
 const textureScale = urlParams.get('texture_scale') || 0.25;
 const textures = urlParams.get('textures') || true;
+const textureFormat = urlParams.get('texture_format') || 'ktx2';
 console.log(map);
 
-loader.load(`${base_url}/gltf/${map}.glb?texture_scale=${textureScale}&textures=${textures}`, (gltf) => {
+loader.load(`${base_url}/gltf/${map}.glb?texture_scale=${textureScale}&textures=${textures}&texture_format=${textureFormat}`, (gltf) => {
     document.body.classList.remove('loading');
     gltf.scene.traverse(child => {
         if ((child as THREE.Mesh).material) {
@@ -0,0 +1,480 @@
+use crate::convert::map_coords;
+use crate::layers::Layer;
+use crate::region::RegionShape;
+use crate::{ConvertOptions, Error};
+use ahash::{HashMap, HashSet};
+use cgmath::Vector3;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use vbsp::{Bsp, Face, Handle, Leaf, Vector, VisData};
+
+const CONTENTS_SOLID: i32 = 1;
+
+/// Entities marking places players can be, used as starting point to find the visible parts of the map
+const PLAYABLE_ENTITIES: &[&str] = &[
+    "info_player_start",
+    "info_player_teamspawn",
+    "info_player_deathmatch",
+    "info_player_terrorist",
+    "info_player_counterterrorist",
+    "info_player_combine",
+    "info_player_rebel",
+    // the 3d skybox isn't in the pvs of the map itself
+    "sky_camera",
+];
+
+/// Which faces of the world to drop based on the visibility data of the map
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Culling {
+    /// Drop faces that are only in solid leaves or leaves outside the map
+    Solid,
+    /// Also drop faces that can't be seen from anywhere reachable from a spawn point
+    Visible,
+}
+
+/// Per face visibility information, deciding which faces are exported and how they are grouped
+#[derive(Default)]
+pub struct FaceVisibility {
+    culled: HashSet<usize>,
+    /// Sorted clusters of every face, only collected when tagging primitives with their clusters
+    clusters: HashMap<usize, Vec<i16>>,
+    /// Area of every face, only collected when splitting the map into areas or layers
+    areas: HashMap<usize, u16>,
+    /// The area containing the 3d skybox
+    sky_area: Option<u16>,
+    /// Region faces have to intersect to be exported
+    region: Option<RegionShape>,
+}
+
+impl FaceVisibility {
+    pub fn new(bsp: &Bsp, options: &ConvertOptions) -> Result<Self, Error> {
+        let culled = options
+            .culling
+            .map(|mode| culled_faces(bsp, mode))
+            .unwrap_or_default();
+        let mut clusters = HashMap::default();
+        let mut areas = HashMap::default();
+        let collect_areas = options.areas || options.layers || !options.exclude.is_empty();
+        if options.clusters || collect_areas {
+            for leaf in bsp.leaves.iter().filter(|leaf| leaf.cluster >= 0) {
+                for face in leaf_faces(bsp, leaf) {
+                    if options.clusters {
+                        add_cluster(clusters.entry(face).or_default(), leaf.cluster);
+                    }
+                    if collect_areas {
+                        areas.entry(face).or_insert(leaf_area(leaf));
+                    }
+                }
+            }
+        }
+        let region = options
+            .region
+            .as_ref()
+            .map(|region| region.shape(bsp))
+            .transpose()?;
+        Ok(FaceVisibility {
+            culled,
+            clusters,
+            areas,
+            sky_area: sky_area(bsp),
+            region,
+        })
+    }
+
+    pub fn is_culled(&self, face: usize) -> bool {
+        self.culled.contains(&face)
+    }
+
+    /// All clusters with a leaf containing the face
+    pub fn clusters(&self, face: usize) -> &[i16] {
+        self.clusters.get(&face).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn area(&self, face: usize) -> Option<u16> {
+        self.areas.get(&face).copied()
+    }
+
+    pub fn sky_area(&self) -> Option<u16> {
+        self.sky_area
+    }
+
+    pub fn region(&self) -> Option<&RegionShape> {
+        self.region.as_ref()
+    }
+
+    /// The layer a face of the world or a brush entity belongs to
+    pub fn face_layer(&self, index: usize, face: &Handle<Face>, world: bool) -> Layer {
+        let in_skybox = self.sky_area.is_some() && self.area(index) == self.sky_area;
+        Layer::for_face(face.texture().name(), world, in_skybox)
+    }
+}
+
+/// Add a cluster to the sorted clusters of a face, faces can be split over the leaves of multiple clusters
+fn add_cluster(clusters: &mut Vec<i16>, cluster: i16) {
+    if let Err(position) = clusters.binary_search(&cluster) {
+        clusters.insert(position, cluster);
+    }
+}
+
+/// Find the area containing the 3d skybox camera
+fn sky_area(bsp: &Bsp) -> Option<u16> {
+    let camera = bsp
+        .entities
+        .iter()
+        .find(|entity| entity.prop("classname").ok() == Some("sky_camera"))?;
+    let origin: Vector = camera.prop("origin").ok()?.parse().ok()?;
+    Some(leaf_area(&bsp.leaf_at(origin))).filter(|area| *area > 0)
+}
+
+/// The area a leaf is in, stored in the lower 9 bits
+pub fn leaf_area(leaf: &Leaf) -> u16 {
+    leaf.area_and_flags as u16 & 0x1ff
+}
+
+/// Find the faces of the world model that should be left out
+fn culled_faces(bsp: &Bsp, mode: Culling) -> HashSet<usize> {
+    let visible_clusters = match mode {
+        Culling::Solid => None,
+        Culling::Visible => playable_clusters(bsp),
+    };
+    let keep_leaf = |leaf: &Leaf| {
+        leaf.cluster >= 0
+            && leaf.contents & CONTENTS_SOLID == 0
+            && visible_clusters
+                .as_ref()
+                .map_or(true, |clusters| clusters.contains(&leaf.cluster))
+    };
+
+    let mut referenced = HashSet::default();
+    let mut kept = HashSet::default();
+    for leaf in bsp.leaves.iter() {
+        let faces = leaf_faces(bsp, leaf);
+        if keep_leaf(leaf) {
+            kept.extend(faces.clone());
+        }
+        referenced.extend(faces);
+    }
+
+    // faces that aren't in any leaf (like displacements) are always kept
+    let culled: HashSet<usize> = referenced.difference(&kept).copied().collect();
+    debug!(culled = culled.len(), "culled faces");
+    culled
+}
+
+fn leaf_faces<'a>(bsp: &'a Bsp, leaf: &Leaf) -> impl Iterator<Item = usize> + Clone + 'a {
+    let start = leaf.first_leaf_face as usize;
+    let end = start + leaf.leaf_face_count as usize;
+    bsp.leaf_faces
+        .get(start..end)
+        .unwrap_or_default()
+        .iter()
+        .map(|leaf_face| leaf_face.face as usize)
+}
+
+/// Find all clusters that can be seen from anywhere players can get to from a spawn point
+///
+/// Since the map has no data on where players can move, every cluster visible from a reachable cluster is assumed to be
+/// reachable itself. This over-estimates what can be seen, but never drops a face that can be seen.
+fn playable_clusters(bsp: &Bsp) -> Option<HashSet<i16>> {
+    if bsp.vis_data.cluster_count == 0 {
+        warn!("map has no visibility data, only culling solid leaves");
+        return None;
+    }
+
+    let spawn_clusters: Vec<i16> = bsp
+        .entities
+        .iter()
+        .filter(|entity| {
+            entity
+                .prop("classname")
+                .is_ok_and(|class| PLAYABLE_ENTITIES.contains(&class))
+        })
+        .filter_map(|entity| entity.prop("origin").ok()?.parse::<Vector>().ok())
+        .map(|origin| bsp.leaf_at(origin).cluster)
+        .filter(|cluster| *cluster >= 0)
+        .collect();
+    if spawn_clusters.is_empty() {
+        warn!("no spawn points found, only culling solid leaves");
+        return None;
+    }
+
+    Some(visible_from(&bsp.vis_data, &spawn_clusters))
+}
+
+/// The given clusters and all clusters in their pvs, repeated for every newly visible cluster until no more are found
+fn visible_from(vis: &VisData, clusters: &[i16]) -> HashSet<i16> {
+    let mut visible: HashSet<i16> = clusters.iter().copied().collect();
+    let mut pending: Vec<i16> = visible.iter().copied().collect();
+    while let Some(cluster) = pending.pop() {
+        for other in visible_clusters(vis, cluster) {
+            if visible.insert(other) {
+                pending.push(other);
+            }
+        }
+    }
+    visible
+}
+
+/// Decode the run length encoded pvs of a cluster
+///
+/// Unlike `VisData::visible_clusters`, a zero byte skips the given number of bytes (not clusters) like the engine does,
+/// and an invalid pvs offset results in an empty pvs instead of a panic.
+fn visible_clusters(vis: &VisData, cluster: i16) -> Vec<i16> {
+    let cluster_count = vis.cluster_count as usize;
+    let Some(mut data) = vis
+        .pvs_offsets
+        .get(cluster as usize)
+        .and_then(|offset| vis.data.get(usize::try_from(*offset).ok()?..))
+    else {
+        return Vec::new();
+    };
+
+    let mut visible = Vec::new();
+    let mut index = 0;
+    while index < cluster_count {
+        match data {
+            [0, skip, rest @ ..] => {
+                index += *skip as usize * 8;
+                data = rest;
+            }
+            [packed, rest @ ..] => {
+                visible.extend(
+                    (0..8)
+                        .filter(|bit| packed & (1 << bit) != 0)
+                        .map(|bit| index + bit)
+                        .filter(|cluster| *cluster < cluster_count)
+                        .map(|cluster| cluster as i16),
+                );
+                index += 8;
+                data = rest;
+            }
+            [] => break,
+        }
+    }
+    visible
+}
+
+/// The cluster structure and visibility data of a map
+///
+/// All bounding boxes are in output coordinates, faces are referenced by their index in the map.
+#[derive(Debug, Serialize)]
+pub struct ClusterData {
+    pub clusters: Vec<Cluster>,
+    pub leaves: Vec<LeafData>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Cluster {
+    pub mins: [f32; 3],
+    pub maxs: [f32; 3],
+    pub leaves: Vec<usize>,
+    /// The decompressed pvs as hex encoded bitset, bit `i % 8` of byte `i / 8` is set if cluster `i` is visible
+    pub visible: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LeafData {
+    /// Cluster of the leaf, -1 for leaves outside of any cluster
+    pub cluster: i16,
+    pub area: u16,
+    pub contents: i32,
+    pub mins: [f32; 3],
+    pub maxs: [f32; 3],
+    pub faces: Vec<usize>,
+}
+
+/// Collect the clusters, leaves and pvs of the map
+///
+/// The bounding boxes get the same transforms as the converted scene: the offset it was recentered by (in map
+/// coordinates), the unit scale and the output coordinate system.
+pub fn cluster_data(bsp: &Bsp, origin_offset: Vector, options: &ConvertOptions) -> ClusterData {
+    let cluster_count = bsp.vis_data.cluster_count as usize;
+    let mut bounds: Vec<Option<([i16; 3], [i16; 3])>> = vec![None; cluster_count];
+    let mut cluster_leaves: Vec<Vec<usize>> = vec![Vec::new(); cluster_count];
+    let transform = |mins: [i16; 3], maxs: [i16; 3]| {
+        output_bounds(
+            mins.map(f32::from),
+            maxs.map(f32::from),
+            origin_offset,
+            options,
+        )
+    };
+
+    let leaves = bsp
+        .leaves
+        .iter()
+        .enumerate()
+        .map(|(index, leaf)| {
+            if let Ok(cluster) = usize::try_from(leaf.cluster) {
+                if cluster < cluster_count {
+                    cluster_leaves[cluster].push(index);
+                    let (mins, maxs) = bounds[cluster].get_or_insert((leaf.mins, leaf.maxs));
+                    for axis in 0..3 {
+                        mins[axis] = mins[axis].min(leaf.mins[axis]);
+                        maxs[axis] = maxs[axis].max(leaf.maxs[axis]);
+                    }
+                }
+            }
+            let (mins, maxs) = transform(leaf.mins, leaf.maxs);
+            LeafData {
+                cluster: leaf.cluster,
+                area: leaf_area(leaf),
+                contents: leaf.contents,
+                mins,
+                maxs,
+                faces: leaf_faces(bsp, leaf).collect(),
+            }
+        })
+        .collect();
+
+    let clusters = cluster_leaves
+        .into_iter()
+        .zip(bounds)
+        .enumerate()
+        .map(|(cluster, (leaves, bounds))| {
+            // clusters without leaves get an empty box at the origin
+            let (mins, maxs) =
+                bounds.map_or(([0.0; 3], [0.0; 3]), |(mins, maxs)| transform(mins, maxs));
+            let mut visible = vec![0u8; cluster_count.div_ceil(8)];
+            for index in visible_clusters(&bsp.vis_data, cluster as i16) {
+                visible[index as usize / 8] |= 1 << (index % 8);
+            }
+            Cluster {
+                mins,
+                maxs,
+                leaves,
+                visible: visible.iter().map(|byte| format!("{byte:02x}")).collect(),
+            }
+        })
+        .collect();
+
+    ClusterData { clusters, leaves }
+}
+
+/// Transform a bounding box in map coordinates into output coordinates
+fn output_bounds(
+    mins: [f32; 3],
+    maxs: [f32; 3],
+    origin_offset: Vector,
+    options: &ConvertOptions,
+) -> ([f32; 3], [f32; 3]) {
+    let rotation = options.coordinates.rotation();
+    let scale = options.unit_scale();
+    let offset: [f32; 3] = origin_offset.into();
+    let mut output_mins = [f32::MAX; 3];
+    let mut output_maxs = [f32::MIN; 3];
+    for corner in 0..8 {
+        let point: [f32; 3] = std::array::from_fn(|axis| {
+            let value = if corner & (1 << axis) == 0 {
+                mins[axis]
+            } else {
+                maxs[axis]
+            };
+            value + offset[axis]
+        });
+        let point: [f32; 3] = (rotation * (Vector3::from(map_coords(point)) * scale)).into();
+        for axis in 0..3 {
+            output_mins[axis] = output_mins[axis].min(point[axis]);
+            output_maxs[axis] = output_maxs[axis].max(point[axis]);
+        }
+    }
+    (output_mins, output_maxs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CoordinateSystem;
+
+    /// Visibility data with the given pvs bytes for every cluster
+    fn vis_data(cluster_count: u32, pvs: &[&[u8]]) -> VisData {
+        let mut data = Vec::new();
+        let mut pvs_offsets = Vec::new();
+        for cluster in pvs {
+            pvs_offsets.push(data.len() as i32);
+            data.extend_from_slice(cluster);
+        }
+        VisData {
+            cluster_count,
+            pas_offsets: pvs_offsets.clone(),
+            pvs_offsets,
+            data,
+        }
+    }
+
+    #[test]
+    fn decode_pvs() {
+        let vis = vis_data(3, &[&[0b011], &[0b111], &[0b110]]);
+        assert_eq!(visible_clusters(&vis, 0), [0, 1]);
+        assert_eq!(visible_clusters(&vis, 1), [0, 1, 2]);
+        assert_eq!(visible_clusters(&vis, 2), [1, 2]);
+    }
+
+    #[test]
+    fn decode_pvs_runs() {
+        // cluster 0, a run of 8 invisible clusters, then cluster 17
+        let vis = vis_data(20, &[&[0b1, 0, 1, 0b10]]);
+        assert_eq!(visible_clusters(&vis, 0), [0, 17]);
+    }
+
+    #[test]
+    fn decode_pvs_padding() {
+        // bits past the cluster count are ignored
+        let vis = vis_data(3, &[&[0xff]]);
+        assert_eq!(visible_clusters(&vis, 0), [0, 1, 2]);
+    }
+
+    #[test]
+    fn decode_invalid_pvs() {
+        let mut vis = vis_data(16, &[&[0xff]]);
+        assert_eq!(visible_clusters(&vis, 0), [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert!(visible_clusters(&vis, 1).is_empty());
+        vis.pvs_offsets[0] = 10;
+        assert!(visible_clusters(&vis, 0).is_empty());
+    }
+
+    #[test]
+    fn collect_face_clusters() {
+        let mut clusters = Vec::new();
+        for cluster in [4, 1, 4, 2, 1] {
+            add_cluster(&mut clusters, cluster);
+        }
+        assert_eq!(clusters, [1, 2, 4]);
+    }
+
+    #[test]
+    fn transform_bounds() {
+        let options = ConvertOptions {
+            unit_scale: Some(0.5),
+            coordinates: CoordinateSystem::Source,
+            ..ConvertOptions::default()
+        };
+        let offset = Vector {
+            x: 10.0,
+            y: 0.0,
+            z: -4.0,
+        };
+        let (mins, maxs) = output_bounds([-10.0, 0.0, 4.0], [10.0, 8.0, 8.0], offset, &options);
+        assert_eq!(mins, [0.0, 0.0, 0.0]);
+        assert_eq!(maxs, [10.0, 4.0, 2.0]);
+    }
+
+    #[test]
+    fn follow_pvs_until_stable() {
+        let vis = vis_data(3, &[&[0b011], &[0b111], &[0b110]]);
+        let visible = visible_from(&vis, &[0]);
+        assert_eq!(visible, [0, 1, 2].into_iter().collect());
+    }
+
+    #[test]
+    fn skip_unreachable_clusters() {
+        // clusters 0 to 2 form a chain, cluster 3 can only be seen from itself
+        let vis = vis_data(4, &[&[0b0011], &[0b0111], &[0b0110], &[0b1000]]);
+        let visible = visible_from(&vis, &[2]);
+        assert_eq!(visible, [0, 1, 2].into_iter().collect());
+        let visible = visible_from(&vis, &[3]);
+        assert_eq!(visible, [3].into_iter().collect());
+    }
+}
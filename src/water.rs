@@ -0,0 +1,108 @@
+use serde_json::{Map, Value};
+use vdf_reader::entry::{Entry, Table};
+use vmt_parser::material::WaterMaterial;
+
+/// Parameters of a `Water` material
+///
+/// `vmt-parser` doesn't expose all parameters we need, so the remaining ones are read from the raw material.
+#[derive(Debug, Clone)]
+pub struct WaterParameters {
+    pub fog_color: [f32; 3],
+    pub fog_start: f32,
+    pub fog_end: f32,
+    pub reflect_tint: [f32; 3],
+    /// Color of the water volume, the fog color of the `$bottommaterial` if it has one
+    pub volume_color: [f32; 3],
+    pub normal_map: Option<String>,
+    /// All parameters from the material source
+    pub raw: Map<String, Value>,
+}
+
+impl WaterParameters {
+    pub fn new(material: &WaterMaterial, table: &Table) -> Self {
+        let params = table.get("water").and_then(Entry::as_table);
+        let color = |key: &str| params.and_then(|params| parse_color(params.get(key)?));
+
+        let raw = params
+            .into_iter()
+            .flat_map(|params| params.iter())
+            .filter(|(key, _)| key.starts_with('$'))
+            .filter_map(|(key, value)| Some((key.clone(), serde_json::to_value(value).ok()?)))
+            .collect();
+
+        let fog_color = color("$fogcolor").unwrap_or([1.0; 3]);
+        WaterParameters {
+            fog_color,
+            fog_start: material.fog_start,
+            fog_end: material.fog_end,
+            reflect_tint: color("$reflecttint").unwrap_or([1.0; 3]),
+            volume_color: fog_color,
+            normal_map: material
+                .normal_map
+                .clone()
+                .or_else(|| material.bump_map.clone()),
+            raw,
+        }
+    }
+
+    /// Use the fog of the material seen from below the water surface for the water volume
+    pub fn apply_bottom_material(&mut self, table: &Table) {
+        let fog_color = table
+            .values()
+            .filter_map(Entry::as_table)
+            .find_map(|params| parse_color(params.get("$fogcolor")?));
+        if let Some(fog_color) = fog_color {
+            self.volume_color = fog_color;
+        }
+    }
+}
+
+/// Parse a color in either `{255 255 255}` or `[1.0 1.0 1.0]` notation into the 0-1 range
+///
+/// The range is decided by the brackets, colors without brackets are read as 0-1.
+fn parse_color(entry: &Entry) -> Option<[f32; 3]> {
+    let value = entry
+        .as_slice()?
+        .iter()
+        .filter_map(Entry::as_str)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let value = value.trim();
+    let max = if value.starts_with('{') { 255.0 } else { 1.0 };
+    let values = value
+        .trim_matches(['{', '}', '[', ']'])
+        .split_whitespace()
+        .map(|value| value.parse::<f32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let color: [f32; 3] = values.get(0..3)?.try_into().ok()?;
+    Some(color.map(|channel| channel / max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color(value: &str) -> Option<[f32; 3]> {
+        parse_color(&Entry::Value(value.into()))
+    }
+
+    #[test]
+    fn parse_byte_color() {
+        assert_eq!(color("{255 51 0}"), Some([1.0, 0.2, 0.0]));
+        assert_eq!(color("{1 1 1}"), Some([1.0 / 255.0; 3]));
+        assert_eq!(color(" { 0 0 0 } "), Some([0.0; 3]));
+    }
+
+    #[test]
+    fn parse_float_color() {
+        assert_eq!(color("[1 0.5 0]"), Some([1.0, 0.5, 0.0]));
+        assert_eq!(color("[1 1 1]"), Some([1.0; 3]));
+        assert_eq!(color("0.5 0.5 0.5"), Some([0.5; 3]));
+    }
+
+    #[test]
+    fn parse_invalid_color() {
+        assert_eq!(color("{255 255}"), None);
+        assert_eq!(color("[red green blue]"), None);
+    }
+}